@@ -32,6 +32,17 @@ where
     Ok(())
 }
 
+/// Remove the temporary file left behind by an overwrite that was
+/// interrupted, which would otherwise make every later overwrite fail. Only
+/// safe while holding a lock every writer of the file takes, as the temporary
+/// file may otherwise belong to an overwrite still in progress.
+pub(crate) fn remove_interrupted(path: &Path) -> io::Result<()> {
+    match fs::remove_file(make_tmp_path(path)?) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
 fn fsync_dir(dir: &Path) -> io::Result<()> {
     let f = File::open(dir)?;
     f.sync_all()
@@ -0,0 +1,71 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use slog::Logger;
+use u2f_core::{self_signed_attestation, Attestation};
+
+use config::AttestationSource;
+use stores::UserSecretStore;
+
+const GENERATED_COMMON_NAME: &str = "Soft U2F";
+
+pub(crate) fn build(
+    source: &AttestationSource,
+    secret_store: &dyn UserSecretStore,
+    log: &Logger,
+) -> io::Result<Attestation> {
+    match source {
+        AttestationSource::Shared => {
+            info!(log, "Using attestation shared by all installs");
+            Ok(self_signed_attestation())
+        }
+        AttestationSource::Files {
+            certificate_chain_path,
+            key_path,
+        } => {
+            info!(log, "Loading attestation"; "certificate_chain" => certificate_chain_path.display(), "key" => key_path.display());
+            load_pem_files(certificate_chain_path, key_path)
+        }
+        AttestationSource::SecretStore {
+            certificate_chain_path,
+            key_path,
+        } => {
+            if let Some(attestation) = secret_store.attestation()? {
+                info!(log, "Using attestation from secret store");
+                return Ok(attestation);
+            }
+            match (certificate_chain_path, key_path) {
+                (Some(certificate_chain_path), Some(key_path)) => {
+                    info!(log, "Importing attestation into secret store"; "certificate_chain" => certificate_chain_path.display(), "key" => key_path.display());
+                    let attestation = load_pem_files(certificate_chain_path, key_path)?;
+                    secret_store.set_attestation(&attestation)?;
+                    Ok(attestation)
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no attestation in secret store and no files configured to import",
+                )),
+            }
+        }
+        AttestationSource::Generated => {
+            if let Some(attestation) = secret_store.attestation()? {
+                info!(log, "Using generated attestation from secret store");
+                return Ok(attestation);
+            }
+            warn!(
+                log,
+                "Generating attestation unique to this install, relying parties can tell it apart"
+            );
+            let attestation = Attestation::generate(GENERATED_COMMON_NAME)?;
+            secret_store.set_attestation(&attestation)?;
+            Ok(attestation)
+        }
+    }
+}
+
+fn load_pem_files(certificate_chain_path: &Path, key_path: &Path) -> io::Result<Attestation> {
+    let certificate_chain = fs::read_to_string(certificate_chain_path)?;
+    let key = fs::read_to_string(key_path)?;
+    Attestation::from_pem(&certificate_chain, &key)
+}
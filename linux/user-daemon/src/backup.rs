@@ -0,0 +1,264 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use u2f_core::{Counter, MasterSecret};
+
+use atomic_file;
+use stores::envelope::{Envelope, EnvelopeKey};
+use stores::{DeviceCounters, Secret, UserSecretStore};
+
+/// Version 1 backups have no master secret or device counters
+const BACKUP_VERSION: u32 = 2;
+
+/// How much restored counters are raised by. Relying parties reject
+/// signatures whose counter is not higher than the last one they saw, and
+/// the credential may have been used many times since the backup was made.
+const RESTORED_COUNTER_INCREASE: Counter = 10_000;
+
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    /// Seconds since the Unix epoch
+    created: u64,
+    secrets: Vec<Secret>,
+    /// Key handles that wrap their key can only be unwrapped with this
+    #[serde(default)]
+    master_secret: Option<MasterSecret>,
+    #[serde(default)]
+    device_counters: DeviceCounters,
+}
+
+/// What to do with a backed up secret that is already in the store
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Conflict {
+    Skip,
+    Replace,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ImportSummary {
+    pub added: usize,
+    pub replaced: usize,
+    pub skipped: usize,
+}
+
+/// Write every secret, the master secret and the device counters in the
+/// store to an encrypted backup file, returns the number of secrets written
+pub(crate) fn export(
+    store: &dyn UserSecretStore,
+    path: &Path,
+    passphrase: &str,
+) -> io::Result<usize> {
+    export_with_key(store, path, &EnvelopeKey::generate(passphrase)?)
+}
+
+fn export_with_key(
+    store: &dyn UserSecretStore,
+    path: &Path,
+    key: &EnvelopeKey,
+) -> io::Result<usize> {
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time moved backwards")
+            .as_secs(),
+        secrets: store.list_secrets()?,
+        master_secret: store.find_master_secret()?,
+        device_counters: store.device_counters()?,
+    };
+    let envelope = key.seal(&serde_json::to_vec(&backup)?)?;
+    atomic_file::overwrite(path, move |writer| {
+        serde_json::to_writer_pretty(writer, &envelope).map_err(|e| e.into())
+    })?;
+    Ok(backup.secrets.len())
+}
+
+/// Restore secrets, the master secret and the device counters from a backup
+/// file, raising counters so signatures made after the restore are accepted
+pub(crate) fn import(
+    store: &dyn UserSecretStore,
+    path: &Path,
+    passphrase: &str,
+    conflict: Conflict,
+) -> io::Result<ImportSummary> {
+    let envelope: Envelope = serde_json::from_reader(File::open(path)?)?;
+    let key = EnvelopeKey::unlock(passphrase, &envelope)?;
+    let backup: Backup = serde_json::from_slice(&key.open(&envelope)?)?;
+    if backup.version > BACKUP_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported backup version {}", backup.version),
+        ));
+    }
+
+    // Key handles wrapped with one master secret cannot be unwrapped with another
+    if let Some(ref master_secret) = backup.master_secret {
+        match store.find_master_secret()? {
+            Some(ref existing) if existing.as_ref() != master_secret.as_ref() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the store has a different master secret than the backup, key handles from one cannot be used with the other",
+                ));
+            }
+            Some(_) => {}
+            None => store.set_master_secret(master_secret)?,
+        }
+    }
+    let mut device_counters = backup.device_counters;
+    device_counters.counter = raise_counter(device_counters.counter);
+    for counter in device_counters.wrapped_keys.values_mut() {
+        *counter = raise_counter(*counter);
+    }
+    store.raise_device_counters(&device_counters)?;
+
+    let existing = store.list_secrets()?;
+    let mut summary = ImportSummary::default();
+    for mut secret in backup.secrets {
+        let current = existing.iter().find(|s| {
+            s.matches(
+                &secret.application_key.application,
+                &secret.application_key.handle,
+            )
+        });
+        match current {
+            None => {
+                secret.counter = raise_counter(secret.counter);
+                store.add_secret(secret)?;
+                summary.added += 1;
+            }
+            Some(current) if conflict == Conflict::Replace => {
+                secret.counter = raise_counter(secret.counter.max(current.counter));
+                store.update_secret(&secret)?;
+                summary.replaced += 1;
+            }
+            Some(_) => summary.skipped += 1,
+        }
+    }
+    Ok(summary)
+}
+
+fn raise_counter(counter: Counter) -> Counter {
+    counter.saturating_add(RESTORED_COUNTER_INCREASE)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use u2f_core::{
+        self_signed_attestation, CryptoOperations, KeyWrappingCryptoOperations, SecretStore,
+    };
+
+    use super::*;
+    use stores::envelope;
+    use stores::file_store_v2::FileStoreV2;
+    use stores::fixtures::{fake_app_id, fake_application_key};
+
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn import_into_empty_store_raises_counters() {
+        let dir = TempDir::new("backup_tests").unwrap();
+        let backup_path = dir.path().join("backup.json");
+        let source = FileStoreV2::new(&dir.path().join("source")).unwrap();
+        let app_key = fake_application_key(&[1]);
+        source.add_application_key(&app_key).unwrap();
+        let counter = source
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        export_with_key(&source, &backup_path, &envelope::fast_key("passphrase")).unwrap();
+
+        let destination = FileStoreV2::new(&dir.path().join("destination")).unwrap();
+        let summary = import(&destination, &backup_path, "passphrase", Conflict::Skip).unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                replaced: 0,
+                skipped: 0,
+            }
+        );
+        let restored_counter = destination
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        assert!(restored_counter > counter + RESTORED_COUNTER_INCREASE);
+    }
+
+    #[test]
+    fn import_restores_wrapped_keys() {
+        let dir = TempDir::new("backup_tests").unwrap();
+        let backup_path = dir.path().join("backup.json");
+        let source = FileStoreV2::new(&dir.path().join("source")).unwrap();
+        let operations = KeyWrappingCryptoOperations::new(
+            self_signed_attestation(),
+            source.master_secret().unwrap(),
+        );
+        // Only the counter of a wrapped key is stored, the key is in its handle
+        let wrapped_key = operations.generate_application_key(&fake_app_id()).unwrap();
+        let counter = source
+            .get_and_increment_counter(&wrapped_key.application, &wrapped_key.handle)
+            .unwrap();
+        export_with_key(&source, &backup_path, &envelope::fast_key("passphrase")).unwrap();
+
+        let destination = FileStoreV2::new(&dir.path().join("destination")).unwrap();
+        import(&destination, &backup_path, "passphrase", Conflict::Skip).unwrap();
+
+        let operations = KeyWrappingCryptoOperations::new(
+            self_signed_attestation(),
+            destination.find_master_secret().unwrap().unwrap(),
+        );
+        assert!(operations
+            .unwrap_application_key(&wrapped_key.application, &wrapped_key.handle)
+            .unwrap()
+            .is_some());
+        let restored_counter = destination
+            .get_and_increment_counter(&wrapped_key.application, &wrapped_key.handle)
+            .unwrap();
+        assert!(restored_counter > counter + RESTORED_COUNTER_INCREASE);
+    }
+
+    #[test]
+    fn import_with_different_master_secret_errors() {
+        let dir = TempDir::new("backup_tests").unwrap();
+        let backup_path = dir.path().join("backup.json");
+        let source = FileStoreV2::new(&dir.path().join("source")).unwrap();
+        source.master_secret().unwrap();
+        export_with_key(&source, &backup_path, &envelope::fast_key("passphrase")).unwrap();
+
+        let destination = FileStoreV2::new(&dir.path().join("destination")).unwrap();
+        destination.master_secret().unwrap();
+
+        assert!(import(&destination, &backup_path, "passphrase", Conflict::Skip).is_err());
+    }
+
+    #[test]
+    fn import_with_skip_keeps_existing_secrets() {
+        let dir = TempDir::new("backup_tests").unwrap();
+        let backup_path = dir.path().join("backup.json");
+        let store = FileStoreV2::new(dir.path()).unwrap();
+        store
+            .add_application_key(&fake_application_key(&[1]))
+            .unwrap();
+        export_with_key(&store, &backup_path, &envelope::fast_key("passphrase")).unwrap();
+
+        let summary = import(&store, &backup_path, "passphrase", Conflict::Skip).unwrap();
+
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(store.list_secrets().unwrap()[0].counter, 0);
+    }
+
+    #[test]
+    fn import_with_wrong_passphrase_errors() {
+        let dir = TempDir::new("backup_tests").unwrap();
+        let backup_path = dir.path().join("backup.json");
+        let store = FileStoreV2::new(dir.path()).unwrap();
+        export_with_key(&store, &backup_path, &envelope::fast_key("passphrase")).unwrap();
+
+        assert!(import(&store, &backup_path, "wrong", Conflict::Skip).is_err());
+    }
+}
@@ -0,0 +1,553 @@
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::Error;
+use slog::Logger;
+use time;
+use u2f_core::{try_reverse_app_id, CounterStrategy, CryptoOperations};
+
+use attestation;
+use backup::{self, Conflict};
+use build_crypto_operations;
+use config::{Config, SecretStoreType};
+use passphrase;
+use storage::{self, AppDirs};
+use stores::{Secret, UserSecretStore};
+
+const LIST_COMMAND: &str = "list";
+const SHOW_COMMAND: &str = "show";
+const DELETE_COMMAND: &str = "delete";
+const RENAME_LABEL_COMMAND: &str = "rename-label";
+const RESET_COUNTER_COMMAND: &str = "reset-counter";
+const EXPORT_COMMAND: &str = "export";
+const IMPORT_COMMAND: &str = "import";
+const MIGRATE_COMMAND: &str = "migrate";
+
+const ID_ARG: &str = "id";
+const LABEL_ARG: &str = "label";
+const FORCE_ARG: &str = "force";
+const BACKUP_PATH_ARG: &str = "backup-path";
+const REPLACE_ARG: &str = "replace";
+const FROM_ARG: &str = "from";
+const DRY_RUN_ARG: &str = "dry-run";
+
+// Long enough to tell apart the registrations of any one user
+const SHORT_ID_LEN: usize = 12;
+
+#[derive(Debug, Fail)]
+enum CredentialLookupError {
+    #[fail(display = "no credential has an id starting with {}", _0)]
+    NotFound(String),
+    #[fail(
+        display = "more than one credential has an id starting with {}, give more of the id",
+        _0
+    )]
+    Ambiguous(String),
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "resetting the counter stops relying parties from noticing a cloned key, pass --force to reset anyway"
+)]
+struct ResetCounterNotForced;
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "every credential counts from the device counter with the {:?} counter strategy, resetting the counter of one credential has no effect",
+    _0
+)]
+struct ResetCounterDeviceWide(CounterStrategy);
+
+#[derive(Debug, Fail)]
+#[fail(display = "{:?} is already the configured secret store", _0)]
+struct MigrateToSameStore(SecretStoreType);
+
+pub(crate) fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    let id_arg = Arg::with_name(ID_ARG)
+        .required(true)
+        .help("Credential id as shown by list, or any unique prefix of it");
+    vec![
+        SubCommand::with_name(LIST_COMMAND).about("List registered credentials"),
+        SubCommand::with_name(SHOW_COMMAND)
+            .about("Show details of a registered credential")
+            .arg(id_arg.clone()),
+        SubCommand::with_name(DELETE_COMMAND)
+            .about("Delete a registered credential, it can no longer be used to sign in")
+            .arg(id_arg.clone()),
+        SubCommand::with_name(RENAME_LABEL_COMMAND)
+            .about("Set the label of a registered credential, an empty label removes it")
+            .arg(id_arg.clone())
+            .arg(Arg::with_name(LABEL_ARG).required(true)),
+        SubCommand::with_name(RESET_COUNTER_COMMAND)
+            .about("Reset the signature counter of a registered credential to zero, only when counting per credential")
+            .arg(id_arg)
+            .arg(
+                Arg::with_name(FORCE_ARG)
+                    .long("force")
+                    .help("Confirm the counter should be reset, relying parties may reject the credential afterwards"),
+            ),
+        SubCommand::with_name(EXPORT_COMMAND)
+            .about("Write every registered credential to a passphrase encrypted backup file")
+            .arg(Arg::with_name(BACKUP_PATH_ARG).required(true)),
+        SubCommand::with_name(IMPORT_COMMAND)
+            .about("Restore registered credentials from a backup file, raising their counters")
+            .arg(Arg::with_name(BACKUP_PATH_ARG).required(true))
+            .arg(
+                Arg::with_name(REPLACE_ARG)
+                    .long("replace")
+                    .help("Replace credentials that are already registered instead of skipping them"),
+            ),
+        SubCommand::with_name(MIGRATE_COMMAND)
+            .about("Copy registered credentials from another secret store into the configured one")
+            .arg(
+                Arg::with_name(FROM_ARG)
+                    .long("from")
+                    .takes_value(true)
+                    .required(true)
+                    .possible_values(&[
+                        "file",
+                        "encrypted-file",
+                        "kernel-keyring",
+                        "indexed-file",
+                        "secret-service",
+                    ])
+                    .help("Secret store to copy credentials from"),
+            )
+            .arg(
+                Arg::with_name(DRY_RUN_ARG)
+                    .long("dry-run")
+                    .help("Only report what would be copied"),
+            ),
+    ]
+}
+
+pub(crate) fn run(
+    command: &str,
+    args: &ArgMatches,
+    dirs: &AppDirs,
+    log: &Logger,
+) -> Result<(), Error> {
+    let config = storage::determine_config(dirs, log)?;
+    let shared_store: Rc<dyn UserSecretStore> = Rc::from(storage::build(dirs, &config, log)?);
+    let store = shared_store.as_ref();
+    match command {
+        LIST_COMMAND => list(store),
+        SHOW_COMMAND => show(store, args.value_of(ID_ARG).unwrap()),
+        DELETE_COMMAND => delete(
+            store,
+            crypto_operations(&config, &shared_store, log)?.as_ref(),
+            args.value_of(ID_ARG).unwrap(),
+        ),
+        RENAME_LABEL_COMMAND => rename_label(
+            store,
+            args.value_of(ID_ARG).unwrap(),
+            args.value_of(LABEL_ARG).unwrap(),
+        ),
+        RESET_COUNTER_COMMAND => reset_counter(
+            store,
+            args.value_of(ID_ARG).unwrap(),
+            args.is_present(FORCE_ARG),
+        ),
+        EXPORT_COMMAND => export(
+            store,
+            Path::new(args.value_of(BACKUP_PATH_ARG).unwrap()),
+            log,
+        ),
+        IMPORT_COMMAND => import(
+            store,
+            Path::new(args.value_of(BACKUP_PATH_ARG).unwrap()),
+            if args.is_present(REPLACE_ARG) {
+                Conflict::Replace
+            } else {
+                Conflict::Skip
+            },
+            log,
+        ),
+        MIGRATE_COMMAND => migrate(
+            store,
+            args.value_of(FROM_ARG).unwrap().parse()?,
+            config.secret_store_type,
+            args.is_present(DRY_RUN_ARG),
+            dirs,
+            log,
+        ),
+        _ => unreachable!("unknown subcommand {}", command),
+    }
+}
+
+fn list(store: &dyn UserSecretStore) -> Result<(), Error> {
+    let mut secrets = store.list_secrets()?;
+    secrets.sort_by_key(|secret| secret.registered);
+    println!(
+        "{:<12}  {:<24}  {:<20}  {:>10}  {}",
+        "ID", "APPLICATION", "REGISTERED", "COUNTER", "LABEL"
+    );
+    for secret in secrets {
+        println!(
+            "{:<12}  {:<24}  {:<20}  {:>10}  {}",
+            short_id(&secret),
+            application(&secret),
+            registered(&secret),
+            secret.counter,
+            secret.label.as_ref().map(String::as_str).unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+fn show(store: &dyn UserSecretStore, id: &str) -> Result<(), Error> {
+    let secret = find(store, id)?;
+    println!("ID:           {}", id_of(&secret));
+    println!("Application:  {}", application(&secret));
+    println!(
+        "App ID hash:  {}",
+        secret.application_key.application.to_base64()
+    );
+    println!(
+        "Label:        {}",
+        secret.label.as_ref().map(String::as_str).unwrap_or("")
+    );
+    println!("Registered:   {}", registered(&secret));
+    println!("Algorithm:    {:?}", secret.application_key.algorithm());
+    println!("Counter:      {}", secret.counter);
+    let metadata = store
+        .application_key_metadata(
+            &secret.application_key.application,
+            &secret.application_key.handle,
+        )?
+        .unwrap_or_default();
+    println!(
+        "Last used:    {}",
+        metadata
+            .last_used
+            .map(format_time)
+            .unwrap_or_else(|| String::from("never"))
+    );
+    println!("Use count:    {}", metadata.use_count);
+    match secret.user {
+        Some(ref user) => {
+            println!("Resident:     yes");
+            println!("User ID:      {}", base64::encode(&user.id));
+            if let Some(ref name) = user.name {
+                println!("User name:    {}", name);
+            }
+            if let Some(ref display_name) = user.display_name {
+                println!("Display name: {}", display_name);
+            }
+        }
+        None => println!("Resident:     no"),
+    }
+    Ok(())
+}
+
+/// Same operations the daemon uses, needed to destroy keys held outside the secret store
+fn crypto_operations(
+    config: &Config,
+    store: &Rc<dyn UserSecretStore>,
+    log: &Logger,
+) -> Result<Box<dyn CryptoOperations>, Error> {
+    let attestation = attestation::build(&config.attestation, store.as_ref(), log)?;
+    Ok(build_crypto_operations(config, store, attestation, log)?)
+}
+
+fn delete(
+    store: &dyn UserSecretStore,
+    operations: &dyn CryptoOperations,
+    id: &str,
+) -> Result<(), Error> {
+    let secret = find(store, id)?;
+    let removed = store.remove_application_key(
+        &secret.application_key.application,
+        &secret.application_key.handle,
+    )?;
+    if !removed {
+        return Err(CredentialLookupError::NotFound(id.to_string()).into());
+    }
+    operations.destroy_key(secret.application_key.key())?;
+    println!(
+        "Deleted credential {} for {}",
+        short_id(&secret),
+        application(&secret)
+    );
+    Ok(())
+}
+
+fn rename_label(store: &dyn UserSecretStore, id: &str, label: &str) -> Result<(), Error> {
+    let mut secret = find(store, id)?;
+    secret.label = if label.is_empty() {
+        None
+    } else {
+        Some(label.to_string())
+    };
+    store.update_secret(&secret)?;
+    println!(
+        "Renamed credential {} for {}",
+        short_id(&secret),
+        application(&secret)
+    );
+    Ok(())
+}
+
+fn reset_counter(store: &dyn UserSecretStore, id: &str, force: bool) -> Result<(), Error> {
+    let mut secret = find(store, id)?;
+    let strategy = store.counter_strategy();
+    if strategy.is_device_wide() {
+        return Err(ResetCounterDeviceWide(strategy).into());
+    }
+    if !force {
+        return Err(ResetCounterNotForced.into());
+    }
+    secret.counter = 0;
+    store.update_secret(&secret)?;
+    println!(
+        "Reset counter of credential {} for {}",
+        short_id(&secret),
+        application(&secret)
+    );
+    Ok(())
+}
+
+fn export(store: &dyn UserSecretStore, path: &Path, log: &Logger) -> Result<(), Error> {
+    let passphrase = passphrase::ask_backup(true, log)?;
+    let count = backup::export(store, path, &passphrase)?;
+    println!("Exported {} credentials to {}", count, path.display());
+    Ok(())
+}
+
+fn import(
+    store: &dyn UserSecretStore,
+    path: &Path,
+    conflict: Conflict,
+    log: &Logger,
+) -> Result<(), Error> {
+    let passphrase = passphrase::ask_backup(false, log)?;
+    let summary = backup::import(store, path, &passphrase, conflict)?;
+    println!(
+        "Imported {} credentials, replaced {}, skipped {} already registered",
+        summary.added, summary.replaced, summary.skipped
+    );
+    Ok(())
+}
+
+fn migrate(
+    store: &dyn UserSecretStore,
+    from: SecretStoreType,
+    configured: SecretStoreType,
+    dry_run: bool,
+    dirs: &AppDirs,
+    log: &Logger,
+) -> Result<(), Error> {
+    if from == configured {
+        return Err(MigrateToSameStore(from).into());
+    }
+    let summary = storage::migrate_from(dirs, from, store, dry_run, log)?;
+    if dry_run {
+        println!(
+            "Would copy {} credentials, {} already present",
+            summary.copied, summary.already_present
+        );
+    } else {
+        println!(
+            "Copied {} credentials, {} already present",
+            summary.copied, summary.already_present
+        );
+    }
+    Ok(())
+}
+
+fn find(store: &dyn UserSecretStore, id: &str) -> Result<Secret, Error> {
+    let mut matches = store
+        .list_secrets()?
+        .into_iter()
+        .filter(|secret| id_of(secret).starts_with(id));
+    match (matches.next(), matches.next()) {
+        (Some(secret), None) => Ok(secret),
+        (None, _) => Err(CredentialLookupError::NotFound(id.to_string()).into()),
+        (Some(_), Some(_)) => Err(CredentialLookupError::Ambiguous(id.to_string()).into()),
+    }
+}
+
+/// Credentials are identified by their key handle, which is unique and not secret
+fn id_of(secret: &Secret) -> String {
+    base64::encode_config(&secret.application_key.handle, base64::URL_SAFE_NO_PAD)
+}
+
+fn short_id(secret: &Secret) -> String {
+    id_of(secret).chars().take(SHORT_ID_LEN).collect()
+}
+
+fn application(secret: &Secret) -> String {
+    try_reverse_app_id(&secret.application_key.application)
+        .unwrap_or_else(|| secret.application_key.application.to_base64())
+}
+
+fn registered(secret: &Secret) -> String {
+    match secret.registered {
+        Some(seconds) => format_unix_time(seconds),
+        None => String::from("unknown"),
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    format_unix_time(
+        time.duration_since(UNIX_EPOCH)
+            .expect("time moved backwards")
+            .as_secs(),
+    )
+}
+
+fn format_unix_time(seconds: u64) -> String {
+    time::at_utc(time::Timespec::new(seconds as i64, 0))
+        .rfc3339()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use u2f_core::{self_signed_attestation, SecretStore, SecureCryptoOperations};
+
+    use super::*;
+    use stores::file_store_v2::FileStoreV2;
+    use stores::fixtures::fake_application_key;
+
+    use self::tempdir::TempDir;
+
+    /// Store with credentials whose ids are AAAA, AAAB and AQAA
+    fn store_with_credentials(dir: &TempDir) -> FileStoreV2 {
+        let store = FileStoreV2::new(dir.path()).unwrap();
+        for handle in &[[0, 0, 0], [0, 0, 1], [1, 0, 0]] {
+            store
+                .add_application_key(&fake_application_key(handle))
+                .unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn find_by_unique_prefix() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+
+        let secret = find(&store, "AQ").unwrap();
+
+        assert_eq!(id_of(&secret), "AQAA");
+    }
+
+    #[test]
+    fn find_by_full_id() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+
+        let secret = find(&store, "AAAB").unwrap();
+
+        assert_eq!(id_of(&secret), "AAAB");
+    }
+
+    #[test]
+    fn find_by_ambiguous_prefix_errors() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+
+        let err = find(&store, "AAA").unwrap_err();
+
+        match err.downcast::<CredentialLookupError>() {
+            Ok(CredentialLookupError::Ambiguous(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn find_unknown_id_errors() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+
+        let err = find(&store, "B").unwrap_err();
+
+        match err.downcast::<CredentialLookupError>() {
+            Ok(CredentialLookupError::NotFound(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rename_label_sets_and_clears_label() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+
+        rename_label(&store, "AQ", "laptop").unwrap();
+        assert_eq!(
+            find(&store, "AQ").unwrap().label,
+            Some(String::from("laptop"))
+        );
+
+        rename_label(&store, "AQ", "").unwrap();
+        assert_eq!(find(&store, "AQ").unwrap().label, None);
+    }
+
+    #[test]
+    fn delete_removes_only_that_credential() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+        let operations = SecureCryptoOperations::new(self_signed_attestation());
+
+        delete(&store, &operations, "AQ").unwrap();
+
+        let mut ids: Vec<String> = store.list_secrets().unwrap().iter().map(id_of).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["AAAA", "AAAB"]);
+        assert!(delete(&store, &operations, "AQ").is_err());
+    }
+
+    #[test]
+    fn reset_counter_without_force_errors() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+        let app_key = fake_application_key(&[1, 0, 0]);
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+
+        let err = reset_counter(&store, "AQ", false).unwrap_err();
+
+        assert!(err.downcast::<ResetCounterNotForced>().is_ok());
+        assert_eq!(find(&store, "AQ").unwrap().counter, 1);
+    }
+
+    #[test]
+    fn reset_counter_with_force_resets_counter() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = store_with_credentials(&dir);
+        let app_key = fake_application_key(&[1, 0, 0]);
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+
+        reset_counter(&store, "AQ", true).unwrap();
+
+        assert_eq!(find(&store, "AQ").unwrap().counter, 0);
+    }
+
+    #[test]
+    fn reset_counter_with_device_wide_strategy_errors() {
+        let dir = TempDir::new("cli_tests").unwrap();
+        let store = FileStoreV2::new(dir.path())
+            .unwrap()
+            .with_counter_strategy(CounterStrategy::Global);
+        let app_key = fake_application_key(&[1]);
+        store.add_application_key(&app_key).unwrap();
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        let id = id_of(&store.list_secrets().unwrap()[0]);
+
+        let err = reset_counter(&store, &id, true).unwrap_err();
+
+        assert!(err.downcast::<ResetCounterDeviceWide>().is_ok());
+        assert_eq!(store.list_secrets().unwrap()[0].counter, 1);
+    }
+}
@@ -2,22 +2,107 @@ use std::fs::File;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use serde_json;
+use u2f_core::CounterStrategy;
 
 use atomic_file;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct Config {
     pub(crate) secret_store_type: SecretStoreType,
+    /// App IDs, or base64 app ID hashes, allowed to authenticate without a user presence prompt
+    #[serde(default)]
+    pub(crate) silent_authentication_app_ids: Vec<String>,
+    /// Derive keys from a master secret and wrap them inside key handles instead of storing each key
+    #[serde(default)]
+    pub(crate) wrap_keys_in_key_handles: bool,
+    #[serde(default)]
+    pub(crate) attestation: AttestationSource,
+    /// Signature counters per credential, shared by every credential, or taken from the clock
+    #[serde(default)]
+    pub(crate) counter_strategy: CounterStrategy,
+    #[serde(default)]
+    pub(crate) crypto_backend: CryptoBackend,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub(crate) enum SecretStoreType {
     File,
+    /// File encrypted with a passphrase that is asked for when the daemon starts
+    EncryptedFile,
+    /// File encrypted with a key held in the kernel keyring, for machines without a Secret Service
+    KernelKeyring,
+    /// Unencrypted file per registration, for users with thousands of registrations
+    IndexedFile,
     SecretService,
 }
 
+impl FromStr for SecretStoreType {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<SecretStoreType> {
+        match s {
+            "file" => Ok(SecretStoreType::File),
+            "encrypted-file" => Ok(SecretStoreType::EncryptedFile),
+            "kernel-keyring" => Ok(SecretStoreType::KernelKeyring),
+            "indexed-file" => Ok(SecretStoreType::IndexedFile),
+            "secret-service" => Ok(SecretStoreType::SecretService),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown secret store type {}", s),
+            )),
+        }
+    }
+}
+
+/// Where the attestation certificate and key used to sign registrations come from
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum AttestationSource {
+    /// Key and certificate shared by every install, so relying parties cannot tell installs apart
+    Shared,
+    /// Certificate chain and key read from PEM files on every start
+    Files {
+        certificate_chain_path: PathBuf,
+        key_path: PathBuf,
+    },
+    /// Certificate chain and key kept in the secret store, imported from the PEM files if given
+    SecretStore {
+        certificate_chain_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+    },
+    /// Certificate and key generated on first start and kept in the secret store
+    Generated,
+}
+
+impl Default for AttestationSource {
+    fn default() -> AttestationSource {
+        AttestationSource::Shared
+    }
+}
+
+/// What generates the per-site keys and signs with them
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) enum CryptoBackend {
+    /// Keys generated by OpenSSL and kept in the secret store
+    OpenSSL,
+    /// Keys generated by ring and kept in the secret store
+    Ring,
+    /// Keys generated on a PKCS#11 token, only a reference to them is kept in the secret store
+    Pkcs11 {
+        module_path: PathBuf,
+        /// Label of the token to use, the first token found if not given
+        token_label: Option<String>,
+    },
+}
+
+impl Default for CryptoBackend {
+    fn default() -> CryptoBackend {
+        CryptoBackend::OpenSSL
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct ConfigFilePath(PathBuf);
 
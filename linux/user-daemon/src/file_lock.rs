@@ -0,0 +1,88 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc;
+
+/// Advisory lock on a file, released when dropped. Locks are taken on a
+/// separate lock file rather than the file they protect, as that file is
+/// replaced on every write.
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Wait until no other process or thread holds the lock
+    pub fn exclusive(path: &Path) -> io::Result<FileLock> {
+        FileLock::lock(path, libc::LOCK_EX)
+    }
+
+    /// Wait until no other process or thread holds the lock exclusively
+    pub fn shared(path: &Path) -> io::Result<FileLock> {
+        FileLock::lock(path, libc::LOCK_SH)
+    }
+
+    fn lock(path: &Path, operation: libc::c_int) -> io::Result<FileLock> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(FileLock { _file: file });
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn exclusive_lock_waits_for_shared_lock() {
+        let dir = TempDir::new("file_lock_tests").unwrap();
+        let path = dir.path().join("lock");
+        let shared = FileLock::shared(&path).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let thread_path = path.clone();
+        let handle = thread::spawn(move || {
+            let _exclusive = FileLock::exclusive(&thread_path).unwrap();
+            sender.send(()).unwrap();
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(shared);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn shared_locks_do_not_wait_for_each_other() {
+        let dir = TempDir::new("file_lock_tests").unwrap();
+        let path = dir.path().join("lock");
+        let _first = FileLock::shared(&path).unwrap();
+
+        let _second = FileLock::shared(&path).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 extern crate alloc;
+extern crate base64;
 extern crate bincode;
 extern crate clap;
 extern crate core;
@@ -10,10 +11,13 @@ extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate notify_rust;
+extern crate openssl;
 #[macro_use]
 extern crate quick_error;
 extern crate secret_service;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
@@ -30,6 +34,7 @@ extern crate u2f_core;
 extern crate u2fhid_protocol;
 
 use std::io;
+use std::rc::Rc;
 
 use clap::{App, Arg};
 use directories::{ProjectDirs, UserDirs};
@@ -41,17 +46,28 @@ use tokio_core::reactor::{Core, Handle};
 use tokio_io::codec::length_delimited;
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_uds::{UCred, UnixStream};
-use u2f_core::{SecretStore, SecureCryptoOperations, U2F};
+use u2f_core::{
+    Attestation, CryptoOperations, KeyWrappingCryptoOperations, Pkcs11CryptoOperations,
+    RingCryptoOperations, SecureCryptoOperations, U2F,
+};
 use u2fhid_protocol::{Packet, U2FHID};
 
+use config::{Config, CryptoBackend};
 use softu2f_system_daemon::{
     CreateDeviceError, CreateDeviceRequest, DeviceDescription, SocketInput, SocketOutput,
 };
 use storage::AppDirs;
+use stores::{SharedSecretStore, UserSecretStore};
 use user_presence::NotificationUserPresence;
 
 mod atomic_file;
+mod attestation;
+mod backup;
+mod cli;
 mod config;
+mod file_lock;
+mod migration;
+mod passphrase;
 mod storage;
 mod stores;
 mod user_presence;
@@ -126,13 +142,20 @@ fn main() -> Result<(), TransportError> {
             .long("socket")
             .takes_value(true)
             .help("Bind to specified socket path instead of file-descriptor from systemd"))
+        .subcommands(cli::subcommands())
         .after_help("By default expects to be run via systemd as root and passed a socket file-descriptor to listen on.")
         .get_matches();
 
+    if let (command, Some(command_args)) = args.subcommand() {
+        // Keep stdout for the command's own output
+        let logger = build_logger(std::io::stderr());
+        let dirs = app_dirs().map_err(|err| TransportError::Failure(err.compat()))?;
+        return cli::run(command, command_args, &dirs, &logger)
+            .map_err(|err| TransportError::Failure(err.compat()));
+    }
+
     let socket_path = args.value_of(PATH_ARG);
-    let decorator = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let logger = Logger::root(drain, o!());
+    let logger = build_logger(std::io::stdout());
 
     info!(logger, "Starting software Universal 2nd Factor device user daemon"; "version" => VERSION);
 
@@ -142,6 +165,15 @@ fn main() -> Result<(), TransportError> {
     core.run(connect(socket_path, handle, &logger))
 }
 
+fn build_logger<W>(writer: W) -> Logger
+where
+    W: io::Write + Send + 'static,
+{
+    let decorator = slog_term::PlainSyncDecorator::new(writer);
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    Logger::root(drain, o!())
+}
+
 fn connect(
     socket_path: &str,
     handle: Handle,
@@ -251,13 +283,33 @@ where
         .filter_map(move |output| socket_output_to_packet(&packet_logger, output))
         .with(|packet| future::ok(packet_to_socket_input(packet)));
 
-    let attestation = u2f_core::self_signed_attestation();
-    let user_presence = Box::new(NotificationUserPresence::new(&handle, log.new(o!())));
-    let operations = Box::new(SecureCryptoOperations::new(attestation));
-    let storage = match build_storage(log) {
-        Ok(store) => store,
+    let dirs = match app_dirs() {
+        Ok(dirs) => dirs,
         Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
     };
+    let config = match storage::determine_config(&dirs, log) {
+        Ok(config) => config,
+        Err(err) => return Box::new(future::err(TransportError::Io(err))),
+    };
+
+    let user_presence = Box::new(NotificationUserPresence::new(
+        &handle,
+        config.silent_authentication_app_ids.clone(),
+        log.new(o!()),
+    ));
+    let storage: Rc<dyn UserSecretStore> = match storage::build(&dirs, &config, log) {
+        Ok(store) => Rc::from(store),
+        Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
+    };
+    let attestation = match attestation::build(&config.attestation, storage.as_ref(), log) {
+        Ok(attestation) => attestation,
+        Err(err) => return Box::new(future::err(TransportError::Io(err))),
+    };
+    let operations = match build_crypto_operations(&config, &storage, attestation, log) {
+        Ok(operations) => operations,
+        Err(err) => return Box::new(future::err(TransportError::Io(err))),
+    };
+    let storage = Box::new(SharedSecretStore(storage));
     let service = match U2F::new(user_presence, operations, storage, log.new(o!())) {
         Ok(service) => service,
         Err(err) => return Box::new(future::err(TransportError::Io(err))),
@@ -273,6 +325,46 @@ where
     ))
 }
 
+fn build_crypto_operations(
+    config: &Config,
+    storage: &Rc<dyn UserSecretStore>,
+    attestation: Attestation,
+    log: &Logger,
+) -> io::Result<Box<dyn CryptoOperations>> {
+    match config.crypto_backend {
+        CryptoBackend::OpenSSL if config.wrap_keys_in_key_handles => {
+            let master_secret = storage.master_secret()?;
+            info!(log, "Wrapping keys inside key handles");
+            let saving_storage = storage.clone();
+            Ok(Box::new(
+                KeyWrappingCryptoOperations::new(attestation, master_secret)
+                    .with_master_secret_saver(move |master_secret| {
+                        saving_storage.set_master_secret(master_secret)
+                    }),
+            ))
+        }
+        CryptoBackend::OpenSSL => Ok(Box::new(SecureCryptoOperations::new(attestation))),
+        _ if config.wrap_keys_in_key_handles => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only keys generated by OpenSSL can be wrapped inside key handles",
+        )),
+        CryptoBackend::Ring => Ok(Box::new(RingCryptoOperations::new(attestation))),
+        CryptoBackend::Pkcs11 {
+            ref module_path,
+            ref token_label,
+        } => {
+            let pin = passphrase::ask_pkcs11_pin(log)?;
+            info!(log, "Generating keys on PKCS#11 token"; "module" => module_path.display());
+            Ok(Box::new(Pkcs11CryptoOperations::new(
+                module_path,
+                token_label.as_ref().map(String::as_str),
+                &pin,
+                attestation,
+            )?))
+        }
+    }
+}
+
 fn socket_output_to_packet(logger: &Logger, event: SocketOutput) -> Option<Packet> {
     match event {
         SocketOutput::Packet(raw_packet) => match Packet::from_bytes(&raw_packet.to_bytes()) {
@@ -293,19 +385,16 @@ fn packet_to_socket_input(packet: Packet) -> SocketInput {
     ))
 }
 
-fn build_storage(log: &Logger) -> Result<Box<dyn SecretStore>, Error> {
+fn app_dirs() -> Result<AppDirs, Error> {
     let user_dirs = UserDirs::new().ok_or(HomeDirectoryNotFound)?;
     let project_dirs =
         ProjectDirs::from("com.github", "danstiner", "Rust U2F").ok_or(HomeDirectoryNotFound)?;
 
-    storage::build(
-        &AppDirs {
-            user_home_dir: user_dirs.home_dir().to_owned(),
-            config_dir: project_dirs.config_dir().to_owned(),
-            data_local_dir: project_dirs.data_local_dir().to_owned(),
-        },
-        log,
-    )
+    Ok(AppDirs {
+        user_home_dir: user_dirs.home_dir().to_owned(),
+        config_dir: project_dirs.config_dir().to_owned(),
+        data_local_dir: project_dirs.data_local_dir().to_owned(),
+    })
 }
 
 fn require_root(cred: UCred) -> Result<(), TransportError> {
@@ -0,0 +1,105 @@
+use std::env;
+use std::io;
+use std::process::{Command, Stdio};
+
+use slog::Logger;
+
+const PASSPHRASE_ENV_VAR: &str = "SOFTU2F_PASSPHRASE";
+const ASK_PASSWORD_ID: &str = "softu2f:secrets";
+const ASK_PASSWORD_PROMPT: &str = "Passphrase to unlock Soft U2F secrets:";
+
+const PKCS11_PIN_ENV_VAR: &str = "SOFTU2F_PKCS11_PIN";
+const PKCS11_ASK_PASSWORD_ID: &str = "softu2f:pkcs11";
+const PKCS11_ASK_PASSWORD_PROMPT: &str = "PIN to unlock the PKCS#11 token for Soft U2F:";
+
+const BACKUP_PASSPHRASE_ENV_VAR: &str = "SOFTU2F_BACKUP_PASSPHRASE";
+const BACKUP_ASK_PASSWORD_ID: &str = "softu2f:backup";
+const BACKUP_ASK_PASSWORD_PROMPT: &str = "Passphrase for Soft U2F backup:";
+const BACKUP_CONFIRM_PROMPT: &str = "Repeat passphrase for Soft U2F backup:";
+
+/// Get the passphrase for the encrypted secret store, from the environment if
+/// set, otherwise by asking through systemd's password agents
+pub(crate) fn ask(log: &Logger) -> io::Result<String> {
+    ask_with(
+        PASSPHRASE_ENV_VAR,
+        ASK_PASSWORD_ID,
+        ASK_PASSWORD_PROMPT,
+        log,
+    )
+}
+
+/// Get the passphrase a backup is encrypted with, a new passphrase is asked
+/// for twice to catch typos
+pub(crate) fn ask_backup(new: bool, log: &Logger) -> io::Result<String> {
+    let passphrase = ask_with(
+        BACKUP_PASSPHRASE_ENV_VAR,
+        BACKUP_ASK_PASSWORD_ID,
+        BACKUP_ASK_PASSWORD_PROMPT,
+        log,
+    )?;
+    if new && env::var_os(BACKUP_PASSPHRASE_ENV_VAR).is_none() {
+        let confirmation = ask_password_agent(BACKUP_ASK_PASSWORD_ID, BACKUP_CONFIRM_PROMPT)?;
+        if confirmation != passphrase {
+            return Err(invalid_passphrase("passphrases do not match"));
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Get the user PIN of the PKCS#11 token keys are generated on, from the
+/// environment if set, otherwise by asking through systemd's password agents
+pub(crate) fn ask_pkcs11_pin(log: &Logger) -> io::Result<String> {
+    ask_with(
+        PKCS11_PIN_ENV_VAR,
+        PKCS11_ASK_PASSWORD_ID,
+        PKCS11_ASK_PASSWORD_PROMPT,
+        log,
+    )
+}
+
+fn ask_with(env_var: &str, id: &str, prompt: &str, log: &Logger) -> io::Result<String> {
+    if let Some(passphrase) = env::var_os(env_var) {
+        debug!(log, "Using passphrase from environment"; "variable" => env_var);
+        return passphrase
+            .into_string()
+            .map_err(|_| invalid_passphrase("passphrase is not valid unicode"))
+            .and_then(non_empty);
+    }
+
+    info!(log, "Asking for passphrase"; "id" => id);
+    ask_password_agent(id, prompt).and_then(non_empty)
+}
+
+fn ask_password_agent(id: &str, prompt: &str) -> io::Result<String> {
+    let output = Command::new("systemd-ask-password")
+        .arg(format!("--id={}", id))
+        .arg(prompt)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("asking for passphrase failed: {}", output.status),
+        ));
+    }
+
+    let mut passphrase = String::from_utf8(output.stdout)
+        .map_err(|_| invalid_passphrase("passphrase is not valid unicode"))?;
+    if passphrase.ends_with('\n') {
+        passphrase.pop();
+    }
+    Ok(passphrase)
+}
+
+fn non_empty(passphrase: String) -> io::Result<String> {
+    if passphrase.is_empty() {
+        Err(invalid_passphrase("passphrase is empty"))
+    } else {
+        Ok(passphrase)
+    }
+}
+
+fn invalid_passphrase(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -1,13 +1,23 @@
 use std::borrow::Borrow;
+use std::fs::File;
 use std::io;
 use std::path::PathBuf;
 
+use notify_rust::Notification;
+use serde_json;
 use slog::Logger;
-use u2f_core::SecretStore;
+use u2f_core::CounterStrategy;
 
-use config::{Config, ConfigFile, ConfigFilePath, SecretStoreType};
+use atomic_file;
+use config::{
+    AttestationSource, Config, ConfigFile, ConfigFilePath, CryptoBackend, SecretStoreType,
+};
+use migration;
+use passphrase;
 use stores::file_store::FileStore;
 use stores::file_store_v2::FileStoreV2;
+use stores::indexed_file_store::IndexedFileStore;
+use stores::kernel_keyring;
 use stores::secret_service_store::SecretServiceStore;
 use stores::UserSecretStore;
 
@@ -17,14 +27,55 @@ pub struct AppDirs {
     pub data_local_dir: PathBuf,
 }
 
-pub(crate) fn build(dirs: &AppDirs, log: &Logger) -> Result<Box<dyn SecretStore>, failure::Error> {
-    let config = determine_config(dirs, log)?;
-    let secret_store = build_secret_store(dirs, &config, log)?;
+/// Failed migrations are only logged until they happened this many times in a row
+const MIGRATION_FAILURES_BEFORE_NOTIFYING: u32 = 2;
+
+/// Which kind of secret store was last used, to notice when the configured one changes
+#[derive(Serialize, Deserialize)]
+struct SecretStoreState {
+    secret_store_type: SecretStoreType,
+    /// Failed attempts to migrate from secret_store_type to the configured store
+    #[serde(default)]
+    failed_migrations: u32,
+}
+
+pub(crate) fn build(
+    dirs: &AppDirs,
+    config: &Config,
+    log: &Logger,
+) -> Result<Box<dyn UserSecretStore>, failure::Error> {
+    let secret_store =
+        build_secret_store(dirs, config.secret_store_type, config.counter_strategy, log)?;
     migrate_legacy_file_store(dirs, secret_store.borrow(), log)?;
-    Ok(secret_store.into_u2f_store())
+    match config.secret_store_type {
+        SecretStoreType::EncryptedFile | SecretStoreType::KernelKeyring => {
+            migrate_unencrypted_file_store(dirs, secret_store.borrow(), log)?
+        }
+        SecretStoreType::File | SecretStoreType::IndexedFile | SecretStoreType::SecretService => {}
+    }
+    migrate_previous_secret_store(dirs, config.secret_store_type, secret_store.borrow(), log)?;
+    Ok(secret_store)
+}
+
+/// Copy secrets from a secret store other than the configured one, into the configured one
+pub(crate) fn migrate_from(
+    dirs: &AppDirs,
+    source_type: SecretStoreType,
+    secret_store: &dyn UserSecretStore,
+    dry_run: bool,
+    log: &Logger,
+) -> Result<migration::MigrationSummary, failure::Error> {
+    // Only read from, so its counters are never used
+    let source = build_secret_store(dirs, source_type, CounterStrategy::default(), log)?;
+    Ok(migration::migrate(
+        source.borrow(),
+        secret_store,
+        dry_run,
+        log,
+    )?)
 }
 
-fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
+pub(crate) fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
     let config_file_path = ConfigFilePath::from_dir(&dirs.config_dir);
     let config_file = match ConfigFile::load(config_file_path.clone())? {
         Some(config) => {
@@ -35,10 +86,19 @@ fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
             let secret_store_type: SecretStoreType;
             if SecretServiceStore::is_supported() {
                 secret_store_type = SecretStoreType::SecretService;
+            } else if kernel_keyring::is_supported() {
+                secret_store_type = SecretStoreType::KernelKeyring;
             } else {
                 secret_store_type = SecretStoreType::File;
             }
-            let config = Config { secret_store_type };
+            let config = Config {
+                secret_store_type,
+                silent_authentication_app_ids: Vec::new(),
+                wrap_keys_in_key_handles: false,
+                attestation: AttestationSource::default(),
+                counter_strategy: CounterStrategy::default(),
+                crypto_backend: CryptoBackend::default(),
+            };
             info!(log, "Creating configuration file"; "path" => config_file_path.get().display());
             ConfigFile::create(config_file_path, config)?
         }
@@ -48,25 +108,155 @@ fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
 
 fn build_secret_store(
     dirs: &AppDirs,
-    config: &Config,
+    secret_store_type: SecretStoreType,
+    counter_strategy: CounterStrategy,
     log: &Logger,
 ) -> Result<Box<dyn UserSecretStore>, failure::Error> {
-    match &config.secret_store_type {
+    debug!(log, "Using counter strategy"; "counter_strategy" => ?counter_strategy);
+    match secret_store_type {
         SecretStoreType::SecretService => {
             info!(
                 log,
                 "Storing secrets in your keychain using the D-Bus Secret Service API"
             );
-            Ok(Box::new(SecretServiceStore::new()?))
+            Ok(Box::new(
+                SecretServiceStore::new()?.with_counter_strategy(counter_strategy),
+            ))
         }
         SecretStoreType::File => {
             let store_dir = dirs.data_local_dir.as_path();
             warn!(log, "Storing secrets in an unencrypted file"; "dir" => store_dir.display());
-            Ok(Box::new(FileStoreV2::new(store_dir)?))
+            Ok(Box::new(
+                FileStoreV2::new(store_dir)?.with_counter_strategy(counter_strategy),
+            ))
+        }
+        SecretStoreType::IndexedFile => {
+            let store_dir = dirs.data_local_dir.as_path();
+            warn!(log, "Storing secrets in unencrypted indexed files"; "dir" => store_dir.display());
+            Ok(Box::new(
+                IndexedFileStore::new(store_dir)?.with_counter_strategy(counter_strategy),
+            ))
+        }
+        SecretStoreType::EncryptedFile => {
+            let store_dir = dirs.data_local_dir.as_path();
+            let passphrase = passphrase::ask(log)?;
+            let store = FileStoreV2::new_encrypted(store_dir, &passphrase)?
+                .with_counter_strategy(counter_strategy);
+            info!(log, "Storing secrets in a passphrase encrypted file"; "dir" => store_dir.display());
+            Ok(Box::new(store))
+        }
+        SecretStoreType::KernelKeyring => {
+            let store_dir = dirs.data_local_dir.as_path();
+            let store =
+                FileStoreV2::new_kernel_keyring(store_dir)?.with_counter_strategy(counter_strategy);
+            info!(log, "Storing secrets in a file encrypted with a key from the kernel keyring"; "dir" => store_dir.display());
+            Ok(Box::new(store))
+        }
+    }
+}
+
+fn migrate_unencrypted_file_store(
+    dirs: &AppDirs,
+    encrypted_store: &dyn UserSecretStore,
+    log: &Logger,
+) -> io::Result<()> {
+    let unencrypted_store = FileStoreV2::new(&dirs.data_local_dir)?;
+    if !unencrypted_store.exists() {
+        return Ok(());
+    }
+    info!(
+        log,
+        "copying secrets from unencrypted file to encrypted file"
+    );
+    migration::migrate(&unencrypted_store, encrypted_store, false, log)?;
+    info!(log, "finished copying secrets");
+    unencrypted_store.delete()?;
+    info!(log, "deleted unencrypted secrets file");
+    Ok(())
+}
+
+/// Secrets are left behind when the configured secret store type changes,
+/// copy them over. Failures are retried on the next start rather than
+/// stopping the daemon, the previous store is left as it was. Repeated
+/// failures are shown to the user as a notification.
+fn migrate_previous_secret_store(
+    dirs: &AppDirs,
+    secret_store_type: SecretStoreType,
+    secret_store: &dyn UserSecretStore,
+    log: &Logger,
+) -> io::Result<()> {
+    match read_secret_store_state(dirs)? {
+        Some(ref state) if state.secret_store_type == secret_store_type => return Ok(()),
+        Some(state) => {
+            info!(log, "Secret store type changed, migrating secrets"; "from" => ?state.secret_store_type, "to" => ?secret_store_type);
+            if let Err(err) = migrate_from(dirs, state.secret_store_type, secret_store, false, log)
+            {
+                let failed_migrations = state.failed_migrations + 1;
+                error!(log, "Migrating secrets failed, will retry on next start"; "error" => %err, "failed_migrations" => failed_migrations);
+                if failed_migrations >= MIGRATION_FAILURES_BEFORE_NOTIFYING {
+                    notify_migration_failure(state.secret_store_type, failed_migrations, &err, log);
+                }
+                return write_secret_store_state(
+                    dirs,
+                    SecretStoreState {
+                        secret_store_type: state.secret_store_type,
+                        failed_migrations,
+                    },
+                );
+            }
         }
+        None => {}
+    }
+    write_secret_store_state(
+        dirs,
+        SecretStoreState {
+            secret_store_type,
+            failed_migrations: 0,
+        },
+    )
+}
+
+fn notify_migration_failure(
+    from: SecretStoreType,
+    failed_migrations: u32,
+    err: &failure::Error,
+    log: &Logger,
+) {
+    let body = format!(
+        "Registrations in the previous {:?} secret store could not be copied after {} attempts, they cannot be used until they are: {}. Run the migrate command to retry.",
+        from, failed_migrations, err
+    );
+    let result = Notification::new()
+        .appname("SoftU2F")
+        .summary("Migrating secrets failed")
+        .body(&body)
+        .icon("dialog-error")
+        .show();
+    if let Err(err) = result {
+        warn!(log, "Failed to show notification"; "error" => %err);
+    }
+}
+
+fn read_secret_store_state(dirs: &AppDirs) -> io::Result<Option<SecretStoreState>> {
+    match File::open(secret_store_state_path(dirs)) {
+        Ok(file) => serde_json::from_reader(file)
+            .map(Some)
+            .map_err(|e| e.into()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
     }
 }
 
+fn write_secret_store_state(dirs: &AppDirs, state: SecretStoreState) -> io::Result<()> {
+    atomic_file::overwrite(&secret_store_state_path(dirs), move |writer| {
+        serde_json::to_writer_pretty(writer, &state).map_err(|e| e.into())
+    })
+}
+
+fn secret_store_state_path(dirs: &AppDirs) -> PathBuf {
+    dirs.data_local_dir.join("secret_store_state.json")
+}
+
 fn migrate_legacy_file_store(
     dirs: &AppDirs,
     secret_store: &dyn UserSecretStore,
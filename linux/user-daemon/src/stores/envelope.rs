@@ -0,0 +1,273 @@
+use std::fmt::{self, Debug};
+use std::io;
+
+use base64;
+use openssl::pkcs5::scrypt;
+use openssl::rand::rand_bytes;
+use openssl::sha::Sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_json;
+
+const ENVELOPE_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+// scrypt cost, about 128 MiB of memory and a second of CPU time to unlock
+const SCRYPT_LOG_N: u8 = 17;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Encrypted container for a secrets file, stored as JSON
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    version: u8,
+    /// Absent when the key is random rather than derived from a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<KdfParams>,
+    nonce: String,
+    ciphertext: String,
+    tag: String,
+}
+
+/// scrypt parameters the envelope key was derived with
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct KdfParams {
+    salt: String,
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl KdfParams {
+    fn generate(log_n: u8) -> io::Result<KdfParams> {
+        let mut salt = [0u8; SALT_LEN];
+        rand_bytes(&mut salt)?;
+        Ok(KdfParams {
+            salt: base64::encode(&salt),
+            log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        })
+    }
+
+    fn max_memory(&self) -> u64 {
+        let n = 1u64 << self.log_n;
+        128 * u64::from(self.r) * (n + u64::from(self.p) + 2)
+    }
+}
+
+/// Key for sealing and opening envelopes, either random or derived from a
+/// passphrase with the memory-hard scrypt function so offline guessing of
+/// the passphrase is slow
+pub(crate) struct EnvelopeKey {
+    params: Option<KdfParams>,
+    key: [u8; KEY_LEN],
+}
+
+impl EnvelopeKey {
+    /// Derive a key for a new envelope, with a fresh salt
+    pub fn generate(passphrase: &str) -> io::Result<EnvelopeKey> {
+        EnvelopeKey::derive(passphrase, KdfParams::generate(SCRYPT_LOG_N)?)
+    }
+
+    /// Derive the key for an existing envelope, fails if the passphrase is
+    /// wrong or the envelope has been tampered with
+    pub fn unlock(passphrase: &str, envelope: &Envelope) -> io::Result<EnvelopeKey> {
+        let params = envelope.kdf.clone().ok_or(invalid_data(
+            "encrypted file is not protected by a passphrase",
+        ))?;
+        let key = EnvelopeKey::derive(passphrase, params)?;
+        key.open(envelope)?;
+        Ok(key)
+    }
+
+    /// New random key, for keeping somewhere other than the encrypted file
+    pub fn random() -> io::Result<EnvelopeKey> {
+        let mut key = [0u8; KEY_LEN];
+        rand_bytes(&mut key)?;
+        Ok(EnvelopeKey { params: None, key })
+    }
+
+    /// Key tied to this machine through its machine ID, which is not kept
+    /// with the user's data so copies of that data cannot be opened elsewhere
+    pub fn for_machine(machine_id: &[u8]) -> io::Result<EnvelopeKey> {
+        if machine_id.is_empty() {
+            return Err(invalid_data("machine ID is empty"));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(b"softu2f machine key");
+        hasher.update(machine_id);
+        Ok(EnvelopeKey {
+            params: None,
+            key: hasher.finish(),
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<EnvelopeKey> {
+        if bytes.len() != KEY_LEN {
+            return Err(invalid_data("encryption key has the wrong length"));
+        }
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(bytes);
+        Ok(EnvelopeKey { params: None, key })
+    }
+
+    fn derive(passphrase: &str, params: KdfParams) -> io::Result<EnvelopeKey> {
+        if params.log_n == 0 || params.log_n > 24 {
+            return Err(invalid_data("unsupported key derivation parameters"));
+        }
+        let salt = base64::decode(&params.salt).map_err(|_| invalid_data("invalid salt"))?;
+        let mut key = [0u8; KEY_LEN];
+        scrypt(
+            passphrase.as_bytes(),
+            &salt,
+            1u64 << params.log_n,
+            u64::from(params.r),
+            u64::from(params.p),
+            params.max_memory(),
+            &mut key,
+        )?;
+        Ok(EnvelopeKey {
+            params: Some(params),
+            key,
+        })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> io::Result<Envelope> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &additional_data(ENVELOPE_VERSION, &self.params)?,
+            plaintext,
+            &mut tag,
+        )?;
+        Ok(Envelope {
+            version: ENVELOPE_VERSION,
+            kdf: self.params.clone(),
+            nonce: base64::encode(&nonce),
+            ciphertext: base64::encode(&ciphertext),
+            tag: base64::encode(&tag),
+        })
+    }
+
+    pub fn open(&self, envelope: &Envelope) -> io::Result<Vec<u8>> {
+        if envelope.version != ENVELOPE_VERSION {
+            return Err(invalid_data("unsupported encrypted file version"));
+        }
+        if envelope.kdf != self.params {
+            return Err(invalid_data(
+                "encrypted file was sealed with a different key",
+            ));
+        }
+        let nonce = base64::decode(&envelope.nonce).map_err(|_| invalid_data("invalid nonce"))?;
+        let ciphertext =
+            base64::decode(&envelope.ciphertext).map_err(|_| invalid_data("invalid ciphertext"))?;
+        let tag = base64::decode(&envelope.tag).map_err(|_| invalid_data("invalid tag"))?;
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &additional_data(envelope.version, &envelope.kdf)?,
+            &ciphertext,
+            &tag,
+        )
+        .map_err(|_| invalid_data("wrong passphrase or corrupted encrypted file"))
+    }
+}
+
+impl Debug for EnvelopeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EnvelopeKey")
+    }
+}
+
+impl AsRef<[u8]> for EnvelopeKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+/// Authenticate the header along with the ciphertext, so the version and key
+/// derivation parameters cannot be swapped out
+fn additional_data(version: u8, params: &Option<KdfParams>) -> io::Result<Vec<u8>> {
+    let mut data = vec![version];
+    data.extend_from_slice(&serde_json::to_vec(params)?);
+    Ok(data)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+pub(crate) fn fast_key(passphrase: &str) -> EnvelopeKey {
+    EnvelopeKey::derive(passphrase, KdfParams::generate(4).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_sealed_returns_plaintext() {
+        let key = fast_key("correct horse");
+
+        let envelope = key.seal(b"secrets").unwrap();
+
+        assert_eq!(key.open(&envelope).unwrap(), b"secrets".to_vec());
+    }
+
+    #[test]
+    fn unlock_with_wrong_passphrase_errors() {
+        let envelope = fast_key("correct horse").seal(b"secrets").unwrap();
+
+        let err = EnvelopeKey::unlock("battery staple", &envelope).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn open_with_tampered_params_errors() {
+        let key = fast_key("correct horse");
+        let mut envelope = key.seal(b"secrets").unwrap();
+        envelope.kdf.as_mut().unwrap().r += 1;
+
+        assert!(EnvelopeKey::unlock("correct horse", &envelope).is_err());
+    }
+
+    #[test]
+    fn open_with_random_key_from_bytes_returns_plaintext() {
+        let key = EnvelopeKey::random().unwrap();
+        let envelope = key.seal(b"secrets").unwrap();
+
+        let reloaded = EnvelopeKey::from_bytes(key.as_ref()).unwrap();
+
+        assert_eq!(reloaded.open(&envelope).unwrap(), b"secrets".to_vec());
+        assert!(EnvelopeKey::unlock("correct horse", &envelope).is_err());
+    }
+
+    #[test]
+    fn open_with_key_for_other_machine_errors() {
+        let envelope = EnvelopeKey::for_machine(b"machine a")
+            .unwrap()
+            .seal(b"secrets")
+            .unwrap();
+
+        let other = EnvelopeKey::for_machine(b"machine b").unwrap();
+
+        assert!(other.open(&envelope).is_err());
+        assert_eq!(
+            EnvelopeKey::for_machine(b"machine a")
+                .unwrap()
+                .open(&envelope)
+                .unwrap(),
+            b"secrets".to_vec()
+        );
+    }
+}
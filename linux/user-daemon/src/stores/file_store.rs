@@ -46,6 +46,11 @@ impl FileStore {
                 Secret {
                     application_key: application_key.clone(),
                     counter: *counter,
+                    user: None,
+                    registered: None,
+                    label: None,
+                    last_used: None,
+                    use_count: 0,
                 }
             })
             .collect();
@@ -0,0 +1,635 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use base64;
+use openssl::sha::Sha256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use u2f_core::{
+    AppId, ApplicationKey, ApplicationKeyMetadata, Attestation, Counter, CounterStrategy,
+    KeyHandle, MasterSecret, ResidentCredential, SecretStore,
+};
+
+use atomic_file;
+use file_lock::FileLock;
+use stores::{DeviceCounters, Secret, UserSecretStore};
+
+/// Everything that is neither a registration nor a counter
+#[derive(Serialize, Deserialize, Default)]
+struct Meta {
+    #[serde(default)]
+    master_secret: Option<MasterSecret>,
+    #[serde(default)]
+    attestation: Option<Attestation>,
+}
+
+/// The parts of a secret that change on every signature, kept apart so
+/// signing does not rewrite the key
+#[derive(Serialize, Deserialize)]
+struct Usage {
+    counter: Counter,
+    last_used: Option<u64>,
+    use_count: u64,
+}
+
+/// Keeps each registration in a file of its own, named by a hash of its
+/// application and key handle, so finding one does not read the others and
+/// signing only rewrites small files of counters
+pub struct IndexedFileStore {
+    dir: PathBuf,
+    counter_strategy: CounterStrategy,
+}
+
+impl IndexedFileStore {
+    pub fn new(dir: &Path) -> io::Result<IndexedFileStore> {
+        Ok(IndexedFileStore {
+            dir: dir.join("secrets.indexed"),
+            counter_strategy: CounterStrategy::default(),
+        })
+    }
+
+    pub fn with_counter_strategy(self, counter_strategy: CounterStrategy) -> IndexedFileStore {
+        IndexedFileStore {
+            counter_strategy,
+            ..self
+        }
+    }
+
+    fn lock_shared(&self) -> io::Result<FileLock> {
+        FileLock::shared(&self.dir.join("lock"))
+    }
+
+    fn lock_exclusive(&self) -> io::Result<FileLock> {
+        FileLock::exclusive(&self.dir.join("lock"))
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.dir.join("store.json")
+    }
+
+    /// Device counters, kept apart from the master secret and attestation as
+    /// they change on every signature
+    fn counters_path(&self) -> PathBuf {
+        self.dir.join("counters.json")
+    }
+
+    fn keys_dir(&self) -> PathBuf {
+        self.dir.join("keys")
+    }
+
+    fn key_path(&self, index: &str) -> PathBuf {
+        self.keys_dir().join(format!("{}.json", index))
+    }
+
+    fn usage_path(&self, index: &str) -> PathBuf {
+        self.dir.join("usage").join(format!("{}.json", index))
+    }
+
+    fn read_meta(&self) -> io::Result<Meta> {
+        Ok(read_json(&self.meta_path())?.unwrap_or_default())
+    }
+
+    fn read_counters(&self) -> io::Result<DeviceCounters> {
+        Ok(read_json(&self.counters_path())?.unwrap_or_default())
+    }
+
+    fn update_meta<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Meta),
+    {
+        let _lock = self.lock_exclusive()?;
+        let mut meta = self.read_meta()?;
+        f(&mut meta);
+        write_json(&self.meta_path(), &meta)
+    }
+
+    /// The secret with the given application and key handle, with its usage applied
+    fn find_secret(&self, application: &AppId, handle: &KeyHandle) -> io::Result<Option<Secret>> {
+        let index = index(application, handle);
+        match read_json::<Secret>(&self.key_path(&index))? {
+            // The index is only a hash, the secret must still match
+            Some(ref secret) if !secret.matches(application, handle) => Ok(None),
+            Some(secret) => self.apply_usage(&index, secret).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn apply_usage(&self, index: &str, mut secret: Secret) -> io::Result<Secret> {
+        if let Some(usage) = read_json::<Usage>(&self.usage_path(index))? {
+            secret.counter = usage.counter;
+            secret.last_used = usage.last_used;
+            secret.use_count = usage.use_count;
+        }
+        Ok(secret)
+    }
+
+    fn read_secrets(&self) -> io::Result<Vec<Secret>> {
+        let entries = match fs::read_dir(self.keys_dir()) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut secrets = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            // Skip files left behind by interrupted writes
+            if path
+                .extension()
+                .map_or(true, |extension| extension != "json")
+            {
+                continue;
+            }
+            let index = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(index) => index.to_string(),
+                None => continue,
+            };
+            if let Some(secret) = read_json(&path)? {
+                secrets.push(self.apply_usage(&index, secret)?);
+            }
+        }
+        secrets.sort_by_key(|secret| secret.registered);
+        Ok(secrets)
+    }
+
+    fn write_secret(&self, secret: &Secret) -> io::Result<()> {
+        let index = index(
+            &secret.application_key.application,
+            &secret.application_key.handle,
+        );
+        write_json(&self.key_path(&index), secret)?;
+        write_json(
+            &self.usage_path(&index),
+            &Usage {
+                counter: secret.counter,
+                last_used: secret.last_used,
+                use_count: secret.use_count,
+            },
+        )
+    }
+
+    fn delete_secret(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        if self.find_secret(application, handle)?.is_none() {
+            return Ok(false);
+        }
+        let index = index(application, handle);
+        remove_if_exists(&self.usage_path(&index))?;
+        remove_if_exists(&self.key_path(&index))?;
+        Ok(true)
+    }
+}
+
+impl UserSecretStore for IndexedFileStore {
+    fn add_secret(&self, secret: Secret) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        self.write_secret(&secret)
+    }
+
+    fn list_secrets(&self) -> io::Result<Vec<Secret>> {
+        let _lock = self.lock_shared()?;
+        self.read_secrets()
+    }
+
+    fn update_secret(&self, secret: &Secret) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        let application = &secret.application_key.application;
+        let handle = &secret.application_key.handle;
+        if self.find_secret(application, handle)?.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such secret"));
+        }
+        self.write_secret(secret)
+    }
+
+    fn find_master_secret(&self) -> io::Result<Option<MasterSecret>> {
+        let _lock = self.lock_shared()?;
+        Ok(self.read_meta()?.master_secret)
+    }
+
+    fn set_master_secret(&self, master_secret: &MasterSecret) -> io::Result<()> {
+        self.update_meta(|meta| meta.master_secret = Some(master_secret.clone()))
+    }
+
+    fn device_counters(&self) -> io::Result<DeviceCounters> {
+        let _lock = self.lock_shared()?;
+        self.read_counters()
+    }
+
+    fn raise_device_counters(&self, counters: &DeviceCounters) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        let mut stored = self.read_counters()?;
+        counters.raise(&mut stored.counter, &mut stored.wrapped_keys);
+        write_json(&self.counters_path(), &stored)
+    }
+
+    fn attestation(&self) -> io::Result<Option<Attestation>> {
+        let _lock = self.lock_shared()?;
+        Ok(self.read_meta()?.attestation)
+    }
+
+    fn set_attestation(&self, attestation: &Attestation) -> io::Result<()> {
+        self.update_meta(|meta| meta.attestation = Some(attestation.clone()))
+    }
+}
+
+impl SecretStore for IndexedFileStore {
+    fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
+        self.add_secret(Secret::new(key.clone(), None))
+    }
+
+    fn get_and_increment_counter(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Counter> {
+        let _lock = self.lock_exclusive()?;
+        let mut counters = self.read_counters()?;
+        let strategy = self.counter_strategy;
+        let now = SystemTime::now();
+        let new_counter = match self.find_secret(application, handle)? {
+            Some(mut secret) => {
+                secret.mark_used();
+                secret.counter = strategy.next(Some(secret.counter), counters.counter, now);
+                write_json(
+                    &self.usage_path(&index(application, handle)),
+                    &Usage {
+                        counter: secret.counter,
+                        last_used: secret.last_used,
+                        use_count: secret.use_count,
+                    },
+                )?;
+                secret.counter
+            }
+            None => {
+                let counter = counters.wrapped_keys.get(application).cloned();
+                let new_counter = strategy.next(counter, counters.counter, now);
+                // Wrapped keys only get a counter of their own when counting per credential
+                if counter.is_some() || !strategy.is_device_wide() {
+                    counters.wrapped_keys.insert(*application, new_counter);
+                }
+                new_counter
+            }
+        };
+        counters.counter = counters.counter.max(new_counter);
+        write_json(&self.counters_path(), &counters)?;
+        Ok(new_counter)
+    }
+
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        let _lock = self.lock_shared()?;
+        Ok(self
+            .find_secret(application, handle)?
+            .map(|secret| secret.application_key))
+    }
+
+    fn remove_all_application_keys(&self) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        for dir in &[self.keys_dir(), self.dir.join("usage")] {
+            match fs::remove_dir_all(dir) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        // The attestation identifies the device rather than any registration, keep it.
+        // Device counters are kept too, relying parties may still know old ones.
+        let meta = self.read_meta()?;
+        write_json(
+            &self.meta_path(),
+            &Meta {
+                attestation: meta.attestation,
+                ..Meta::default()
+            },
+        )
+    }
+
+    fn list_application_keys(&self) -> io::Result<Vec<ApplicationKey>> {
+        Ok(self
+            .list_secrets()?
+            .into_iter()
+            .map(|secret| secret.application_key)
+            .collect())
+    }
+
+    fn remove_application_key(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        let _lock = self.lock_exclusive()?;
+        self.delete_secret(application, handle)
+    }
+
+    fn application_key_metadata(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKeyMetadata>> {
+        let _lock = self.lock_shared()?;
+        Ok(self
+            .find_secret(application, handle)?
+            .map(|secret| secret.metadata()))
+    }
+
+    fn counter_strategy(&self) -> CounterStrategy {
+        self.counter_strategy
+    }
+
+    fn add_resident_credential(&self, credential: &ResidentCredential) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        // Resident credentials are rare, finding them by scanning every secret is fine
+        for secret in self.read_secrets()? {
+            let replaced = match secret.user {
+                Some(ref user) => {
+                    secret
+                        .application_key
+                        .application
+                        .eq_consttime(&credential.application_key.application)
+                        && user.id == credential.user.id
+                }
+                None => false,
+            };
+            if replaced {
+                self.delete_secret(
+                    &secret.application_key.application,
+                    &secret.application_key.handle,
+                )?;
+            }
+        }
+        self.write_secret(&Secret::new(
+            credential.application_key.clone(),
+            Some(credential.user.clone()),
+        ))
+    }
+
+    fn list_resident_credentials(
+        &self,
+        application: &AppId,
+    ) -> io::Result<Vec<ResidentCredential>> {
+        Ok(self
+            .list_secrets()?
+            .into_iter()
+            .rev()
+            .filter(|s| s.application_key.application.eq_consttime(application))
+            .filter_map(|s| {
+                let application_key = s.application_key;
+                s.user.map(|user| ResidentCredential {
+                    application_key,
+                    user,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Name of the files of a registration. A hash, so file names do not reveal
+/// key handles or which sites are registered.
+fn index(application: &AppId, handle: &KeyHandle) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(application.as_ref());
+    hasher.update(handle.as_ref());
+    base64::encode_config(&hasher.finish(), base64::URL_SAFE_NO_PAD)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    match File::open(path) {
+        Ok(file) => serde_json::from_reader(file)
+            .map(Some)
+            .map_err(|e| e.into()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Callers must hold the exclusive lock
+fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    atomic_file::remove_interrupted(path)?;
+    atomic_file::overwrite(path, move |writer| {
+        serde_json::to_writer(writer, value).map_err(|e| e.into())
+    })
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::os::unix::fs::MetadataExt;
+    use std::time::Instant;
+
+    use u2f_core::User;
+
+    use super::*;
+    use stores::fixtures::{fake_app_id, fake_key};
+
+    use self::tempdir::TempDir;
+
+    fn fake_application_key(n: u32) -> ApplicationKey {
+        let handle = [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8];
+        ApplicationKey::new(fake_app_id(), KeyHandle::from(&handle), fake_key())
+    }
+
+    #[test]
+    fn get_and_increment_counter() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(0);
+        store.add_application_key(&app_key).unwrap();
+
+        let counter0 = store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        let counter1 = store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+
+        assert_eq!(counter0 + 1, counter1);
+        assert_eq!(store.list_secrets().unwrap()[0].counter, counter1);
+    }
+
+    #[test]
+    fn retrieve_finds_only_matching_key() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(1);
+        store.add_application_key(&app_key).unwrap();
+
+        assert!(store
+            .retrieve_application_key(&app_key.application, &app_key.handle)
+            .unwrap()
+            .is_some());
+        assert!(store
+            .retrieve_application_key(&app_key.application, &fake_application_key(2).handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn secret_in_wrong_index_is_not_returned() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(1);
+        let other_app_key = fake_application_key(2);
+        store.add_application_key(&other_app_key).unwrap();
+        fs::rename(
+            store.key_path(&index(&other_app_key.application, &other_app_key.handle)),
+            store.key_path(&index(&app_key.application, &app_key.handle)),
+        )
+        .unwrap();
+
+        assert!(store
+            .retrieve_application_key(&app_key.application, &app_key.handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn increment_does_not_rewrite_key() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(0);
+        store.add_application_key(&app_key).unwrap();
+        let key_path = store.key_path(&index(&app_key.application, &app_key.handle));
+        let inode = fs::metadata(&key_path).unwrap().ino();
+
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+
+        assert_eq!(fs::metadata(&key_path).unwrap().ino(), inode);
+    }
+
+    #[test]
+    fn increment_does_not_rewrite_master_secret() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(0);
+        store.add_application_key(&app_key).unwrap();
+        store.master_secret().unwrap();
+        let inode = fs::metadata(store.meta_path()).unwrap().ino();
+
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        store
+            .get_and_increment_counter(&fake_app_id(), &fake_application_key(1).handle)
+            .unwrap();
+
+        assert_eq!(fs::metadata(store.meta_path()).unwrap().ino(), inode);
+    }
+
+    #[test]
+    fn remove_all_application_keys_keeps_device_counters() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path())
+            .unwrap()
+            .with_counter_strategy(CounterStrategy::Global);
+        let app_key = fake_application_key(1);
+        // Wrapped in its key handle, no secret stored
+        let wrapped_key = fake_application_key(2);
+        store.add_application_key(&app_key).unwrap();
+        store.master_secret().unwrap();
+        store
+            .get_and_increment_counter(&app_key.application, &app_key.handle)
+            .unwrap();
+        store
+            .get_and_increment_counter(&wrapped_key.application, &wrapped_key.handle)
+            .unwrap();
+        let counters = store.device_counters().unwrap();
+
+        store.remove_all_application_keys().unwrap();
+
+        assert!(store.list_application_keys().unwrap().is_empty());
+        assert!(store.find_master_secret().unwrap().is_none());
+        assert_eq!(store.device_counters().unwrap(), counters);
+    }
+
+    #[test]
+    fn remove_application_key_removes_only_matching_key() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let app_key = fake_application_key(1);
+        let other_app_key = fake_application_key(2);
+        store.add_application_key(&app_key).unwrap();
+        store.add_application_key(&other_app_key).unwrap();
+
+        assert!(store
+            .remove_application_key(&app_key.application, &app_key.handle)
+            .unwrap());
+
+        assert!(!store
+            .remove_application_key(&app_key.application, &app_key.handle)
+            .unwrap());
+        assert_eq!(store.list_application_keys().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn resident_credential_replaces_same_user() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let user = User {
+            id: vec![1, 2, 3],
+            name: Some(String::from("user")),
+            display_name: None,
+        };
+        for n in 0..2 {
+            store
+                .add_resident_credential(&ResidentCredential {
+                    application_key: fake_application_key(n),
+                    user: user.clone(),
+                })
+                .unwrap();
+        }
+        store.add_application_key(&fake_application_key(2)).unwrap();
+
+        let credentials = store.list_resident_credentials(&fake_app_id()).unwrap();
+
+        assert_eq!(credentials.len(), 1);
+        assert_eq!(
+            credentials[0].application_key.handle,
+            fake_application_key(1).handle
+        );
+    }
+
+    fn time_increments(store: &IndexedFileStore, credentials: u32) -> f64 {
+        const INCREMENTS: u32 = 1000;
+        let start = Instant::now();
+        for n in 0..INCREMENTS {
+            let app_key = fake_application_key(n * 7919 % credentials);
+            store
+                .get_and_increment_counter(&app_key.application, &app_key.handle)
+                .unwrap();
+        }
+        let elapsed = start.elapsed();
+        (elapsed.as_secs() as f64 * 1e6 + f64::from(elapsed.subsec_micros()))
+            / f64::from(INCREMENTS)
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to see the timings
+    #[test]
+    #[ignore]
+    fn benchmark_increment_with_thousands_of_credentials() {
+        let dir = TempDir::new("indexed_file_store_tests").unwrap();
+        let store = IndexedFileStore::new(dir.path()).unwrap();
+        let mut registered = 0;
+        for &credentials in &[100, 1_000, 10_000] {
+            while registered < credentials {
+                store
+                    .add_application_key(&fake_application_key(registered))
+                    .unwrap();
+                registered += 1;
+            }
+            let micros = time_increments(&store, credentials);
+            println!(
+                "{:>6} credentials: {:>8.1}µs per increment",
+                credentials, micros
+            );
+        }
+    }
+}
@@ -0,0 +1,233 @@
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+
+use libc::{self, c_long};
+
+// From linux/keyctl.h
+const KEY_SPEC_USER_KEYRING: c_long = -4;
+const KEYCTL_GET_KEYRING_ID: c_long = 0;
+const KEYCTL_SETPERM: c_long = 5;
+const KEYCTL_SEARCH: c_long = 10;
+const KEYCTL_READ: c_long = 11;
+#[cfg(test)]
+const KEYCTL_INVALIDATE: c_long = 21;
+
+// Full access when possessed, read only for other processes of the same user
+const KEY_POS_ALL: u32 = 0x3f00_0000;
+const KEY_USR_VIEW: u32 = 0x0001_0000;
+const KEY_USR_READ: u32 = 0x0002_0000;
+const KEY_USR_SEARCH: u32 = 0x0008_0000;
+
+const USER_KEY_TYPE: &str = "user";
+
+/// Whether the kernel key retention service is available to this process
+pub fn is_supported() -> bool {
+    unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            KEYCTL_GET_KEYRING_ID,
+            KEY_SPEC_USER_KEYRING,
+            0 as c_long,
+        ) >= 0
+    }
+}
+
+/// Keys held outside the file they decrypt
+pub trait Keyring {
+    /// Payload of the key with the description, None if there is no such key
+    fn find_key(&self, description: &str) -> io::Result<Option<Vec<u8>>>;
+    /// Add a key, replacing any key with the same description
+    fn add_key(&self, description: &str, payload: &[u8]) -> io::Result<()>;
+}
+
+/// The kernel user keyring of the user the daemon runs as
+pub struct UserKeyring;
+
+impl Keyring for UserKeyring {
+    fn find_key(&self, description: &str) -> io::Result<Option<Vec<u8>>> {
+        find_key(description)
+    }
+
+    fn add_key(&self, description: &str, payload: &[u8]) -> io::Result<()> {
+        add_key(description, payload)
+    }
+}
+
+/// Keyring kept in memory, so tests do not depend on the kernel keyring
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeKeyring(RefCell<HashMap<String, Vec<u8>>>);
+
+#[cfg(test)]
+impl FakeKeyring {
+    /// Remove a key, as if the machine had been rebooted
+    pub fn remove_key(&self, description: &str) {
+        self.0.borrow_mut().remove(description);
+    }
+}
+
+#[cfg(test)]
+impl Keyring for FakeKeyring {
+    fn find_key(&self, description: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.0.borrow().get(description).cloned())
+    }
+
+    fn add_key(&self, description: &str, payload: &[u8]) -> io::Result<()> {
+        self.0
+            .borrow_mut()
+            .insert(description.to_owned(), payload.to_vec());
+        Ok(())
+    }
+}
+
+/// Read the payload of a key in the user keyring, None if there is no such key
+fn find_key(description: &str) -> io::Result<Option<Vec<u8>>> {
+    let serial = match search(description)? {
+        Some(serial) => serial,
+        None => return Ok(None),
+    };
+
+    let mut payload = Vec::new();
+    loop {
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                serial,
+                payload.as_mut_ptr(),
+                payload.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = len as usize;
+        // The key may have been updated between reads, retry until it fits
+        if len <= payload.len() {
+            payload.truncate(len);
+            return Ok(Some(payload));
+        }
+        payload.resize(len, 0);
+    }
+}
+
+/// Add a key to the user keyring, replacing any key with the same description
+fn add_key(description: &str, payload: &[u8]) -> io::Result<()> {
+    let key_type = c_string(USER_KEY_TYPE)?;
+    let description = c_string(description)?;
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            payload.as_ptr(),
+            payload.len(),
+            KEY_SPEC_USER_KEYRING,
+        )
+    };
+    if serial < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let permissions = c_long::from(KEY_POS_ALL | KEY_USR_VIEW | KEY_USR_READ | KEY_USR_SEARCH);
+    let result = unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_SETPERM, serial, permissions) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Invalidate a key in the user keyring, as if the machine had been rebooted
+#[cfg(test)]
+fn remove_key(description: &str) -> io::Result<()> {
+    let serial = search(description)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such key"))?;
+    let result = unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, serial) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Serial number of a key in the user keyring
+fn search(description: &str) -> io::Result<Option<c_long>> {
+    let key_type = c_string(USER_KEY_TYPE)?;
+    let description = c_string(description)?;
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            KEYCTL_SEARCH,
+            KEY_SPEC_USER_KEYRING,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            0 as c_long,
+        )
+    };
+    if serial < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOKEY) | Some(libc::EKEYEXPIRED) | Some(libc::EKEYREVOKED) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(serial))
+}
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Key description that no other test or running daemon uses
+#[cfg(test)]
+fn unique_test_description(name: &str) -> String {
+    use std::process;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time moved backwards")
+        .subsec_nanos();
+    format!("softu2f-test:{}:{}:{}", name, process::id(), nanos)
+}
+
+// These use the kernel keyring of the user running them, which is not
+// available in every sandbox, run with `cargo test -- --ignored`
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore]
+    fn missing_key_is_none() {
+        let description = unique_test_description("missing");
+
+        assert_eq!(find_key(&description).unwrap(), None);
+    }
+
+    #[test]
+    #[ignore]
+    fn added_key_is_found() {
+        let description = unique_test_description("added");
+        add_key(&description, &[1, 2, 3]).unwrap();
+
+        let payload = find_key(&description).unwrap();
+        remove_key(&description).unwrap();
+
+        assert_eq!(payload, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    #[ignore]
+    fn removed_key_is_none() {
+        let description = unique_test_description("removed");
+        add_key(&description, &[1, 2, 3]).unwrap();
+
+        remove_key(&description).unwrap();
+
+        assert_eq!(find_key(&description).unwrap(), None);
+    }
+}
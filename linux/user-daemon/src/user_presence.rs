@@ -1,8 +1,10 @@
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use futures::future;
 use futures::prelude::*;
-use futures_cpupool::CpuPool;
+use futures_cpupool::{CpuFuture, CpuPool};
 use notify_rust::{self, Notification, NotificationHint, NotificationUrgency};
 use slog::Logger;
 use time::Duration;
@@ -24,13 +26,19 @@ lazy_static! {
 pub struct NotificationUserPresence {
     executor: CpuPool,
     logger: Logger,
+    silent_authentication_app_ids: Vec<String>,
 }
 
 impl NotificationUserPresence {
-    pub fn new(_handle: &Handle, logger: Logger) -> NotificationUserPresence {
+    pub fn new(
+        _handle: &Handle,
+        silent_authentication_app_ids: Vec<String>,
+        logger: Logger,
+    ) -> NotificationUserPresence {
         NotificationUserPresence {
             executor: CpuPool::new(MAX_CONCURRENT_NOTIFICATIONS),
             logger,
+            silent_authentication_app_ids,
         }
     }
 
@@ -39,8 +47,12 @@ impl NotificationUserPresence {
 
         let body = message.to_owned();
         let logger = self.logger.clone();
+        let notification_id = Arc::new(Mutex::new(None));
+        let shown_notification_id = notification_id.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let shown_cancelled = cancelled.clone();
 
-        Box::new(self.executor.spawn_fn(move || {
+        let result = self.executor.spawn_fn(move || {
             let mut notification = Notification::new();
             notification
                 .appname(APPNAME)
@@ -66,6 +78,16 @@ impl NotificationUserPresence {
             }
 
             let notify_handle = notification.show().unwrap();
+            {
+                let mut notification_id = shown_notification_id.lock().unwrap();
+                // Dropped while the notification was being shown, too early to close it
+                if shown_cancelled.load(Ordering::SeqCst) {
+                    debug!(logger, "Closing notification of request cancelled while showing it"; "id" => notify_handle.id());
+                    close_notification(notify_handle.id(), &logger);
+                    return Ok(false);
+                }
+                *notification_id = Some(notify_handle.id());
+            }
 
             let mut action = String::new();
             notify_handle.wait_for_action(|a| action = a.to_owned());
@@ -81,7 +103,76 @@ impl NotificationUserPresence {
             debug!(logger, "test_user_presence"; "action" => action, "user_present" => user_present);
 
             Ok(user_present)
-        }))
+        });
+
+        Box::new(PendingNotification {
+            cancelled,
+            completed: false,
+            logger: self.logger.clone(),
+            notification_id,
+            result,
+        })
+    }
+}
+
+/// Closes the notification when dropped before the user acted on it, e.g.
+/// because the host cancelled the request
+struct PendingNotification {
+    /// Set when dropped, for the worker to close a notification shown afterwards
+    cancelled: Arc<AtomicBool>,
+    completed: bool,
+    logger: Logger,
+    notification_id: Arc<Mutex<Option<u32>>>,
+    result: CpuFuture<bool, io::Error>,
+}
+
+impl Future for PendingNotification {
+    type Item = bool;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<bool, io::Error> {
+        let user_present = match self.result.poll()? {
+            Async::Ready(user_present) => user_present,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        self.completed = true;
+        Ok(Async::Ready(user_present))
+    }
+}
+
+impl Drop for PendingNotification {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let notification_id = {
+            // Held while setting the flag, so the worker either sees it or has stored the id
+            let notification_id = self.notification_id.lock().unwrap();
+            self.cancelled.store(true, Ordering::SeqCst);
+            *notification_id
+        };
+        if let Some(id) = notification_id {
+            debug!(self.logger, "Closing cancelled notification"; "id" => id);
+            close_notification(id, &self.logger);
+        }
+    }
+}
+
+/// Replacing the notification with one that expires at once closes it, which
+/// also ends the wait for an action on the executor
+fn close_notification(id: u32, logger: &Logger) {
+    let result = Notification::new()
+        .id(id)
+        .appname(APPNAME)
+        .summary(SUMMARY)
+        .body("Request cancelled")
+        .icon(ICON)
+        .hint(NotificationHint::Category(String::from(HINT_CATEGORY)))
+        .hint(NotificationHint::Transient(true))
+        .timeout(1)
+        .show();
+    if let Err(err) = result {
+        warn!(logger, "Failed to close cancelled notification"; "error" => %err);
     }
 }
 
@@ -104,6 +195,22 @@ impl UserPresence for NotificationUserPresence {
         self.test_user_presence(&message)
     }
 
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        let message = String::from("Reset security key, removing all registrations");
+        self.test_user_presence(&message)
+    }
+
+    fn allow_silent_authentication(&self, application: &AppId) -> bool {
+        let app_id = try_reverse_app_id(application);
+        let app_id_hash = application.to_base64();
+        let allowed = self
+            .silent_authentication_app_ids
+            .iter()
+            .any(|id| Some(id) == app_id.as_ref() || *id == app_id_hash);
+        debug!(self.logger, "allow_silent_authentication"; "app_id" => app_id_hash, "allowed" => allowed);
+        allowed
+    }
+
     fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
         let message = String::from("Ready to authenticate");
         Notification::new()
@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate u2f_core;
+
+fuzz_target!(|data: &[u8]| {
+    // Any input must decode to a request or an error, never panic
+    let _ = u2f_core::Request::decode(data);
+});
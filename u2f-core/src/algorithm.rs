@@ -0,0 +1,85 @@
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKeyRef};
+
+use constants::*;
+
+/// Signature algorithm of a credential, as identified by COSE. U2F only
+/// knows ES256, the others are for CTAP2 credentials.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    /// ECDSA on P-256 with SHA-256
+    Es256,
+    /// ECDSA on P-384 with SHA-384
+    Es384,
+    /// EdDSA on Ed25519
+    EdDsa,
+    /// RSASSA-PKCS1-v1_5 with SHA-256
+    Rs256,
+}
+
+impl Default for Algorithm {
+    fn default() -> Algorithm {
+        Algorithm::Es256
+    }
+}
+
+impl Algorithm {
+    pub fn from_cose(identifier: i64) -> Option<Algorithm> {
+        match identifier {
+            COSE_ALGORITHM_ES256 => Some(Algorithm::Es256),
+            COSE_ALGORITHM_ES384 => Some(Algorithm::Es384),
+            COSE_ALGORITHM_EDDSA => Some(Algorithm::EdDsa),
+            COSE_ALGORITHM_RS256 => Some(Algorithm::Rs256),
+            _ => None,
+        }
+    }
+
+    pub fn to_cose(self) -> i64 {
+        match self {
+            Algorithm::Es256 => COSE_ALGORITHM_ES256,
+            Algorithm::Es384 => COSE_ALGORITHM_ES384,
+            Algorithm::EdDsa => COSE_ALGORITHM_EDDSA,
+            Algorithm::Rs256 => COSE_ALGORITHM_RS256,
+        }
+    }
+
+    /// The algorithm a key is used with, None for keys of any other type or curve
+    pub(crate) fn of_pkey<T: HasPublic>(pkey: &PKeyRef<T>) -> Option<Algorithm> {
+        match pkey.id() {
+            Id::EC => {
+                let ec_key = pkey.ec_key().ok()?;
+                match ec_key.group().curve_name() {
+                    Some(Nid::X9_62_PRIME256V1) => Some(Algorithm::Es256),
+                    Some(Nid::SECP384R1) => Some(Algorithm::Es384),
+                    _ => None,
+                }
+            }
+            Id::ED25519 => Some(Algorithm::EdDsa),
+            Id::RSA => Some(Algorithm::Rs256),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cose_identifiers_round_trip() {
+        for &algorithm in &[
+            Algorithm::Es256,
+            Algorithm::Es384,
+            Algorithm::EdDsa,
+            Algorithm::Rs256,
+        ] {
+            assert_eq!(Algorithm::from_cose(algorithm.to_cose()), Some(algorithm));
+        }
+    }
+
+    #[test]
+    fn unknown_cose_identifier_is_none() {
+        // ES512
+        assert_eq!(Algorithm::from_cose(-36), None);
+    }
+}
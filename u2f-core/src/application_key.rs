@@ -1,3 +1,6 @@
+use std::time::SystemTime;
+
+use algorithm::Algorithm;
 use app_id::AppId;
 use key_handle::KeyHandle;
 use private_key::PrivateKey;
@@ -7,13 +10,30 @@ pub struct ApplicationKey {
     pub application: AppId,
     pub handle: KeyHandle,
     key: PrivateKey,
+    /// Keys stored before other algorithms were supported are all ES256
+    #[serde(default)]
+    algorithm: Algorithm,
 }
 
 impl ApplicationKey {
     pub fn new(application: AppId, handle: KeyHandle, key: PrivateKey) -> ApplicationKey {
-        ApplicationKey { application, handle, key }
+        let algorithm = key.algorithm().unwrap_or_default();
+        ApplicationKey { application, handle, key, algorithm }
     }
-    pub(crate) fn key(&self) -> &PrivateKey {
+    pub fn key(&self) -> &PrivateKey {
         &self.key
     }
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+}
+
+/// Bookkeeping a secret store keeps alongside an application key
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApplicationKeyMetadata {
+    /// Unknown for keys stored before registration times were recorded
+    pub registered: Option<SystemTime>,
+    pub last_used: Option<SystemTime>,
+    /// Number of authentications signed with the key
+    pub use_count: u64,
 }
\ No newline at end of file
@@ -1,25 +1,134 @@
 use std::fmt::{self, Debug};
-use openssl::x509::X509;
+use std::io;
+use std::result::Result;
 
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use algorithm::Algorithm;
 use private_key::PrivateKey;
+use serde_base64::{from_base64, to_base64};
 
-#[derive(Clone)]
+const GENERATED_CERTIFICATE_VALIDITY_DAYS: u32 = 3652;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Attestation {
     pub(crate) certificate: AttestationCertificate,
+    /// Intermediate certificates, in order from the attestation certificate towards the root
+    #[serde(default)]
+    pub(crate) chain: Vec<AttestationCertificate>,
     pub(crate) key: PrivateKey,
 }
 
+impl Attestation {
+    /// Load an attestation certificate chain and its private key, the chain
+    /// starts with the attestation certificate followed by any intermediates
+    pub fn from_pem(certificate_chain_pem: &str, key_pem: &str) -> io::Result<Attestation> {
+        let mut certificates = X509::stack_from_pem(certificate_chain_pem.as_bytes())?.into_iter();
+        let certificate = certificates.next().ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no attestation certificate found",
+        ))?;
+        let key = EcKey::private_key_from_pem(key_pem.as_bytes())?;
+        let pkey = PKey::from_ec_key(key.clone())?;
+        // U2F only knows P-256 attestation, and CTAP2 attestation statements are marked ES256
+        if Algorithm::of_pkey(&pkey) != Some(Algorithm::Es256) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "attestation key must be on the P-256 curve",
+            ));
+        }
+
+        let certificate_public_key = certificate.public_key()?;
+        if !certificate_public_key.public_eq(&pkey) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "attestation key does not match attestation certificate",
+            ));
+        }
+
+        Ok(Attestation {
+            certificate: AttestationCertificate(certificate),
+            chain: certificates.map(AttestationCertificate).collect(),
+            key: PrivateKey::from_ec_key(key),
+        })
+    }
+
+    /// Generate a new key and self-signed certificate. Unlike the shared
+    /// self-signed attestation, this is unique to one install and so lets
+    /// relying parties recognize it across registrations.
+    pub fn generate(common_name: &str) -> io::Result<Attestation> {
+        Attestation::generate_on_curve(common_name, Nid::X9_62_PRIME256V1)
+    }
+
+    fn generate_on_curve(common_name: &str, curve: Nid) -> io::Result<Attestation> {
+        let group = EcGroup::from_curve_name(curve)?;
+        let key = EcKey::generate(&group)?;
+        let pkey = PKey::from_ec_key(key.clone())?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let name = name.build();
+
+        let mut serial_number = BigNum::new()?;
+        serial_number.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509Builder::new()?;
+        // X.509 v3
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial_number.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&Asn1Time::days_from_now(
+            GENERATED_CERTIFICATE_VALIDITY_DAYS,
+        )?)?;
+        builder.set_pubkey(&pkey)?;
+        builder.sign(&pkey, MessageDigest::sha256())?;
+
+        Ok(Attestation {
+            certificate: AttestationCertificate(builder.build()),
+            chain: Vec::new(),
+            key: PrivateKey::from_ec_key(key),
+        })
+    }
+
+    pub fn certificate(&self) -> &AttestationCertificate {
+        &self.certificate
+    }
+}
+
+impl Debug for Attestation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Attestation")
+    }
+}
+
 #[derive(Clone)]
 pub struct AttestationCertificate(pub(crate) X509);
 
 impl AttestationCertificate {
-    pub(crate) fn from_pem(pem: &str) -> AttestationCertificate {
-        AttestationCertificate(X509::from_pem(pem.as_bytes()).unwrap())
+    pub fn from_pem(pem: &str) -> io::Result<AttestationCertificate> {
+        Ok(AttestationCertificate(X509::from_pem(pem.as_bytes())?))
+    }
+
+    pub fn from_der(der: &[u8]) -> io::Result<AttestationCertificate> {
+        Ok(AttestationCertificate(X509::from_der(der)?))
     }
 
-    pub(crate) fn to_der(&self) -> Vec<u8> {
+    pub fn to_der(&self) -> Vec<u8> {
         self.0.to_der().unwrap()
     }
+
+    pub fn to_pem(&self) -> Vec<u8> {
+        self.0.to_pem().unwrap()
+    }
 }
 
 impl Debug for AttestationCertificate {
@@ -27,3 +136,89 @@ impl Debug for AttestationCertificate {
         write!(f, "AttestationCertificate")
     }
 }
+
+impl Serialize for AttestationCertificate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        to_base64(&self.to_der(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AttestationCertificate {
+    fn deserialize<D>(deserializer: D) -> Result<AttestationCertificate, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let der = from_base64(deserializer)?;
+        X509::from_der(&der)
+            .map(AttestationCertificate)
+            .map_err(|err| D::Error::custom(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pem(key: &PrivateKey) -> String {
+        String::from_utf8(key.ec_key().unwrap().private_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn generate_certificate_matches_key() {
+        let attestation = Attestation::generate("Soft U2F Test").unwrap();
+
+        let certificate_pem = String::from_utf8(attestation.certificate.to_pem()).unwrap();
+        let key_pem = key_pem(&attestation.key);
+        let loaded = Attestation::from_pem(&certificate_pem, &key_pem).unwrap();
+
+        assert_eq!(
+            loaded.certificate.to_der(),
+            attestation.certificate.to_der()
+        );
+        assert!(loaded.chain.is_empty());
+    }
+
+    #[test]
+    fn from_pem_with_mismatched_key_errors() {
+        let attestation = Attestation::generate("Soft U2F Test").unwrap();
+        let other = Attestation::generate("Soft U2F Test").unwrap();
+
+        let certificate_pem = String::from_utf8(attestation.certificate.to_pem()).unwrap();
+        let key_pem = key_pem(&other.key);
+
+        assert!(Attestation::from_pem(&certificate_pem, &key_pem).is_err());
+    }
+
+    #[test]
+    fn from_pem_keeps_chain_order() {
+        let attestation = Attestation::generate("Soft U2F Test").unwrap();
+        let intermediate = Attestation::generate("Soft U2F Intermediate").unwrap();
+
+        let mut chain_pem = attestation.certificate.to_pem();
+        chain_pem.extend_from_slice(&intermediate.certificate.to_pem());
+        let key_pem = key_pem(&attestation.key);
+        let loaded =
+            Attestation::from_pem(&String::from_utf8(chain_pem).unwrap(), &key_pem).unwrap();
+
+        assert_eq!(
+            loaded.certificate.to_der(),
+            attestation.certificate.to_der()
+        );
+        assert_eq!(loaded.chain.len(), 1);
+        assert_eq!(loaded.chain[0].to_der(), intermediate.certificate.to_der());
+    }
+
+    #[test]
+    fn from_pem_with_p384_key_errors() {
+        let attestation = Attestation::generate_on_curve("Soft U2F Test", Nid::SECP384R1).unwrap();
+
+        let certificate_pem = String::from_utf8(attestation.certificate.to_pem()).unwrap();
+        let key_pem = key_pem(&attestation.key);
+
+        assert!(Attestation::from_pem(&certificate_pem, &key_pem).is_err());
+    }
+}
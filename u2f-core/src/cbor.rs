@@ -0,0 +1,365 @@
+use std::io::{Cursor, Read};
+use std::result::Result;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+const MAJOR_TYPE_UNSIGNED: u8 = 0;
+const MAJOR_TYPE_NEGATIVE: u8 = 1;
+const MAJOR_TYPE_BYTES: u8 = 2;
+const MAJOR_TYPE_TEXT: u8 = 3;
+const MAJOR_TYPE_ARRAY: u8 = 4;
+const MAJOR_TYPE_MAP: u8 = 5;
+const MAJOR_TYPE_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+
+// CTAP2 limits nesting of request messages, anything deeper is malformed
+const MAX_NESTING_DEPTH: usize = 8;
+
+/// Subset of CBOR (RFC 7049) used by CTAP2 messages.
+/// Tags, floats and indefinite length items are not supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum DecodeError {
+        UnexpectedEnd
+        TrailingData
+        IntegerOverflow
+        InvalidUtf8
+        NestingTooDeep
+        Unsupported(initial_byte: u8)
+    }
+}
+
+impl Value {
+    pub fn text<S: Into<String>>(s: S) -> Value {
+        Value::Text(s.into())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn is_map(&self) -> bool {
+        match self {
+            Value::Map(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Look up the value for an integer key, if this is a map
+    pub fn get_int(&self, key: i64) -> Option<&Value> {
+        self.get(&Value::Integer(key))
+    }
+
+    /// Look up the value for a text key, if this is a map
+    pub fn get_text(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.as_text() == Some(key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Encode using the CTAP2 canonical CBOR encoding rules:
+    /// shortest length encodings and map keys sorted by their encoded form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes);
+        bytes
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(i) if *i >= 0 => write_header(out, MAJOR_TYPE_UNSIGNED, *i as u64),
+            Value::Integer(i) => write_header(out, MAJOR_TYPE_NEGATIVE, (-1 - *i) as u64),
+            Value::Bytes(bytes) => {
+                write_header(out, MAJOR_TYPE_BYTES, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Value::Text(text) => {
+                write_header(out, MAJOR_TYPE_TEXT, text.len() as u64);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(values) => {
+                write_header(out, MAJOR_TYPE_ARRAY, values.len() as u64);
+                for value in values {
+                    value.write(out);
+                }
+            }
+            Value::Map(entries) => {
+                let mut encoded: Vec<(Vec<u8>, Vec<u8>)> = entries
+                    .iter()
+                    .map(|(k, v)| (k.to_bytes(), v.to_bytes()))
+                    .collect();
+                encoded.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+                write_header(out, MAJOR_TYPE_MAP, encoded.len() as u64);
+                for (k, v) in encoded {
+                    out.extend_from_slice(&k);
+                    out.extend_from_slice(&v);
+                }
+            }
+            Value::Bool(false) => out.push(MAJOR_TYPE_SIMPLE << 5 | SIMPLE_FALSE),
+            Value::Bool(true) => out.push(MAJOR_TYPE_SIMPLE << 5 | SIMPLE_TRUE),
+            Value::Null => out.push(MAJOR_TYPE_SIMPLE << 5 | SIMPLE_NULL),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Value, DecodeError> {
+        let mut reader = Cursor::new(bytes);
+        let value = read_value(&mut reader, 0)?;
+        if reader.position() as usize != bytes.len() {
+            return Err(DecodeError::TrailingData);
+        }
+        Ok(value)
+    }
+}
+
+fn write_header(out: &mut Vec<u8>, major_type: u8, value: u64) {
+    let major_type = major_type << 5;
+    if value < 24 {
+        out.push(major_type | value as u8);
+    } else if value <= u64::from(u8::max_value()) {
+        out.push(major_type | 24);
+        out.push(value as u8);
+    } else if value <= u64::from(u16::max_value()) {
+        out.push(major_type | 25);
+        out.write_u16::<BigEndian>(value as u16).unwrap();
+    } else if value <= u64::from(u32::max_value()) {
+        out.push(major_type | 26);
+        out.write_u32::<BigEndian>(value as u32).unwrap();
+    } else {
+        out.push(major_type | 27);
+        out.write_u64::<BigEndian>(value).unwrap();
+    }
+}
+
+fn read_value(reader: &mut Cursor<&[u8]>, depth: usize) -> Result<Value, DecodeError> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(DecodeError::NestingTooDeep);
+    }
+
+    let initial_byte = reader.read_u8().map_err(|_| DecodeError::UnexpectedEnd)?;
+    let major_type = initial_byte >> 5;
+    let additional_info = initial_byte & 0b0001_1111;
+
+    if major_type == MAJOR_TYPE_SIMPLE {
+        return match additional_info {
+            SIMPLE_FALSE => Ok(Value::Bool(false)),
+            SIMPLE_TRUE => Ok(Value::Bool(true)),
+            SIMPLE_NULL => Ok(Value::Null),
+            _ => Err(DecodeError::Unsupported(initial_byte)),
+        };
+    }
+
+    let argument = match additional_info {
+        value @ 0..=23 => u64::from(value),
+        24 => u64::from(reader.read_u8().map_err(|_| DecodeError::UnexpectedEnd)?),
+        25 => u64::from(
+            reader
+                .read_u16::<BigEndian>()
+                .map_err(|_| DecodeError::UnexpectedEnd)?,
+        ),
+        26 => u64::from(
+            reader
+                .read_u32::<BigEndian>()
+                .map_err(|_| DecodeError::UnexpectedEnd)?,
+        ),
+        27 => reader
+            .read_u64::<BigEndian>()
+            .map_err(|_| DecodeError::UnexpectedEnd)?,
+        _ => return Err(DecodeError::Unsupported(initial_byte)),
+    };
+
+    // Every item takes at least one byte, so a length beyond the remaining
+    // input is malformed and must not be used to size an allocation.
+    let remaining = reader.get_ref().len() as u64 - reader.position();
+
+    match major_type {
+        MAJOR_TYPE_UNSIGNED => {
+            if argument > i64::max_value() as u64 {
+                return Err(DecodeError::IntegerOverflow);
+            }
+            Ok(Value::Integer(argument as i64))
+        }
+        MAJOR_TYPE_NEGATIVE => {
+            if argument > i64::max_value() as u64 {
+                return Err(DecodeError::IntegerOverflow);
+            }
+            Ok(Value::Integer(-1 - argument as i64))
+        }
+        MAJOR_TYPE_BYTES | MAJOR_TYPE_TEXT => {
+            if argument > remaining {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let mut bytes = vec![0u8; argument as usize];
+            reader
+                .read_exact(&mut bytes[..])
+                .map_err(|_| DecodeError::UnexpectedEnd)?;
+            if major_type == MAJOR_TYPE_BYTES {
+                Ok(Value::Bytes(bytes))
+            } else {
+                String::from_utf8(bytes)
+                    .map(Value::Text)
+                    .map_err(|_| DecodeError::InvalidUtf8)
+            }
+        }
+        MAJOR_TYPE_ARRAY => {
+            if argument > remaining {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let mut values = Vec::with_capacity(argument as usize);
+            for _ in 0..argument {
+                values.push(read_value(reader, depth + 1)?);
+            }
+            Ok(Value::Array(values))
+        }
+        MAJOR_TYPE_MAP => {
+            if argument > remaining / 2 {
+                return Err(DecodeError::UnexpectedEnd);
+            }
+            let mut entries = Vec::with_capacity(argument as usize);
+            for _ in 0..argument {
+                let key = read_value(reader, depth + 1)?;
+                let value = read_value(reader, depth + 1)?;
+                entries.push((key, value));
+            }
+            Ok(Value::Map(entries))
+        }
+        _ => Err(DecodeError::Unsupported(initial_byte)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_use_shortest_encoding() {
+        assert_eq!(Value::Integer(0).to_bytes(), vec![0x00]);
+        assert_eq!(Value::Integer(23).to_bytes(), vec![0x17]);
+        assert_eq!(Value::Integer(24).to_bytes(), vec![0x18, 0x18]);
+        assert_eq!(Value::Integer(1000).to_bytes(), vec![0x19, 0x03, 0xe8]);
+        assert_eq!(Value::Integer(-1).to_bytes(), vec![0x20]);
+        assert_eq!(Value::Integer(-7).to_bytes(), vec![0x26]);
+        assert_eq!(Value::Integer(-257).to_bytes(), vec![0x39, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn map_keys_are_sorted_canonically() {
+        let map = Value::Map(vec![
+            (Value::text("up"), Value::Bool(true)),
+            (Value::Integer(3), Value::Null),
+            (Value::text("a"), Value::Bool(false)),
+            (Value::Integer(-1), Value::Null),
+            (Value::Integer(1), Value::Null),
+        ]);
+
+        assert_eq!(
+            map.to_bytes(),
+            vec![
+                0xa5, 0x01, 0xf6, 0x03, 0xf6, 0x20, 0xf6, 0x61, 0x61, 0xf4, 0x62, 0x75, 0x70, 0xf5
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = Value::Map(vec![
+            (Value::Integer(1), Value::Bytes(vec![1, 2, 3])),
+            (
+                Value::Integer(2),
+                Value::Array(vec![Value::text("FIDO_2_0"), Value::Integer(-7)]),
+            ),
+            (Value::text("rk"), Value::Bool(false)),
+        ]);
+
+        assert_eq!(Value::from_bytes(&value.to_bytes()).unwrap(), value);
+    }
+
+    #[test]
+    fn decode_truncated_is_error() {
+        assert_matches!(
+            Value::from_bytes(&[0x5a, 0xff, 0xff, 0xff, 0xff]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_matches!(
+            Value::from_bytes(&[0xa2, 0x01]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn decode_trailing_data_is_error() {
+        assert_matches!(
+            Value::from_bytes(&[0x01, 0x02]),
+            Err(DecodeError::TrailingData)
+        );
+    }
+
+    #[test]
+    fn decode_deep_nesting_is_error() {
+        let bytes = vec![0x81; 64];
+        assert_matches!(Value::from_bytes(&bytes), Err(DecodeError::NestingTooDeep));
+    }
+
+    #[test]
+    fn decode_float_is_unsupported() {
+        assert_matches!(
+            Value::from_bytes(&[0xf9, 0x3c, 0x00]),
+            Err(DecodeError::Unsupported(0xf9))
+        );
+    }
+}
@@ -0,0 +1,548 @@
+use std::io;
+use std::result::Result;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::future;
+use futures::Future;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKeyRef, Public};
+use openssl::sign::Verifier;
+
+use app_id::AppId;
+use attestation::AttestationCertificate;
+use constants::*;
+use key_handle::KeyHandle;
+use public_key::PublicKey;
+use request::{AuthenticateControlCode, Request};
+use response::Response;
+
+use super::message_to_sign_for_authenticate;
+use super::message_to_sign_for_register;
+use super::user_presence_byte;
+use super::Challenge;
+use super::Counter;
+use super::Service;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ClientError {
+        Io(err: io::Error) {
+            from()
+        }
+        Status(status_word: u16) {
+            description("Authenticator returned an error status")
+            display("Authenticator returned status word {:#06x}", status_word)
+        }
+        MalformedResponse(reason: &'static str) {
+            description("Response is malformed")
+            display("Response is malformed: {}", reason)
+        }
+        InvalidCertificate(reason: String) {
+            description("Attestation certificate is invalid")
+            display("Attestation certificate is invalid: {}", reason)
+        }
+        InvalidPublicKey(reason: String) {
+            description("User public key is invalid")
+            display("User public key is invalid: {}", reason)
+        }
+        InvalidSignature {
+            description("Signature does not verify")
+        }
+        CounterNotIncreased(previous: Counter, counter: Counter) {
+            description("Counter did not increase")
+            display("Counter {} is not greater than the previous counter {}", counter, previous)
+        }
+        UserNotPresent {
+            description("User presence was not verified")
+        }
+    }
+}
+
+/// Carries raw U2F request messages to an authenticator
+pub trait Transport {
+    /// Resolves to the raw response message, ending with its status word
+    fn transmit(&self, request: Vec<u8>) -> Box<dyn Future<Item = Vec<u8>, Error = io::Error>>;
+}
+
+/// Talks to an authenticator service in the same process, e.g. `U2F`.
+/// Requests are still encoded and decoded, so malformed messages are
+/// answered with a status word like they would be over HID.
+pub struct ServiceTransport<S>(pub S);
+
+impl<S> Transport for ServiceTransport<S>
+where
+    S: Service<Request = Request, Response = Response, Error = io::Error>,
+    S::Future: 'static,
+{
+    fn transmit(&self, request: Vec<u8>) -> Box<dyn Future<Item = Vec<u8>, Error = io::Error>> {
+        match Request::decode(&request) {
+            Ok(request) => Box::new(self.0.call(request).map(Response::into_bytes)),
+            Err(error) => {
+                let mut bytes = Vec::new();
+                error.status_code().write(&mut bytes);
+                Box::new(future::ok(bytes))
+            }
+        }
+    }
+}
+
+/// A key registered with an authenticator, as kept by the relying party
+#[derive(Clone, Debug)]
+pub struct RegisteredKey {
+    pub application: AppId,
+    pub key_handle: KeyHandle,
+    /// Raw uncompressed P-256 point the authenticator signs with
+    pub user_public_key: Vec<u8>,
+    pub attestation_certificate: AttestationCertificate,
+    /// Counter of the last verified authentication, None until the first one
+    pub counter: Option<Counter>,
+}
+
+#[derive(Debug)]
+pub struct VerifiedAuthentication {
+    pub user_present: bool,
+    pub counter: Counter,
+}
+
+/// Relying party side of U2F, builds requests for an authenticator and
+/// verifies its responses the way a server would.
+pub struct Client<T> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Client<T> {
+        Client { transport }
+    }
+
+    pub fn get_version(&self) -> Box<dyn Future<Item = String, Error = ClientError>> {
+        Box::new(
+            self.transport
+                .transmit(version_request())
+                .from_err()
+                .and_then(|response| {
+                    let body = split_status_word(&response)?;
+                    String::from_utf8(body.to_vec())
+                        .map_err(|_| ClientError::MalformedResponse("version is not ASCII"))
+                }),
+        )
+    }
+
+    /// Register a new key, verifying the attestation signature
+    pub fn register(
+        &self,
+        application: AppId,
+        challenge: Challenge,
+    ) -> Box<dyn Future<Item = RegisteredKey, Error = ClientError>> {
+        Box::new(
+            self.transport
+                .transmit(register_request(&application, &challenge))
+                .from_err()
+                .and_then(move |response| verify_registration(&application, &challenge, &response)),
+        )
+    }
+
+    /// Authenticate with a registered key, verifying the signature and that
+    /// the counter increased. Update the key's counter with the result to
+    /// detect cloned authenticators on the next authentication.
+    pub fn authenticate(
+        &self,
+        key: &RegisteredKey,
+        challenge: Challenge,
+        control_code: AuthenticateControlCode,
+    ) -> Box<dyn Future<Item = VerifiedAuthentication, Error = ClientError>> {
+        let key = key.clone();
+        let require_user_presence = match control_code {
+            AuthenticateControlCode::EnforceUserPresenceAndSign => true,
+            _ => false,
+        };
+        let request =
+            authenticate_request(&key.application, &challenge, &key.key_handle, &control_code);
+        Box::new(
+            self.transport
+                .transmit(request)
+                .from_err()
+                .and_then(move |response| {
+                    let authentication = verify_authentication(&key, &challenge, &response)?;
+                    if require_user_presence && !authentication.user_present {
+                        return Err(ClientError::UserNotPresent);
+                    }
+                    Ok(authentication)
+                }),
+        )
+    }
+
+    /// Whether the authenticator recognizes the key handle for the application
+    pub fn check_key_handle(
+        &self,
+        application: &AppId,
+        key_handle: &KeyHandle,
+    ) -> Box<dyn Future<Item = bool, Error = ClientError>> {
+        let request = authenticate_request(
+            application,
+            &Challenge([0u8; 32]),
+            key_handle,
+            &AuthenticateControlCode::CheckOnly,
+        );
+        Box::new(
+            self.transport.transmit(request).from_err().and_then(
+                |response| match split_status_word(&response) {
+                    // A check-only request never succeeds, the status word is the answer
+                    Err(ClientError::Status(SW_CONDITIONS_NOT_SATISFIED)) => Ok(true),
+                    Err(ClientError::Status(SW_WRONG_DATA)) => Ok(false),
+                    Err(err) => Err(err),
+                    Ok(_) => Err(ClientError::MalformedResponse(
+                        "check-only request succeeded",
+                    )),
+                },
+            ),
+        )
+    }
+}
+
+/// Raw register request message with extended length encoding
+pub fn register_request(application: &AppId, challenge: &Challenge) -> Vec<u8> {
+    // The challenge parameter [32 bytes] and the application parameter [32 bytes].
+    let mut data = Vec::with_capacity(64);
+    data.extend_from_slice(challenge.as_ref());
+    data.extend_from_slice(application.as_ref());
+    encode_apdu(REGISTER_COMMAND_CODE, 0, &data)
+}
+
+/// Raw authenticate request message with extended length encoding
+pub fn authenticate_request(
+    application: &AppId,
+    challenge: &Challenge,
+    key_handle: &KeyHandle,
+    control_code: &AuthenticateControlCode,
+) -> Vec<u8> {
+    let control_byte = match control_code {
+        AuthenticateControlCode::CheckOnly => AUTH_CHECK_ONLY,
+        AuthenticateControlCode::EnforceUserPresenceAndSign => AUTH_ENFORCE,
+        AuthenticateControlCode::DontEnforceUserPresenceAndSign => AUTH_DONT_ENFORCE,
+    };
+
+    // The challenge parameter [32 bytes], the application parameter [32 bytes],
+    // the key handle length byte [1 byte] and the key handle [variable length].
+    let key_handle_bytes = key_handle.as_ref();
+    let mut data = Vec::with_capacity(65 + key_handle_bytes.len());
+    data.extend_from_slice(challenge.as_ref());
+    data.extend_from_slice(application.as_ref());
+    data.push(key_handle_bytes.len() as u8);
+    data.extend_from_slice(key_handle_bytes);
+    encode_apdu(AUTHENTICATE_COMMAND_CODE, control_byte, &data)
+}
+
+/// Raw version request message
+pub fn version_request() -> Vec<u8> {
+    encode_apdu(VERSION_COMMAND_CODE, 0, &[])
+}
+
+/// Check a raw register response message against the request it answers
+pub fn verify_registration(
+    application: &AppId,
+    challenge: &Challenge,
+    response: &[u8],
+) -> Result<RegisteredKey, ClientError> {
+    let body = split_status_word(response)?;
+
+    // reserved byte [1 byte], user public key [65 bytes], key handle length byte [1 byte]
+    if body.len() < 67 {
+        return Err(ClientError::MalformedResponse("registration is too short"));
+    }
+    if body[0] != 0x05 {
+        return Err(ClientError::MalformedResponse("reserved byte is not 0x05"));
+    }
+    let user_public_key = &body[1..66];
+    PublicKey::from_bytes(user_public_key).map_err(ClientError::InvalidPublicKey)?;
+
+    let key_handle_len = body[66] as usize;
+    if body.len() < 67 + key_handle_len {
+        return Err(ClientError::MalformedResponse("key handle is truncated"));
+    }
+    let key_handle = KeyHandle::from(&body[67..67 + key_handle_len]);
+
+    // The attestation certificate is followed by the signature, only its DER
+    // header tells where it ends
+    let remaining = &body[67 + key_handle_len..];
+    let certificate_len = match der_len(remaining) {
+        Some(len) if len <= remaining.len() => len,
+        _ => return Err(ClientError::MalformedResponse("certificate is truncated")),
+    };
+    let (certificate_der, signature) = remaining.split_at(certificate_len);
+    let attestation_certificate = AttestationCertificate::from_der(certificate_der)
+        .map_err(|err| ClientError::InvalidCertificate(err.to_string()))?;
+    let attestation_public_key = attestation_certificate
+        .0
+        .public_key()
+        .map_err(|err| ClientError::InvalidCertificate(err.to_string()))?;
+
+    let message =
+        message_to_sign_for_register(application, challenge, user_public_key, &key_handle);
+    verify_signature(&attestation_public_key, &message, signature)?;
+
+    Ok(RegisteredKey {
+        application: *application,
+        key_handle,
+        user_public_key: user_public_key.to_vec(),
+        attestation_certificate,
+        counter: None,
+    })
+}
+
+/// Check a raw authenticate response message against the request it answers
+pub fn verify_authentication(
+    key: &RegisteredKey,
+    challenge: &Challenge,
+    response: &[u8],
+) -> Result<VerifiedAuthentication, ClientError> {
+    let body = split_status_word(response)?;
+
+    // user presence byte [1 byte], counter [4 bytes]
+    if body.len() < 5 {
+        return Err(ClientError::MalformedResponse(
+            "authentication is too short",
+        ));
+    }
+    let user_presence = body[0];
+    let counter = BigEndian::read_u32(&body[1..5]);
+    let signature = &body[5..];
+
+    let public_key =
+        PublicKey::from_bytes(&key.user_public_key).map_err(ClientError::InvalidPublicKey)?;
+    let message =
+        message_to_sign_for_authenticate(&key.application, challenge, user_presence, counter);
+    verify_signature(public_key.as_pkey(), &message, signature)?;
+
+    if let Some(previous) = key.counter {
+        if counter <= previous {
+            return Err(ClientError::CounterNotIncreased(previous, counter));
+        }
+    }
+
+    Ok(VerifiedAuthentication {
+        user_present: user_presence == user_presence_byte(true),
+        counter,
+    })
+}
+
+/// Command APDU with extended length encoding, the maximum response length
+/// is always requested
+fn encode_apdu(instruction: u8, parameter1: u8, data: &[u8]) -> Vec<u8> {
+    // CLA, INS, P1, P2
+    let mut bytes = vec![0u8, instruction, parameter1, 0u8];
+
+    // Extended length encoding begins with a byte of value 0, Lc is omitted without request data
+    bytes.push(0u8);
+    if !data.is_empty() {
+        bytes.push((data.len() >> 8) as u8);
+        bytes.push(data.len() as u8);
+        bytes.extend_from_slice(data);
+    }
+
+    // Le: Ne = 65 536
+    bytes.extend_from_slice(&[0u8, 0u8]);
+    bytes
+}
+
+/// Response data without the status word, or the status word if it is an error
+fn split_status_word(response: &[u8]) -> Result<&[u8], ClientError> {
+    if response.len() < 2 {
+        return Err(ClientError::MalformedResponse("status word is missing"));
+    }
+    let (body, status_word) = response.split_at(response.len() - 2);
+    match BigEndian::read_u16(status_word) {
+        SW_NO_ERROR => Ok(body),
+        status_word => Err(ClientError::Status(status_word)),
+    }
+}
+
+/// Length of the DER encoded value at the start of the bytes, including its
+/// tag and length, None if the length is not encoded in definite form
+fn der_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 2 {
+        return None;
+    }
+    let first_len_byte = bytes[1] as usize;
+    if first_len_byte < 0x80 {
+        return Some(2 + first_len_byte);
+    }
+    let len_bytes = first_len_byte & 0x7f;
+    if len_bytes == 0 || len_bytes > 4 || bytes.len() < 2 + len_bytes {
+        return None;
+    }
+    let len = bytes[2..2 + len_bytes]
+        .iter()
+        .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+    Some(2 + len_bytes + len)
+}
+
+fn verify_signature(
+    public_key: &PKeyRef<Public>,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), ClientError> {
+    let mut verifier =
+        Verifier::new(MessageDigest::sha256(), public_key).map_err(io::Error::from)?;
+    verifier.update(message).map_err(io::Error::from)?;
+    // Malformed signatures fail to parse instead of failing to verify
+    match verifier.verify(signature) {
+        Ok(true) => Ok(()),
+        Ok(false) | Err(_) => Err(ClientError::InvalidSignature),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use application_key::ApplicationKey;
+    use openssl_crypto::OpenSSLCryptoOperations;
+    use self_signed_attestation::self_signed_attestation;
+    use CryptoOperations;
+
+    fn registration_response(
+        operations: &OpenSSLCryptoOperations,
+        application: &AppId,
+        challenge: &Challenge,
+    ) -> (ApplicationKey, Vec<u8>) {
+        let application_key = operations.generate_application_key(application).unwrap();
+        let user_public_key = PublicKey::from_key(application_key.key()).to_raw();
+        let signature = operations
+            .attest(&message_to_sign_for_register(
+                application,
+                challenge,
+                &user_public_key,
+                &application_key.handle,
+            ))
+            .unwrap();
+        let response = Response::Registration {
+            user_public_key,
+            key_handle: application_key.handle.clone(),
+            attestation_certificate: operations.get_attestation_certificate(),
+            signature,
+        };
+        (application_key, response.into_bytes())
+    }
+
+    #[test]
+    fn register_request_decodes() {
+        let application = AppId([0x11u8; 32]);
+        let challenge = Challenge([0x22u8; 32]);
+
+        match Request::decode(&register_request(&application, &challenge)) {
+            Ok(Request::Register {
+                application: decoded_application,
+                challenge: decoded_challenge,
+            }) => {
+                assert_eq!(decoded_application, application);
+                assert_eq!(decoded_challenge.as_ref(), challenge.as_ref());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn authenticate_request_decodes() {
+        let application = AppId([0x11u8; 32]);
+        let challenge = Challenge([0x22u8; 32]);
+        let key_handle = KeyHandle::from(&[0x33u8; 64]);
+
+        let request = authenticate_request(
+            &application,
+            &challenge,
+            &key_handle,
+            &AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+        );
+
+        match Request::decode(&request) {
+            Ok(Request::Authenticate {
+                application: decoded_application,
+                control_code: AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+                key_handle: decoded_key_handle,
+                ..
+            }) => {
+                assert_eq!(decoded_application, application);
+                assert_eq!(decoded_key_handle, key_handle);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn version_request_decodes() {
+        assert_matches!(Request::decode(&version_request()), Ok(Request::GetVersion));
+    }
+
+    #[test]
+    fn der_len_long_form() {
+        assert_eq!(der_len(&[0x30, 0x82, 0x01, 0x02]), Some(4 + 0x0102));
+        assert_eq!(der_len(&[0x30, 0x05]), Some(7));
+        // Indefinite length
+        assert_eq!(der_len(&[0x30, 0x80]), None);
+    }
+
+    #[test]
+    fn error_status_word_is_reported() {
+        let key = RegisteredKey {
+            application: AppId([0u8; 32]),
+            key_handle: KeyHandle::from(&[0u8; 64]),
+            user_public_key: vec![0u8; 65],
+            attestation_certificate: self_signed_attestation().certificate,
+            counter: None,
+        };
+
+        assert_matches!(
+            verify_authentication(&key, &Challenge([0u8; 32]), &[0x69, 0x85]),
+            Err(ClientError::Status(SW_CONDITIONS_NOT_SATISFIED))
+        );
+    }
+
+    #[test]
+    fn verify_registration_returns_key() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application = AppId(rand::random());
+        let challenge = Challenge(rand::random());
+        let (application_key, response) =
+            registration_response(&operations, &application, &challenge);
+
+        let key = verify_registration(&application, &challenge, &response).unwrap();
+
+        assert_eq!(key.key_handle, application_key.handle);
+        assert_eq!(
+            key.user_public_key,
+            PublicKey::from_key(application_key.key()).to_raw()
+        );
+        assert_eq!(key.counter, None);
+    }
+
+    #[test]
+    fn verify_registration_with_other_challenge_fails() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application = AppId(rand::random());
+        let (_, response) =
+            registration_response(&operations, &application, &Challenge(rand::random()));
+
+        assert_matches!(
+            verify_registration(&application, &Challenge(rand::random()), &response),
+            Err(ClientError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn verify_registration_with_truncated_certificate_fails() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application = AppId(rand::random());
+        let challenge = Challenge(rand::random());
+        let (application_key, response) =
+            registration_response(&operations, &application, &challenge);
+        let certificate_end = 67 + application_key.handle.as_ref().len() + 10;
+        let mut truncated = response[..certificate_end].to_vec();
+        truncated.extend_from_slice(&[0x90, 0x00]);
+
+        assert_matches!(
+            verify_registration(&application, &challenge, &truncated),
+            Err(ClientError::MalformedResponse(_))
+        );
+    }
+}
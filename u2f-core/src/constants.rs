@@ -8,6 +8,7 @@ pub(crate) const SW_NO_ERROR: u16 = 0x9000; // The command completed successfull
 pub(crate) const SW_WRONG_DATA: u16 = 0x6A80; // The request was rejected due to an invalid key handle.
 pub(crate) const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985; // The request was rejected due to test-of-user-presence being required.
 pub(crate) const SW_COMMAND_NOT_ALLOWED: u16 = 0x6986;
+pub(crate) const SW_WRONG_P1P2: u16 = 0x6B00; // The parameters P1 or P2 of the request are incorrect.
 pub(crate) const SW_INS_NOT_SUPPORTED: u16 = 0x6D00; // The Instruction of the request is not supported.
 pub(crate) const SW_WRONG_LENGTH: u16 = 0x6700; // The length of the request was invalid.
 pub(crate) const SW_CLA_NOT_SUPPORTED: u16 = 0x6E00; // The Class byte of the request is not supported.
@@ -21,3 +22,47 @@ pub(crate) const DEFAULT_KEY_HANDLE_LEN: usize = 255;
 pub(crate) const MAX_KEY_HANDLE_LEN: usize = 255;
 
 pub(crate) const EC_POINT_FORMAT_UNCOMPRESSED: u8 = 0x04;
+
+pub(crate) const CTAP2_MAKE_CREDENTIAL_COMMAND_CODE: u8 = 0x01;
+pub(crate) const CTAP2_GET_ASSERTION_COMMAND_CODE: u8 = 0x02;
+pub(crate) const CTAP2_GET_INFO_COMMAND_CODE: u8 = 0x04;
+pub(crate) const CTAP2_RESET_COMMAND_CODE: u8 = 0x07;
+pub(crate) const CTAP2_GET_NEXT_ASSERTION_COMMAND_CODE: u8 = 0x08;
+
+pub(crate) const CTAP2_OK: u8 = 0x00; // Indicates successful response.
+pub(crate) const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01; // The command is not a valid CTAP command.
+pub(crate) const CTAP1_ERR_INVALID_PARAMETER: u8 = 0x02; // The command included an invalid parameter.
+pub(crate) const CTAP1_ERR_INVALID_LENGTH: u8 = 0x03; // Invalid message or item length.
+pub(crate) const CTAP2_ERR_CBOR_UNEXPECTED_TYPE: u8 = 0x11; // Invalid/unexpected CBOR error.
+pub(crate) const CTAP2_ERR_INVALID_CBOR: u8 = 0x12; // Error when parsing CBOR.
+pub(crate) const CTAP2_ERR_MISSING_PARAMETER: u8 = 0x14; // Missing non-optional parameter.
+pub(crate) const CTAP2_ERR_CREDENTIAL_EXCLUDED: u8 = 0x19; // Valid credential found in the exclude list.
+pub(crate) const CTAP2_ERR_UNSUPPORTED_ALGORITHM: u8 = 0x26; // Authenticator does not support requested algorithm.
+pub(crate) const CTAP2_ERR_OPERATION_DENIED: u8 = 0x27; // Not authorized for requested operation.
+pub(crate) const CTAP2_ERR_UNSUPPORTED_OPTION: u8 = 0x2B; // Unsupported option.
+pub(crate) const CTAP2_ERR_INVALID_OPTION: u8 = 0x2C; // Not a valid option for current operation.
+pub(crate) const CTAP2_ERR_KEEPALIVE_CANCEL: u8 = 0x2D; // Pending keep alive was cancelled.
+pub(crate) const CTAP2_ERR_NO_CREDENTIALS: u8 = 0x2E; // No valid credentials provided.
+pub(crate) const CTAP2_ERR_NOT_ALLOWED: u8 = 0x30; // Continuation command, such as, authenticatorGetNextAssertion not allowed.
+pub(crate) const CTAP1_ERR_OTHER: u8 = 0x7F; // Other unspecified error.
+
+pub(crate) const COSE_ALGORITHM_ES256: i64 = -7; // ECDSA w/ SHA-256
+pub(crate) const COSE_ALGORITHM_ES384: i64 = -35; // ECDSA w/ SHA-384
+pub(crate) const COSE_ALGORITHM_EDDSA: i64 = -8; // EdDSA
+pub(crate) const COSE_ALGORITHM_RS256: i64 = -257; // RSASSA-PKCS1-v1_5 w/ SHA-256
+pub(crate) const COSE_KEY_TYPE_OKP: i64 = 1;
+pub(crate) const COSE_KEY_TYPE_EC2: i64 = 2;
+pub(crate) const COSE_KEY_TYPE_RSA: i64 = 3;
+pub(crate) const COSE_CURVE_P256: i64 = 1;
+pub(crate) const COSE_CURVE_P384: i64 = 2;
+pub(crate) const COSE_CURVE_ED25519: i64 = 6;
+
+pub(crate) const AUTHENTICATOR_DATA_FLAG_USER_PRESENT: u8 = 0b0000_0001;
+pub(crate) const AUTHENTICATOR_DATA_FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0b0100_0000;
+
+// Authenticator Attestation GUID identifying this authenticator model
+pub(crate) const AAGUID: [u8; 16] = [
+    0x5e, 0x0f, 0x6a, 0x3c, 0x1d, 0x8e, 0x4b, 0x63, 0x9a, 0x4c, 0x2f, 0x27, 0x7b, 0x0e, 0x21, 0xd5,
+];
+
+pub(crate) const CTAP2_MAX_MESSAGE_SIZE: i64 = 1200;
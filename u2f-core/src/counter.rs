@@ -0,0 +1,114 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Counter;
+
+/// How signature counters are assigned. Relying parties reject a signature
+/// whose counter is not higher than the last one they saw for the credential,
+/// so every strategy must hand out increasing counters for each credential.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum CounterStrategy {
+    /// Each credential counts its own signatures, which reveals how often
+    /// each site is used
+    PerCredential,
+    /// A single counter shared by every credential
+    Global,
+    /// Seconds since the Unix epoch, raised past the last counter when
+    /// signing more than once a second or after the clock moved backwards
+    Time,
+}
+
+impl Default for CounterStrategy {
+    fn default() -> CounterStrategy {
+        CounterStrategy::PerCredential
+    }
+}
+
+impl CounterStrategy {
+    /// Whether counters are only kept for the whole device
+    pub fn is_device_wide(self) -> bool {
+        match self {
+            CounterStrategy::PerCredential => false,
+            CounterStrategy::Global | CounterStrategy::Time => true,
+        }
+    }
+
+    /// The counter to sign with next, given the last counter used by the
+    /// credential, if it has its own, and the last counter used by any
+    /// credential. Both are taken into account so that changing strategy
+    /// never makes a credential's counter go backwards.
+    pub fn next(self, credential: Option<Counter>, device: Counter, now: SystemTime) -> Counter {
+        match self {
+            CounterStrategy::PerCredential => credential.unwrap_or(device).saturating_add(1),
+            CounterStrategy::Global => credential.unwrap_or(0).max(device).saturating_add(1),
+            CounterStrategy::Time => {
+                let seconds = now
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0);
+                let seconds = if seconds > u64::from(Counter::max_value()) {
+                    Counter::max_value()
+                } else {
+                    seconds as Counter
+                };
+                CounterStrategy::Global
+                    .next(credential, device, now)
+                    .max(seconds)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn per_credential_counts_from_credential_counter() {
+        assert_eq!(CounterStrategy::PerCredential.next(Some(5), 100, at(0)), 6);
+    }
+
+    #[test]
+    fn per_credential_without_credential_counter_continues_device_counter() {
+        assert_eq!(CounterStrategy::PerCredential.next(None, 100, at(0)), 101);
+    }
+
+    #[test]
+    fn global_counts_from_device_counter() {
+        assert_eq!(CounterStrategy::Global.next(Some(5), 100, at(0)), 101);
+    }
+
+    #[test]
+    fn global_never_goes_below_credential_counter() {
+        assert_eq!(CounterStrategy::Global.next(Some(500), 100, at(0)), 501);
+    }
+
+    #[test]
+    fn time_uses_current_time() {
+        assert_eq!(
+            CounterStrategy::Time.next(Some(5), 100, at(1_500_000_000)),
+            1_500_000_000
+        );
+    }
+
+    #[test]
+    fn time_increases_when_clock_is_behind() {
+        assert_eq!(
+            CounterStrategy::Time.next(None, 1_500_000_000, at(1_000)),
+            1_500_000_001
+        );
+    }
+
+    #[test]
+    fn counters_saturate() {
+        assert_eq!(
+            CounterStrategy::Global.next(None, Counter::max_value(), at(0)),
+            Counter::max_value()
+        );
+    }
+}
@@ -0,0 +1,1030 @@
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::result::Result;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use futures::future;
+use futures::Future;
+use ring::digest;
+
+use algorithm::Algorithm;
+use app_id::AppId;
+use application_key::ApplicationKey;
+use attestation::AttestationCertificate;
+use cbor::Value;
+use constants::*;
+use key_handle::KeyHandle;
+use public_key::PublicKey;
+use resident_credential::{ResidentCredential, User};
+
+use super::{Counter, SignError, Signature, U2FInner, U2F};
+
+#[derive(Debug)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Ctap2Request {
+    MakeCredential {
+        client_data_hash: Vec<u8>,
+        rp: RelyingParty,
+        user: User,
+        algorithms: Vec<i64>,
+        exclude_list: Vec<KeyHandle>,
+        resident_key: bool,
+        user_verification: bool,
+    },
+    GetAssertion {
+        rp_id: String,
+        client_data_hash: Vec<u8>,
+        allow_list: Vec<KeyHandle>,
+        user_presence: bool,
+        user_verification: bool,
+    },
+    GetNextAssertion,
+    GetInfo,
+    Reset,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ctap2StatusCode {
+    Ok,
+    InvalidCommand,
+    InvalidParameter,
+    InvalidLength,
+    CborUnexpectedType,
+    InvalidCbor,
+    MissingParameter,
+    CredentialExcluded,
+    UnsupportedAlgorithm,
+    OperationDenied,
+    UnsupportedOption,
+    InvalidOption,
+    KeepaliveCancel,
+    NoCredentials,
+    NotAllowed,
+    Other,
+}
+
+impl Ctap2StatusCode {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Ctap2StatusCode::Ok => CTAP2_OK,
+            Ctap2StatusCode::InvalidCommand => CTAP1_ERR_INVALID_COMMAND,
+            Ctap2StatusCode::InvalidParameter => CTAP1_ERR_INVALID_PARAMETER,
+            Ctap2StatusCode::InvalidLength => CTAP1_ERR_INVALID_LENGTH,
+            Ctap2StatusCode::CborUnexpectedType => CTAP2_ERR_CBOR_UNEXPECTED_TYPE,
+            Ctap2StatusCode::InvalidCbor => CTAP2_ERR_INVALID_CBOR,
+            Ctap2StatusCode::MissingParameter => CTAP2_ERR_MISSING_PARAMETER,
+            Ctap2StatusCode::CredentialExcluded => CTAP2_ERR_CREDENTIAL_EXCLUDED,
+            Ctap2StatusCode::UnsupportedAlgorithm => CTAP2_ERR_UNSUPPORTED_ALGORITHM,
+            Ctap2StatusCode::OperationDenied => CTAP2_ERR_OPERATION_DENIED,
+            Ctap2StatusCode::UnsupportedOption => CTAP2_ERR_UNSUPPORTED_OPTION,
+            Ctap2StatusCode::InvalidOption => CTAP2_ERR_INVALID_OPTION,
+            Ctap2StatusCode::KeepaliveCancel => CTAP2_ERR_KEEPALIVE_CANCEL,
+            Ctap2StatusCode::NoCredentials => CTAP2_ERR_NO_CREDENTIALS,
+            Ctap2StatusCode::NotAllowed => CTAP2_ERR_NOT_ALLOWED,
+            Ctap2StatusCode::Other => CTAP1_ERR_OTHER,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Ctap2Response {
+    MakeCredential {
+        auth_data: Vec<u8>,
+        attestation_certificate: AttestationCertificate,
+        attestation_chain: Vec<AttestationCertificate>,
+        attestation_signature: Box<dyn Signature>,
+    },
+    GetAssertion {
+        credential_id: KeyHandle,
+        auth_data: Vec<u8>,
+        signature: Box<dyn Signature>,
+        user: Option<User>,
+        number_of_credentials: Option<usize>,
+    },
+    GetInfo,
+    Reset,
+    Error(Ctap2StatusCode),
+}
+
+impl Ctap2Response {
+    /// Status byte followed by the CBOR encoded response parameters, if any
+    pub fn into_bytes(self) -> Vec<u8> {
+        let parameters = match self {
+            Ctap2Response::MakeCredential {
+                auth_data,
+                attestation_certificate,
+                attestation_chain,
+                attestation_signature,
+            } => Value::Map(vec![
+                // fmt
+                (Value::Integer(0x01), Value::text("packed")),
+                // authData
+                (Value::Integer(0x02), Value::Bytes(auth_data)),
+                // attStmt
+                (
+                    Value::Integer(0x03),
+                    Value::Map(vec![
+                        // Attestation keys are always P-256, see Attestation::from_pem
+                        (Value::text("alg"), Value::Integer(COSE_ALGORITHM_ES256)),
+                        (
+                            Value::text("sig"),
+                            Value::Bytes(attestation_signature.as_ref().as_ref().to_vec()),
+                        ),
+                        (
+                            Value::text("x5c"),
+                            Value::Array(
+                                Some(&attestation_certificate)
+                                    .into_iter()
+                                    .chain(attestation_chain.iter())
+                                    .map(|certificate| Value::Bytes(certificate.to_der()))
+                                    .collect(),
+                            ),
+                        ),
+                    ]),
+                ),
+            ]),
+            Ctap2Response::GetAssertion {
+                credential_id,
+                auth_data,
+                signature,
+                user,
+                number_of_credentials,
+            } => {
+                let mut parameters = vec![
+                    // credential
+                    (
+                        Value::Integer(0x01),
+                        Value::Map(vec![
+                            (
+                                Value::text("id"),
+                                Value::Bytes(credential_id.as_ref().to_vec()),
+                            ),
+                            (Value::text("type"), Value::text("public-key")),
+                        ]),
+                    ),
+                    // authData
+                    (Value::Integer(0x02), Value::Bytes(auth_data)),
+                    // signature
+                    (
+                        Value::Integer(0x03),
+                        Value::Bytes(signature.as_ref().as_ref().to_vec()),
+                    ),
+                ];
+                // user, only the user ID since user verification is not supported
+                if let Some(user) = user {
+                    parameters.push((
+                        Value::Integer(0x04),
+                        Value::Map(vec![(Value::text("id"), Value::Bytes(user.id))]),
+                    ));
+                }
+                // numberOfCredentials
+                if let Some(number_of_credentials) = number_of_credentials {
+                    parameters.push((
+                        Value::Integer(0x05),
+                        Value::Integer(number_of_credentials as i64),
+                    ));
+                }
+                Value::Map(parameters)
+            }
+            Ctap2Response::GetInfo => Value::Map(vec![
+                // versions
+                (
+                    Value::Integer(0x01),
+                    Value::Array(vec![Value::text("U2F_V2"), Value::text("FIDO_2_0")]),
+                ),
+                // aaguid
+                (Value::Integer(0x03), Value::Bytes(AAGUID.to_vec())),
+                // options
+                (
+                    Value::Integer(0x04),
+                    Value::Map(vec![
+                        (Value::text("plat"), Value::Bool(false)),
+                        (Value::text("rk"), Value::Bool(true)),
+                        (Value::text("up"), Value::Bool(true)),
+                    ]),
+                ),
+                // maxMsgSize
+                (Value::Integer(0x05), Value::Integer(CTAP2_MAX_MESSAGE_SIZE)),
+            ]),
+            Ctap2Response::Reset => return vec![CTAP2_OK],
+            Ctap2Response::Error(status) => return vec![status.to_byte()],
+        };
+
+        let mut bytes = vec![CTAP2_OK];
+        bytes.extend_from_slice(&parameters.to_bytes());
+        bytes
+    }
+}
+
+impl Ctap2Request {
+    /// Decode a CTAP2 message: a command byte followed by CBOR encoded parameters
+    pub fn decode(data: &[u8]) -> Result<Ctap2Request, Ctap2StatusCode> {
+        let (&command_code, parameters) =
+            data.split_first().ok_or(Ctap2StatusCode::InvalidLength)?;
+        match command_code {
+            CTAP2_MAKE_CREDENTIAL_COMMAND_CODE => {
+                decode_make_credential(&decode_parameters(parameters)?)
+            }
+            CTAP2_GET_ASSERTION_COMMAND_CODE => {
+                decode_get_assertion(&decode_parameters(parameters)?)
+            }
+            CTAP2_GET_INFO_COMMAND_CODE => Ok(Ctap2Request::GetInfo),
+            CTAP2_RESET_COMMAND_CODE => Ok(Ctap2Request::Reset),
+            CTAP2_GET_NEXT_ASSERTION_COMMAND_CODE => Ok(Ctap2Request::GetNextAssertion),
+            _ => Err(Ctap2StatusCode::InvalidCommand),
+        }
+    }
+}
+
+fn decode_parameters(bytes: &[u8]) -> Result<Value, Ctap2StatusCode> {
+    let value = Value::from_bytes(bytes).map_err(|_| Ctap2StatusCode::InvalidCbor)?;
+    if !value.is_map() {
+        return Err(Ctap2StatusCode::CborUnexpectedType);
+    }
+    Ok(value)
+}
+
+fn decode_make_credential(parameters: &Value) -> Result<Ctap2Request, Ctap2StatusCode> {
+    // clientDataHash (0x01)
+    let client_data_hash = expect_bytes(required(parameters, 0x01)?)?.to_vec();
+
+    // rp (0x02)
+    let rp = required(parameters, 0x02)?;
+    let rp = RelyingParty {
+        id: expect_text(rp.get_text("id").ok_or(Ctap2StatusCode::MissingParameter)?)?.to_string(),
+        name: optional_text(rp, "name")?,
+    };
+
+    // user (0x03)
+    let user = required(parameters, 0x03)?;
+    let user = User {
+        id: expect_bytes(
+            user.get_text("id")
+                .ok_or(Ctap2StatusCode::MissingParameter)?,
+        )?
+        .to_vec(),
+        name: optional_text(user, "name")?,
+        display_name: optional_text(user, "displayName")?,
+    };
+
+    // pubKeyCredParams (0x04)
+    let mut algorithms = Vec::new();
+    for param in expect_array(required(parameters, 0x04)?)? {
+        let credential_type = param
+            .get_text("type")
+            .ok_or(Ctap2StatusCode::MissingParameter)?;
+        let algorithm = param
+            .get_text("alg")
+            .ok_or(Ctap2StatusCode::MissingParameter)?;
+        if expect_text(credential_type)? == "public-key" {
+            algorithms.push(
+                algorithm
+                    .as_integer()
+                    .ok_or(Ctap2StatusCode::CborUnexpectedType)?,
+            );
+        }
+    }
+
+    // excludeList (0x05)
+    let exclude_list = match parameters.get_int(0x05) {
+        Some(list) => decode_credential_descriptors(list)?,
+        None => Vec::new(),
+    };
+
+    // options (0x07)
+    let mut resident_key = false;
+    let mut user_verification = false;
+    if let Some(options) = parameters.get_int(0x07) {
+        if options.get_text("up").is_some() {
+            return Err(Ctap2StatusCode::InvalidOption);
+        }
+        resident_key = optional_bool(options, "rk")?.unwrap_or(false);
+        user_verification = optional_bool(options, "uv")?.unwrap_or(false);
+    }
+
+    Ok(Ctap2Request::MakeCredential {
+        client_data_hash,
+        rp,
+        user,
+        algorithms,
+        exclude_list,
+        resident_key,
+        user_verification,
+    })
+}
+
+fn decode_get_assertion(parameters: &Value) -> Result<Ctap2Request, Ctap2StatusCode> {
+    // rpId (0x01)
+    let rp_id = expect_text(required(parameters, 0x01)?)?.to_string();
+
+    // clientDataHash (0x02)
+    let client_data_hash = expect_bytes(required(parameters, 0x02)?)?.to_vec();
+
+    // allowList (0x03)
+    let allow_list = match parameters.get_int(0x03) {
+        Some(list) => decode_credential_descriptors(list)?,
+        None => Vec::new(),
+    };
+
+    // options (0x05)
+    let mut user_presence = true;
+    let mut user_verification = false;
+    if let Some(options) = parameters.get_int(0x05) {
+        user_presence = optional_bool(options, "up")?.unwrap_or(true);
+        user_verification = optional_bool(options, "uv")?.unwrap_or(false);
+    }
+
+    Ok(Ctap2Request::GetAssertion {
+        rp_id,
+        client_data_hash,
+        allow_list,
+        user_presence,
+        user_verification,
+    })
+}
+
+/// Credential descriptors of an unknown type, or with an ID too long
+/// to be a key handle we issued, can never match and are skipped.
+fn decode_credential_descriptors(list: &Value) -> Result<Vec<KeyHandle>, Ctap2StatusCode> {
+    let mut handles = Vec::new();
+    for descriptor in expect_array(list)? {
+        let credential_type = descriptor
+            .get_text("type")
+            .ok_or(Ctap2StatusCode::MissingParameter)?;
+        let id = expect_bytes(
+            descriptor
+                .get_text("id")
+                .ok_or(Ctap2StatusCode::MissingParameter)?,
+        )?;
+        if expect_text(credential_type)? == "public-key" && id.len() <= MAX_KEY_HANDLE_LEN {
+            handles.push(KeyHandle::from(id));
+        }
+    }
+    Ok(handles)
+}
+
+fn required(parameters: &Value, key: i64) -> Result<&Value, Ctap2StatusCode> {
+    parameters
+        .get_int(key)
+        .ok_or(Ctap2StatusCode::MissingParameter)
+}
+
+fn expect_bytes(value: &Value) -> Result<&[u8], Ctap2StatusCode> {
+    value.as_bytes().ok_or(Ctap2StatusCode::CborUnexpectedType)
+}
+
+fn expect_text(value: &Value) -> Result<&str, Ctap2StatusCode> {
+    value.as_text().ok_or(Ctap2StatusCode::CborUnexpectedType)
+}
+
+fn expect_array(value: &Value) -> Result<&[Value], Ctap2StatusCode> {
+    value.as_array().ok_or(Ctap2StatusCode::CborUnexpectedType)
+}
+
+fn optional_text(map: &Value, key: &str) -> Result<Option<String>, Ctap2StatusCode> {
+    match map.get_text(key) {
+        Some(value) => Ok(Some(expect_text(value)?.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn optional_bool(map: &Value, key: &str) -> Result<Option<bool>, Ctap2StatusCode> {
+    match map.get_text(key) {
+        Some(value) => Ok(Some(
+            value.as_bool().ok_or(Ctap2StatusCode::CborUnexpectedType)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    enum Ctap2Error {
+        Status(code: Ctap2StatusCode) {
+            from()
+        }
+        Io(err: io::Error) {
+            from()
+        }
+        Signing(err: SignError) {
+            from()
+        }
+    }
+}
+
+/// Assertions remaining after a getAssertion that matched several resident
+/// credentials, handed out one at a time by getNextAssertion.
+pub(crate) struct PendingAssertions {
+    client_data_hash: Vec<u8>,
+    credentials: VecDeque<ResidentCredential>,
+}
+
+impl U2F {
+    pub fn ctap2(
+        &self,
+        request: Ctap2Request,
+    ) -> Box<dyn Future<Item = Ctap2Response, Error = io::Error>> {
+        let self_rc = self.0.clone();
+        let logger = self.0.logger.clone();
+        match request {
+            Ctap2Request::GetNextAssertion => {}
+            _ => {
+                self.0.pending_assertions.borrow_mut().take();
+            }
+        }
+        let response: Box<dyn Future<Item = Ctap2Response, Error = Ctap2Error>> = match request {
+            Ctap2Request::MakeCredential {
+                client_data_hash,
+                rp,
+                algorithms,
+                user,
+                exclude_list,
+                resident_key,
+                user_verification,
+            } => {
+                debug!(logger, "Ctap2Request::MakeCredential"; "rp_id" => &rp.id);
+                // The first supported algorithm, in the relying party's order of preference
+                let algorithm = match algorithms
+                    .into_iter()
+                    .filter_map(Algorithm::from_cose)
+                    .find(|&algorithm| self.0.operations.supports_algorithm(algorithm))
+                {
+                    Some(algorithm) => algorithm,
+                    None => {
+                        return Box::new(future::ok(Ctap2Response::Error(
+                            Ctap2StatusCode::UnsupportedAlgorithm,
+                        )));
+                    }
+                };
+                if user_verification {
+                    return Box::new(future::ok(Ctap2Response::Error(
+                        Ctap2StatusCode::UnsupportedOption,
+                    )));
+                }
+                Self::_make_credential_step1(
+                    self_rc,
+                    rp_id_hash(&rp.id),
+                    client_data_hash,
+                    user,
+                    algorithm,
+                    exclude_list,
+                    resident_key,
+                )
+            }
+            Ctap2Request::GetAssertion {
+                rp_id,
+                client_data_hash,
+                allow_list,
+                user_presence,
+                user_verification,
+            } => {
+                debug!(logger, "Ctap2Request::GetAssertion"; "rp_id" => &rp_id);
+                if !user_presence || user_verification {
+                    return Box::new(future::ok(Ctap2Response::Error(
+                        Ctap2StatusCode::UnsupportedOption,
+                    )));
+                }
+                Self::_get_assertion_step1(
+                    self_rc,
+                    rp_id_hash(&rp_id),
+                    client_data_hash,
+                    allow_list,
+                )
+            }
+            Ctap2Request::GetNextAssertion => {
+                debug!(logger, "Ctap2Request::GetNextAssertion");
+                Box::new(future::result(Self::_get_next_assertion(self_rc)))
+            }
+            Ctap2Request::GetInfo => {
+                debug!(logger, "Ctap2Request::GetInfo");
+                Box::new(future::ok(Ctap2Response::GetInfo))
+            }
+            Ctap2Request::Reset => {
+                debug!(logger, "Ctap2Request::Reset");
+                Self::_reset(self_rc)
+            }
+        };
+
+        Box::new(response.or_else(move |err| match err {
+            Ctap2Error::Status(status) => {
+                info!(logger, "CTAP2 error"; "status" => ?status);
+                Ok(Ctap2Response::Error(status))
+            }
+            Ctap2Error::Io(err) => {
+                info!(logger, "I/O error"; "error" => ?err);
+                Ok(Ctap2Response::Error(Ctap2StatusCode::Other))
+            }
+            Ctap2Error::Signing(err) => {
+                info!(logger, "Signing error"; "error" => ?err);
+                Ok(Ctap2Response::Error(Ctap2StatusCode::Other))
+            }
+        }))
+    }
+
+    fn _make_credential_step1(
+        self_rc: Rc<U2FInner>,
+        application: AppId,
+        client_data_hash: Vec<u8>,
+        user: User,
+        algorithm: Algorithm,
+        exclude_list: Vec<KeyHandle>,
+        resident_key: bool,
+    ) -> Box<dyn Future<Item = Ctap2Response, Error = Ctap2Error>> {
+        let mut excluded = false;
+        for handle in &exclude_list {
+            match self_rc.retrieve_application_key(&application, handle) {
+                Ok(Some(_)) => {
+                    excluded = true;
+                    break;
+                }
+                Ok(None) => {}
+                Err(err) => return Box::new(future::err(err.into())),
+            }
+        }
+
+        // Even when excluded, user presence is tested first so the
+        // relying party cannot silently probe for existing credentials.
+        Box::new(
+            self_rc
+                .approval
+                .approve_registration(&application)
+                .from_err()
+                .and_then(move |user_present| {
+                    if !user_present {
+                        Err(Ctap2StatusCode::OperationDenied.into())
+                    } else if excluded {
+                        Err(Ctap2StatusCode::CredentialExcluded.into())
+                    } else {
+                        Self::_make_credential_step2(
+                            self_rc,
+                            application,
+                            client_data_hash,
+                            user,
+                            algorithm,
+                            resident_key,
+                        )
+                    }
+                }),
+        )
+    }
+
+    fn _make_credential_step2(
+        self_rc: Rc<U2FInner>,
+        application: AppId,
+        client_data_hash: Vec<u8>,
+        user: User,
+        algorithm: Algorithm,
+        resident_key: bool,
+    ) -> Result<Ctap2Response, Ctap2Error> {
+        let application_key = self_rc
+            .operations
+            .generate_credential_key(&application, algorithm)?;
+        if resident_key {
+            self_rc
+                .storage
+                .add_resident_credential(&ResidentCredential {
+                    application_key: application_key.clone(),
+                    user,
+                })?;
+        } else {
+            self_rc.add_application_key(&application_key)?;
+        }
+
+        let auth_data = authenticator_data(
+            &application,
+            AUTHENTICATOR_DATA_FLAG_USER_PRESENT | AUTHENTICATOR_DATA_FLAG_ATTESTED_CREDENTIAL_DATA,
+            0,
+            Some(&attested_credential_data(&application_key)),
+        );
+        let attestation_signature = self_rc
+            .operations
+            .attest(&message_to_sign(&auth_data, &client_data_hash))?;
+
+        Ok(Ctap2Response::MakeCredential {
+            auth_data,
+            attestation_certificate: self_rc.operations.get_attestation_certificate(),
+            attestation_chain: self_rc.operations.get_attestation_chain(),
+            attestation_signature,
+        })
+    }
+
+    fn _get_assertion_step1(
+        self_rc: Rc<U2FInner>,
+        application: AppId,
+        client_data_hash: Vec<u8>,
+        allow_list: Vec<KeyHandle>,
+    ) -> Box<dyn Future<Item = Ctap2Response, Error = Ctap2Error>> {
+        // Without an allow list the relying party asks for its resident
+        // credentials, otherwise the first known key handle is used.
+        let mut credentials: VecDeque<(ApplicationKey, Option<User>)> = VecDeque::new();
+        if allow_list.is_empty() {
+            match self_rc.storage.list_resident_credentials(&application) {
+                Ok(resident_credentials) => {
+                    credentials.extend(
+                        resident_credentials
+                            .into_iter()
+                            .map(|credential| (credential.application_key, Some(credential.user))),
+                    );
+                }
+                Err(err) => return Box::new(future::err(err.into())),
+            }
+        } else {
+            for handle in &allow_list {
+                match self_rc.retrieve_application_key(&application, handle) {
+                    Ok(Some(key)) => {
+                        credentials.push_back((key, None));
+                        break;
+                    }
+                    Ok(None) => {}
+                    Err(err) => return Box::new(future::err(err.into())),
+                }
+            }
+        }
+        let (application_key, user) = match credentials.pop_front() {
+            Some(credential) => credential,
+            None => return Box::new(future::err(Ctap2StatusCode::NoCredentials.into())),
+        };
+
+        Box::new(
+            self_rc
+                .approval
+                .approve_authentication(&application)
+                .from_err()
+                .and_then(move |user_present| {
+                    if !user_present {
+                        return Err(Ctap2StatusCode::OperationDenied.into());
+                    }
+                    let number_of_credentials = if credentials.is_empty() {
+                        None
+                    } else {
+                        Some(credentials.len() + 1)
+                    };
+                    let response = Self::_get_assertion_step2(
+                        &self_rc,
+                        &client_data_hash,
+                        application_key,
+                        user,
+                        number_of_credentials,
+                    )?;
+                    if !credentials.is_empty() {
+                        *self_rc.pending_assertions.borrow_mut() = Some(PendingAssertions {
+                            client_data_hash,
+                            credentials: credentials
+                                .into_iter()
+                                .filter_map(|(application_key, user)| {
+                                    user.map(|user| ResidentCredential {
+                                        application_key,
+                                        user,
+                                    })
+                                })
+                                .collect(),
+                        });
+                    }
+                    Ok(response)
+                }),
+        )
+    }
+
+    fn _get_assertion_step2(
+        self_rc: &Rc<U2FInner>,
+        client_data_hash: &[u8],
+        application_key: ApplicationKey,
+        user: Option<User>,
+        number_of_credentials: Option<usize>,
+    ) -> Result<Ctap2Response, Ctap2Error> {
+        let counter = self_rc
+            .storage
+            .get_and_increment_counter(&application_key.application, &application_key.handle)?;
+
+        let auth_data = authenticator_data(
+            &application_key.application,
+            AUTHENTICATOR_DATA_FLAG_USER_PRESENT,
+            counter,
+            None,
+        );
+        let signature = self_rc.operations.sign(
+            application_key.key(),
+            &message_to_sign(&auth_data, client_data_hash),
+        )?;
+
+        Ok(Ctap2Response::GetAssertion {
+            credential_id: application_key.handle,
+            auth_data,
+            signature,
+            user,
+            number_of_credentials,
+        })
+    }
+
+    fn _get_next_assertion(self_rc: Rc<U2FInner>) -> Result<Ctap2Response, Ctap2Error> {
+        // User presence was already tested by the getAssertion that started this sequence.
+        let mut pending_assertions = self_rc.pending_assertions.borrow_mut();
+        let (client_data_hash, credential) = match pending_assertions.as_mut() {
+            Some(pending) => match pending.credentials.pop_front() {
+                Some(credential) => (pending.client_data_hash.clone(), credential),
+                None => return Err(Ctap2StatusCode::NotAllowed.into()),
+            },
+            None => return Err(Ctap2StatusCode::NotAllowed.into()),
+        };
+        if pending_assertions
+            .as_ref()
+            .map_or(false, |pending| pending.credentials.is_empty())
+        {
+            pending_assertions.take();
+        }
+        drop(pending_assertions);
+
+        Self::_get_assertion_step2(
+            &self_rc,
+            &client_data_hash,
+            credential.application_key,
+            Some(credential.user),
+            None,
+        )
+    }
+
+    fn _reset(self_rc: Rc<U2FInner>) -> Box<dyn Future<Item = Ctap2Response, Error = Ctap2Error>> {
+        Box::new(
+            self_rc
+                .approval
+                .approve_reset()
+                .from_err()
+                .and_then(move |user_present| {
+                    if !user_present {
+                        return Err(Ctap2StatusCode::OperationDenied.into());
+                    }
+                    let application_keys = self_rc.storage.list_application_keys()?;
+                    self_rc.storage.remove_all_application_keys()?;
+                    info!(self_rc.logger, "reset, removed all application keys");
+                    for application_key in application_keys {
+                        if let Err(err) = self_rc.operations.destroy_key(application_key.key()) {
+                            warn!(self_rc.logger, "reset, failed to destroy key"; "error" => %err);
+                        }
+                    }
+                    // Key handles that carry their key must stop working too
+                    self_rc.operations.reset()?;
+                    Ok(Ctap2Response::Reset)
+                }),
+        )
+    }
+}
+
+fn rp_id_hash(rp_id: &str) -> AppId {
+    AppId::from_bytes(digest::digest(&digest::SHA256, rp_id.as_bytes()).as_ref())
+}
+
+fn authenticator_data(
+    application: &AppId,
+    flags: u8,
+    counter: Counter,
+    attested_credential_data: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+
+    // SHA-256 hash of the RP ID [32 bytes].
+    data.extend_from_slice(application.as_ref());
+
+    // Flags [1 byte].
+    data.push(flags);
+
+    // Signature counter [4 bytes], big-endian.
+    data.write_u32::<BigEndian>(counter).unwrap();
+
+    // Attested credential data [variable length], only present when registering.
+    if let Some(attested_credential_data) = attested_credential_data {
+        data.extend_from_slice(attested_credential_data);
+    }
+
+    data
+}
+
+fn attested_credential_data(application_key: &ApplicationKey) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    let credential_id = application_key.handle.as_ref();
+
+    // AAGUID of the authenticator [16 bytes].
+    data.extend_from_slice(&AAGUID);
+
+    // Byte length L of the credential ID [2 bytes], big-endian.
+    data.write_u16::<BigEndian>(credential_id.len() as u16)
+        .unwrap();
+
+    // Credential ID [L bytes].
+    data.extend_from_slice(credential_id);
+
+    // Credential public key encoded in COSE_Key format [variable length].
+    let public_key = PublicKey::from_key(application_key.key());
+    data.extend_from_slice(&cose_key(&public_key, application_key.algorithm()).to_bytes());
+
+    data
+}
+
+fn cose_key(public_key: &PublicKey, algorithm: Algorithm) -> Value {
+    // alg
+    let alg = (Value::Integer(3), Value::Integer(algorithm.to_cose()));
+    match algorithm {
+        Algorithm::Es256 | Algorithm::Es384 => {
+            // [0x04, X, Y]
+            let raw = public_key.to_raw();
+            let coordinate_len = (raw.len() - 1) / 2;
+            let curve = match algorithm {
+                Algorithm::Es384 => COSE_CURVE_P384,
+                _ => COSE_CURVE_P256,
+            };
+            Value::Map(vec![
+                // kty
+                (Value::Integer(1), Value::Integer(COSE_KEY_TYPE_EC2)),
+                alg,
+                // crv
+                (Value::Integer(-1), Value::Integer(curve)),
+                // x
+                (
+                    Value::Integer(-2),
+                    Value::Bytes(raw[1..1 + coordinate_len].to_vec()),
+                ),
+                // y
+                (
+                    Value::Integer(-3),
+                    Value::Bytes(raw[1 + coordinate_len..].to_vec()),
+                ),
+            ])
+        }
+        Algorithm::EdDsa => Value::Map(vec![
+            // kty
+            (Value::Integer(1), Value::Integer(COSE_KEY_TYPE_OKP)),
+            alg,
+            // crv
+            (Value::Integer(-1), Value::Integer(COSE_CURVE_ED25519)),
+            // x
+            (
+                Value::Integer(-2),
+                Value::Bytes(public_key.to_ed25519().unwrap()),
+            ),
+        ]),
+        Algorithm::Rs256 => {
+            let (modulus, exponent) = public_key.to_rsa_components().unwrap();
+            Value::Map(vec![
+                // kty
+                (Value::Integer(1), Value::Integer(COSE_KEY_TYPE_RSA)),
+                alg,
+                // n
+                (Value::Integer(-1), Value::Bytes(modulus)),
+                // e
+                (Value::Integer(-2), Value::Bytes(exponent)),
+            ])
+        }
+    }
+}
+
+fn message_to_sign(auth_data: &[u8], client_data_hash: &[u8]) -> Vec<u8> {
+    let mut message: Vec<u8> = Vec::new();
+    message.extend_from_slice(auth_data);
+    message.extend_from_slice(client_data_hash);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_request(command_code: u8, parameters: Value) -> Vec<u8> {
+        let mut bytes = vec![command_code];
+        bytes.extend_from_slice(&parameters.to_bytes());
+        bytes
+    }
+
+    fn make_credential_parameters() -> Vec<(Value, Value)> {
+        vec![
+            (Value::Integer(0x01), Value::Bytes(vec![0u8; 32])),
+            (
+                Value::Integer(0x02),
+                Value::Map(vec![(Value::text("id"), Value::text("example.com"))]),
+            ),
+            (
+                Value::Integer(0x03),
+                Value::Map(vec![
+                    (Value::text("id"), Value::Bytes(vec![1, 2, 3, 4])),
+                    (Value::text("name"), Value::text("alice")),
+                ]),
+            ),
+            (
+                Value::Integer(0x04),
+                Value::Array(vec![Value::Map(vec![
+                    (Value::text("alg"), Value::Integer(COSE_ALGORITHM_ES256)),
+                    (Value::text("type"), Value::text("public-key")),
+                ])]),
+            ),
+        ]
+    }
+
+    #[test]
+    fn decode_empty_is_invalid_length() {
+        assert_matches!(
+            Ctap2Request::decode(&[]),
+            Err(Ctap2StatusCode::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn decode_unknown_command_is_invalid_command() {
+        assert_matches!(
+            Ctap2Request::decode(&[0x42]),
+            Err(Ctap2StatusCode::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn decode_get_info() {
+        assert_matches!(
+            Ctap2Request::decode(&[CTAP2_GET_INFO_COMMAND_CODE]),
+            Ok(Ctap2Request::GetInfo)
+        );
+    }
+
+    #[test]
+    fn decode_make_credential() {
+        let bytes = encode_request(
+            CTAP2_MAKE_CREDENTIAL_COMMAND_CODE,
+            Value::Map(make_credential_parameters()),
+        );
+
+        match Ctap2Request::decode(&bytes).unwrap() {
+            Ctap2Request::MakeCredential {
+                rp,
+                user,
+                algorithms,
+                exclude_list,
+                resident_key,
+                ..
+            } => {
+                assert_eq!(rp.id, "example.com");
+                assert_eq!(user.id, vec![1, 2, 3, 4]);
+                assert_eq!(user.name, Some(String::from("alice")));
+                assert_eq!(algorithms, vec![COSE_ALGORITHM_ES256]);
+                assert!(exclude_list.is_empty());
+                assert!(!resident_key);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn decode_make_credential_without_user_is_missing_parameter() {
+        let mut parameters = make_credential_parameters();
+        parameters.retain(|(k, _)| k != &Value::Integer(0x03));
+        let bytes = encode_request(CTAP2_MAKE_CREDENTIAL_COMMAND_CODE, Value::Map(parameters));
+
+        assert_matches!(
+            Ctap2Request::decode(&bytes),
+            Err(Ctap2StatusCode::MissingParameter)
+        );
+    }
+
+    #[test]
+    fn decode_make_credential_with_up_option_is_invalid_option() {
+        let mut parameters = make_credential_parameters();
+        parameters.push((
+            Value::Integer(0x07),
+            Value::Map(vec![(Value::text("up"), Value::Bool(true))]),
+        ));
+        let bytes = encode_request(CTAP2_MAKE_CREDENTIAL_COMMAND_CODE, Value::Map(parameters));
+
+        assert_matches!(
+            Ctap2Request::decode(&bytes),
+            Err(Ctap2StatusCode::InvalidOption)
+        );
+    }
+
+    #[test]
+    fn decode_get_assertion_with_malformed_cbor_is_invalid_cbor() {
+        assert_matches!(
+            Ctap2Request::decode(&[CTAP2_GET_ASSERTION_COMMAND_CODE, 0xa1]),
+            Err(Ctap2StatusCode::InvalidCbor)
+        );
+    }
+
+    #[test]
+    fn error_response_is_single_status_byte() {
+        assert_eq!(
+            Ctap2Response::Error(Ctap2StatusCode::NoCredentials).into_bytes(),
+            vec![CTAP2_ERR_NO_CREDENTIALS]
+        );
+    }
+
+    #[test]
+    fn get_info_response_lists_fido2_version() {
+        let bytes = Ctap2Response::GetInfo.into_bytes();
+        assert_eq!(bytes[0], CTAP2_OK);
+
+        let info = Value::from_bytes(&bytes[1..]).unwrap();
+        let versions = info.get_int(0x01).unwrap().as_array().unwrap();
+        assert!(versions.contains(&Value::text("FIDO_2_0")));
+        assert!(versions.contains(&Value::text("U2F_V2")));
+    }
+}
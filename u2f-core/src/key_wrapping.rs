@@ -0,0 +1,357 @@
+use std::cell::RefCell;
+use std::fmt::{self, Debug};
+use std::io;
+use std::result::Result;
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use app_id::AppId;
+use application_key::ApplicationKey;
+use attestation::{Attestation, AttestationCertificate};
+use key_handle::KeyHandle;
+use openssl_crypto::OpenSSLCryptoOperations;
+use private_key::PrivateKey;
+use serde_base64::{from_base64, to_base64};
+
+use super::CryptoOperations;
+use super::SignError;
+use super::Signature;
+
+const KEY_HANDLE_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const PRIVATE_KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const KEY_HANDLE_LEN: usize = 1 + NONCE_LEN + PRIVATE_KEY_LEN + TAG_LEN;
+
+const DERIVE_KEY_LABEL: &[u8] = b"u2f key derivation";
+const WRAP_KEY_LABEL: &[u8] = b"u2f key wrapping";
+
+/// Device secret all key handles are derived from and wrapped with
+#[derive(Clone)]
+pub struct MasterSecret([u8; 32]);
+
+impl MasterSecret {
+    pub fn generate() -> MasterSecret {
+        MasterSecret(rand::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MasterSecret {
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(bytes);
+        MasterSecret(secret)
+    }
+
+    fn hmac(&self, label: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        let pkey = PKey::hmac(&self.0).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+        signer.update(label).unwrap();
+        for part in parts {
+            signer.update(part).unwrap();
+        }
+        signer.sign_to_vec().unwrap()
+    }
+}
+
+impl AsRef<[u8]> for MasterSecret {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl Debug for MasterSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MasterSecret")
+    }
+}
+
+impl Serialize for MasterSecret {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        to_base64(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MasterSecret {
+    fn deserialize<D>(deserializer: D) -> Result<MasterSecret, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let bytes = from_base64(deserializer)?;
+        if bytes.len() != 32 {
+            return Err(D::Error::invalid_length(bytes.len(), &"32 bytes"));
+        }
+        Ok(MasterSecret::from_bytes(&bytes))
+    }
+}
+
+/// Stateless credentials: the per-site private key is derived from the
+/// master secret and carried inside the key handle, encrypted and
+/// authenticated with the application parameter, like hardware tokens do.
+///
+/// Key handle layout:
+/// version [1 byte] | nonce [12 bytes] | encrypted private key [32 bytes] | tag [16 bytes]
+pub struct KeyWrappingCryptoOperations {
+    master_secret: RefCell<MasterSecret>,
+    operations: OpenSSLCryptoOperations,
+    /// Keeps a master secret generated on reset, so it is still used after a restart
+    save_master_secret: Option<Box<dyn Fn(&MasterSecret) -> io::Result<()>>>,
+}
+
+impl KeyWrappingCryptoOperations {
+    pub fn new(
+        attestation: Attestation,
+        master_secret: MasterSecret,
+    ) -> KeyWrappingCryptoOperations {
+        KeyWrappingCryptoOperations {
+            master_secret: RefCell::new(master_secret),
+            operations: OpenSSLCryptoOperations::new(attestation),
+            save_master_secret: None,
+        }
+    }
+
+    pub fn with_master_secret_saver<F>(self, save_master_secret: F) -> KeyWrappingCryptoOperations
+    where
+        F: Fn(&MasterSecret) -> io::Result<()> + 'static,
+    {
+        KeyWrappingCryptoOperations {
+            save_master_secret: Some(Box::new(save_master_secret)),
+            ..self
+        }
+    }
+
+    fn wrapping_key(&self) -> Vec<u8> {
+        self.master_secret.borrow().hmac(WRAP_KEY_LABEL, &[])
+    }
+
+    /// Derive a private key for the application, None if the derived
+    /// scalar is not a valid P-256 private key and a new nonce is needed
+    fn derive_key(&self, application: &AppId, nonce: &[u8]) -> Option<EcKey<Private>> {
+        let scalar = self
+            .master_secret
+            .borrow()
+            .hmac(DERIVE_KEY_LABEL, &[application.as_ref(), nonce]);
+        private_key_from_scalar(&scalar)
+    }
+}
+
+impl CryptoOperations for KeyWrappingCryptoOperations {
+    fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.operations.attest(data)
+    }
+
+    fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
+        loop {
+            let nonce: [u8; NONCE_LEN] = rand::random();
+            let ec_key = match self.derive_key(application, &nonce) {
+                Some(ec_key) => ec_key,
+                None => continue,
+            };
+            let scalar = ec_key.private_key().to_vec_padded(PRIVATE_KEY_LEN as i32)?;
+
+            let mut tag = [0u8; TAG_LEN];
+            let ciphertext = encrypt_aead(
+                Cipher::aes_256_gcm(),
+                &self.wrapping_key(),
+                Some(&nonce),
+                application.as_ref(),
+                &scalar,
+                &mut tag,
+            )?;
+
+            let mut handle = Vec::with_capacity(KEY_HANDLE_LEN);
+            handle.push(KEY_HANDLE_VERSION);
+            handle.extend_from_slice(&nonce);
+            handle.extend_from_slice(&ciphertext);
+            handle.extend_from_slice(&tag);
+
+            return Ok(ApplicationKey::new(
+                *application,
+                KeyHandle::from(&handle),
+                PrivateKey::from_ec_key(ec_key),
+            ));
+        }
+    }
+
+    fn unwrap_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        let bytes = handle.as_ref();
+        if bytes.len() != KEY_HANDLE_LEN || bytes[0] != KEY_HANDLE_VERSION {
+            return Ok(None);
+        }
+        let nonce = &bytes[1..1 + NONCE_LEN];
+        let ciphertext = &bytes[1 + NONCE_LEN..1 + NONCE_LEN + PRIVATE_KEY_LEN];
+        let tag = &bytes[1 + NONCE_LEN + PRIVATE_KEY_LEN..];
+
+        // Fails when the handle was not created by this device, or for a different application
+        let scalar = match decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.wrapping_key(),
+            Some(nonce),
+            application.as_ref(),
+            ciphertext,
+            tag,
+        ) {
+            Ok(scalar) => scalar,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(private_key_from_scalar(&scalar).map(|ec_key| {
+            ApplicationKey::new(
+                *application,
+                handle.clone(),
+                PrivateKey::from_ec_key(ec_key),
+            )
+        }))
+    }
+
+    fn get_attestation_certificate(&self) -> AttestationCertificate {
+        self.operations.get_attestation_certificate()
+    }
+
+    fn get_attestation_chain(&self) -> Vec<AttestationCertificate> {
+        self.operations.get_attestation_chain()
+    }
+
+    fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.operations.sign(key, data)
+    }
+
+    fn destroy_key(&self, key: &PrivateKey) -> io::Result<()> {
+        self.operations.destroy_key(key)
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        let master_secret = MasterSecret::generate();
+        if let Some(ref save_master_secret) = self.save_master_secret {
+            save_master_secret(&master_secret)?;
+        }
+        *self.master_secret.borrow_mut() = master_secret;
+        Ok(())
+    }
+}
+
+fn private_key_from_scalar(scalar: &[u8]) -> Option<EcKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let mut ctx = BigNumContext::new().unwrap();
+    let mut order = BigNum::new().unwrap();
+    group.order(&mut order, &mut ctx).unwrap();
+
+    let private_number = BigNum::from_slice(scalar).unwrap();
+    if private_number.num_bits() == 0 || private_number >= order {
+        return None;
+    }
+
+    let mut public_point = EcPoint::new(&group).unwrap();
+    public_point
+        .mul_generator(&group, &private_number, &ctx)
+        .unwrap();
+    EcKey::from_private_components(&group, &private_number, &public_point).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    use public_key::PublicKey;
+
+    fn fake_attestation() -> Attestation {
+        ::self_signed_attestation()
+    }
+
+    #[test]
+    fn unwrap_generated_key_returns_same_key() {
+        let operations =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate());
+        let application = AppId(rand::random());
+
+        let generated = operations.generate_application_key(&application).unwrap();
+        let unwrapped = operations
+            .unwrap_application_key(&application, &generated.handle)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            PublicKey::from_key(unwrapped.key()).to_raw(),
+            PublicKey::from_key(generated.key()).to_raw()
+        );
+    }
+
+    #[test]
+    fn unwrap_with_other_application_is_none() {
+        let operations =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate());
+        let generated = operations
+            .generate_application_key(&AppId(rand::random()))
+            .unwrap();
+
+        assert!(operations
+            .unwrap_application_key(&AppId(rand::random()), &generated.handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unwrap_with_other_master_secret_is_none() {
+        let application = AppId(rand::random());
+        let generated =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate())
+                .generate_application_key(&application)
+                .unwrap();
+        let operations =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate());
+
+        assert!(operations
+            .unwrap_application_key(&application, &generated.handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unwrap_random_handle_is_none() {
+        let operations =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate());
+
+        assert!(operations
+            .unwrap_application_key(&AppId(rand::random()), &rand::random())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unwrap_after_reset_is_none() {
+        let application = AppId(rand::random());
+        let saved = Rc::new(RefCell::new(None));
+        let saved_clone = saved.clone();
+        let operations =
+            KeyWrappingCryptoOperations::new(fake_attestation(), MasterSecret::generate())
+                .with_master_secret_saver(move |master_secret| {
+                    *saved_clone.borrow_mut() = Some(master_secret.clone());
+                    Ok(())
+                });
+        let generated = operations.generate_application_key(&application).unwrap();
+
+        operations.reset().unwrap();
+
+        assert!(operations
+            .unwrap_application_key(&application, &generated.handle)
+            .unwrap()
+            .is_none());
+        let saved = saved.borrow().clone().unwrap();
+        assert_eq!(saved.as_ref(), operations.master_secret.borrow().as_ref());
+    }
+}
@@ -1,13 +1,16 @@
 use std::io;
 
+use algorithm::Algorithm;
 use app_id::AppId;
 use application_key::ApplicationKey;
 use attestation::{Attestation, AttestationCertificate};
 use key_handle::KeyHandle;
 use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::PKey;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
 use openssl::sign::Signer;
 use private_key::PrivateKey;
 
@@ -15,6 +18,8 @@ use super::CryptoOperations;
 use super::Signature;
 use super::SignError;
 
+const RSA_KEY_BITS: u32 = 2048;
+
 pub struct OpenSSLCryptoOperations {
     attestation: Attestation,
 }
@@ -29,7 +34,20 @@ impl OpenSSLCryptoOperations {
     fn generate_key() -> PrivateKey {
         let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
         let ec_key = EcKey::generate(&group).unwrap();
-        PrivateKey(ec_key)
+        PrivateKey::from_ec_key(ec_key)
+    }
+
+    fn generate_key_with_algorithm(algorithm: Algorithm) -> Result<PrivateKey, ErrorStack> {
+        let pkey = match algorithm {
+            Algorithm::Es256 => return Ok(Self::generate_key()),
+            Algorithm::Es384 => {
+                let group = EcGroup::from_curve_name(Nid::SECP384R1)?;
+                return Ok(PrivateKey::from_ec_key(EcKey::generate(&group)?));
+            }
+            Algorithm::EdDsa => PKey::generate_ed25519()?,
+            Algorithm::Rs256 => PKey::from_rsa(Rsa::generate(RSA_KEY_BITS)?)?,
+        };
+        PrivateKey::from_pkey(&pkey).ok_or_else(ErrorStack::get)
     }
 
     fn generate_key_handle() -> io::Result<KeyHandle> {
@@ -48,22 +66,78 @@ impl CryptoOperations for OpenSSLCryptoOperations {
         Ok(ApplicationKey::new(*application, handle, key))
     }
 
+    fn supports_algorithm(&self, _algorithm: Algorithm) -> bool {
+        true
+    }
+
+    fn generate_credential_key(
+        &self,
+        application: &AppId,
+        algorithm: Algorithm,
+    ) -> io::Result<ApplicationKey> {
+        let key = Self::generate_key_with_algorithm(algorithm)?;
+        let handle = Self::generate_key_handle()?;
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+
+    fn unwrap_application_key(
+        &self,
+        _application: &AppId,
+        _handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        // Key handles are random, keys are kept by the secret store
+        Ok(None)
+    }
+
     fn get_attestation_certificate(&self) -> AttestationCertificate {
         self.attestation.certificate.clone()
     }
 
+    fn get_attestation_chain(&self) -> Vec<AttestationCertificate> {
+        self.attestation.chain.clone()
+    }
+
     fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
-        let ec_key = key.0.to_owned();
-        let pkey = PKey::from_ec_key(ec_key).unwrap();
-        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
-        signer.update(data).unwrap();
-        let signature = signer.sign_to_vec().unwrap();
-        Ok(Box::new(RawSignature(signature)))
+        sign_in_memory(key, data)
     }
 }
 
+/// Sign with a key OpenSSL can read, a key held by a token is unsupported
+pub(crate) fn sign_in_memory(
+    key: &PrivateKey,
+    data: &[u8],
+) -> Result<Box<dyn Signature>, SignError> {
+    let pkey = key.to_pkey().ok_or(SignError::UnsupportedKey)?;
+    let algorithm = key.algorithm().ok_or(SignError::UnsupportedKey)?;
+    match sign_with_pkey(&pkey, algorithm, data) {
+        Ok(signature) => Ok(Box::new(signature)),
+        Err(err) => Err(SignError::Backend(err.to_string())),
+    }
+}
+
+/// Signature in the form WebAuthn expects for the algorithm: DER encoded for
+/// ECDSA, raw for EdDSA and RSA
+fn sign_with_pkey(
+    pkey: &PKey<Private>,
+    algorithm: Algorithm,
+    data: &[u8],
+) -> Result<RawSignature, ErrorStack> {
+    let digest = match algorithm {
+        Algorithm::Es256 | Algorithm::Rs256 => MessageDigest::sha256(),
+        Algorithm::Es384 => MessageDigest::sha384(),
+        Algorithm::EdDsa => {
+            // EdDSA hashes the message itself, in a single pass
+            let mut signer = Signer::new_without_digest(pkey)?;
+            return signer.sign_oneshot_to_vec(data).map(RawSignature);
+        }
+    };
+    let mut signer = Signer::new(digest, pkey)?;
+    signer.update(data)?;
+    signer.sign_to_vec().map(RawSignature)
+}
+
 #[derive(Debug)]
-struct RawSignature(Vec<u8>);
+pub(crate) struct RawSignature(pub(crate) Vec<u8>);
 
 impl Signature for RawSignature {}
 
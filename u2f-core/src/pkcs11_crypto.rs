@@ -0,0 +1,401 @@
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::sha::sha256;
+use pkcs11::errors::Error as Pkcs11Error;
+use pkcs11::types::*;
+use pkcs11::Ctx;
+
+use app_id::AppId;
+use application_key::ApplicationKey;
+use attestation::{Attestation, AttestationCertificate};
+use constants::EC_POINT_FORMAT_UNCOMPRESSED;
+use key_handle::KeyHandle;
+use openssl_crypto::{sign_in_memory, RawSignature};
+use private_key::{KeyMaterial, Pkcs11KeyRef, PrivateKey};
+use public_key::PublicKey;
+
+use super::CryptoOperations;
+use super::SignError;
+use super::Signature;
+
+/// DER encoded object identifier of the P-256 curve, prime256v1
+const P256_EC_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const DER_OCTET_STRING: u8 = 0x04;
+const RAW_PUBLIC_KEY_LEN: usize = 65;
+const KEY_ID_LEN: usize = 16;
+const KEY_LABEL: &[u8] = b"softu2f";
+
+/// Generates and signs with per-site keys held by a PKCS#11 token, such as
+/// SoftHSM or a smartcard. Only a reference to each key is kept by the secret
+/// store, the private key never leaves the token. The attestation key is
+/// still held in memory, it is shared by all installs or kept in the secret
+/// store already.
+pub struct Pkcs11CryptoOperations {
+    ctx: Ctx,
+    session: CK_SESSION_HANDLE,
+    attestation: Attestation,
+}
+
+impl Pkcs11CryptoOperations {
+    /// Load the PKCS#11 module and log in to the token with the given label,
+    /// or the first token found if no label is given
+    pub fn new(
+        module_path: &Path,
+        token_label: Option<&str>,
+        pin: &str,
+        attestation: Attestation,
+    ) -> io::Result<Pkcs11CryptoOperations> {
+        let ctx = Ctx::new_and_initialize(module_path).map_err(to_io_error)?;
+        let slot = find_slot(&ctx, token_label)?;
+        let session = ctx
+            .open_session(slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None)
+            .map_err(to_io_error)?;
+        ctx.login(session, CKU_USER, Some(pin))
+            .map_err(to_io_error)?;
+        Ok(Pkcs11CryptoOperations {
+            ctx,
+            session,
+            attestation,
+        })
+    }
+
+    fn generate_key(&self) -> io::Result<PrivateKey> {
+        let id: [u8; KEY_ID_LEN] = rand::random();
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_EC_KEY_PAIR_GEN,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let public_template = vec![
+            // Only needed until its point has been read
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_VERIFY).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(P256_EC_PARAMS),
+        ];
+        let private_template = vec![
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_EXTRACTABLE).with_bool(&CK_FALSE),
+            CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&CK_TRUE),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+            CK_ATTRIBUTE::new(CKA_LABEL).with_bytes(KEY_LABEL),
+        ];
+        let (public_key, _) = self
+            .ctx
+            .generate_key_pair(
+                self.session,
+                &mechanism,
+                &public_template,
+                &private_template,
+            )
+            .map_err(to_io_error)?;
+
+        let point = self.read_ec_point(public_key)?;
+        self.ctx
+            .destroy_object(self.session, public_key)
+            .map_err(to_io_error)?;
+        PublicKey::from_bytes(&point)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        Ok(PrivateKey(KeyMaterial::Pkcs11(Pkcs11KeyRef {
+            id: id.to_vec(),
+            public_key: point,
+        })))
+    }
+
+    /// Raw uncompressed point of a public key object
+    fn read_ec_point(&self, object: CK_OBJECT_HANDLE) -> io::Result<Vec<u8>> {
+        // First ask for the length, then for the value
+        let mut template = vec![CK_ATTRIBUTE::new(CKA_EC_POINT)];
+        self.ctx
+            .get_attribute_value(self.session, object, &mut template)
+            .map_err(to_io_error)?;
+        let buffer = vec![0u8; template[0].ulValueLen as usize];
+        let mut template = vec![CK_ATTRIBUTE::new(CKA_EC_POINT).with_bytes(&buffer)];
+        self.ctx
+            .get_attribute_value(self.session, object, &mut template)
+            .map_err(to_io_error)?;
+        let mut value = template[0].get_bytes();
+
+        // Tokens wrap the point in a DER octet string, as the standard asks, or not
+        if value.len() == RAW_PUBLIC_KEY_LEN + 2
+            && value[0] == DER_OCTET_STRING
+            && value[1] as usize == RAW_PUBLIC_KEY_LEN
+        {
+            value.drain(..2);
+        }
+        if value.len() != RAW_PUBLIC_KEY_LEN || value[0] != EC_POINT_FORMAT_UNCOMPRESSED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "token returned an unexpected public key format",
+            ));
+        }
+        Ok(value)
+    }
+
+    fn find_private_key(&self, id: &[u8]) -> Result<CK_OBJECT_HANDLE, Pkcs11Error> {
+        self.find_private_key_if_present(id)?
+            .ok_or_else(|| Pkcs11Error::Module("private key not found on token"))
+    }
+
+    fn find_private_key_if_present(
+        &self,
+        id: &[u8],
+    ) -> Result<Option<CK_OBJECT_HANDLE>, Pkcs11Error> {
+        let template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PRIVATE_KEY),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+        ];
+        self.ctx.find_objects_init(self.session, &template)?;
+        let objects = self.ctx.find_objects(self.session, 1);
+        self.ctx.find_objects_final(self.session)?;
+        Ok(objects?.pop())
+    }
+
+    fn sign_on_token(
+        &self,
+        key_ref: &Pkcs11KeyRef,
+        data: &[u8],
+    ) -> Result<RawSignature, SignError> {
+        let key = self.find_private_key(&key_ref.id).map_err(to_sign_error)?;
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_ECDSA,
+            pParameter: ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        // CKM_ECDSA signs a digest that is already computed
+        let digest = sha256(data).to_vec();
+        self.ctx
+            .sign_init(self.session, &mechanism, key)
+            .map_err(to_sign_error)?;
+        let signature = self
+            .ctx
+            .sign(self.session, &digest)
+            .map_err(to_sign_error)?;
+        raw_to_der(&signature).map(RawSignature)
+    }
+}
+
+impl CryptoOperations for Pkcs11CryptoOperations {
+    fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.sign(&self.attestation.key, data)
+    }
+
+    fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
+        let key = self.generate_key()?;
+        let handle: KeyHandle = rand::random();
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+
+    fn unwrap_application_key(
+        &self,
+        _application: &AppId,
+        _handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        // Key handles are random, key references are kept by the secret store
+        Ok(None)
+    }
+
+    fn get_attestation_certificate(&self) -> AttestationCertificate {
+        self.attestation.certificate.clone()
+    }
+
+    fn get_attestation_chain(&self) -> Vec<AttestationCertificate> {
+        self.attestation.chain.clone()
+    }
+
+    fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        match key.0 {
+            KeyMaterial::Pkcs11(ref key_ref) => Ok(Box::new(self.sign_on_token(key_ref, data)?)),
+            // Keys registered before switching to the token still work
+            KeyMaterial::EcKey(_) | KeyMaterial::Pkcs8(_) => sign_in_memory(key, data),
+        }
+    }
+
+    fn destroy_key(&self, key: &PrivateKey) -> io::Result<()> {
+        let key_ref = match key.0 {
+            KeyMaterial::Pkcs11(ref key_ref) => key_ref,
+            KeyMaterial::EcKey(_) | KeyMaterial::Pkcs8(_) => return Ok(()),
+        };
+        // Already gone, e.g. the token was reinitialized
+        let object = match self
+            .find_private_key_if_present(&key_ref.id)
+            .map_err(to_io_error)?
+        {
+            Some(object) => object,
+            None => return Ok(()),
+        };
+        self.ctx
+            .destroy_object(self.session, object)
+            .map_err(to_io_error)
+    }
+}
+
+impl Drop for Pkcs11CryptoOperations {
+    fn drop(&mut self) {
+        let _ = self.ctx.close_session(self.session);
+    }
+}
+
+fn find_slot(ctx: &Ctx, token_label: Option<&str>) -> io::Result<CK_SLOT_ID> {
+    for slot in ctx.get_slot_list(true).map_err(to_io_error)? {
+        let label = match token_label {
+            Some(label) => label,
+            None => return Ok(slot),
+        };
+        let info = ctx.get_token_info(slot).map_err(to_io_error)?;
+        // Labels are padded with spaces to 32 bytes
+        if String::from_utf8_lossy(&info.label).trim_end() == label {
+            return Ok(slot);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        match token_label {
+            Some(label) => format!("no PKCS#11 token labelled {}", label),
+            None => String::from("no PKCS#11 token present"),
+        },
+    ))
+}
+
+/// PKCS#11 gives ECDSA signatures as r and s concatenated, U2F wants them DER encoded
+fn raw_to_der(signature: &[u8]) -> Result<Vec<u8>, SignError> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err(SignError::Backend(format!(
+            "unexpected ECDSA signature length {}",
+            signature.len()
+        )));
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    BigNum::from_slice(r)
+        .and_then(|r| BigNum::from_slice(s).map(|s| (r, s)))
+        .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+        .and_then(|signature| signature.to_der())
+        .map_err(|err| SignError::Backend(err.to_string()))
+}
+
+fn to_io_error(err: Pkcs11Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+fn to_sign_error(err: Pkcs11Error) -> SignError {
+    SignError::Backend(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use openssl::hash::MessageDigest;
+    use openssl::sign::Verifier;
+
+    use super::*;
+    use self_signed_attestation::self_signed_attestation;
+
+    const MODULE_ENV_VAR: &str = "SOFTU2F_TEST_PKCS11_MODULE";
+    const TOKEN_LABEL_ENV_VAR: &str = "SOFTU2F_TEST_PKCS11_TOKEN";
+    const PIN_ENV_VAR: &str = "SOFTU2F_TEST_PKCS11_PIN";
+
+    /// Operations on the SoftHSM token named by the environment. The tests
+    /// using it are ignored by default, to run them:
+    ///
+    /// softhsm2-util --init-token --free --label softu2f-test --pin 1234 --so-pin 1234
+    /// SOFTU2F_TEST_PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so \
+    ///     SOFTU2F_TEST_PKCS11_TOKEN=softu2f-test SOFTU2F_TEST_PKCS11_PIN=1234 \
+    ///     cargo test -- --ignored
+    fn softhsm_operations() -> Pkcs11CryptoOperations {
+        let module_path = PathBuf::from(
+            env::var_os(MODULE_ENV_VAR)
+                .unwrap_or_else(|| panic!("{} must name a PKCS#11 module", MODULE_ENV_VAR)),
+        );
+        let token_label = env::var(TOKEN_LABEL_ENV_VAR).ok();
+        let pin = env::var(PIN_ENV_VAR).unwrap_or_else(|_| String::from("1234"));
+        Pkcs11CryptoOperations::new(
+            &module_path,
+            token_label.as_ref().map(String::as_str),
+            &pin,
+            self_signed_attestation(),
+        )
+        .unwrap()
+    }
+
+    fn verify(key: &PrivateKey, signature: &[u8], data: &[u8]) -> bool {
+        let public_key = PublicKey::from_key(key);
+        let mut verifier = Verifier::new(MessageDigest::sha256(), public_key.as_pkey()).unwrap();
+        verifier.update(data).unwrap();
+        verifier.verify(signature).unwrap()
+    }
+
+    #[test]
+    fn raw_signature_is_der_encoded() {
+        let mut raw = vec![0u8; 64];
+        raw[31] = 1;
+        raw[63] = 2;
+
+        let der = raw_to_der(&raw).unwrap();
+
+        assert_eq!(der, vec![0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn raw_signature_with_odd_length_is_rejected() {
+        assert!(raw_to_der(&[0u8; 63]).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn generated_key_signs_on_token() {
+        let operations = softhsm_operations();
+        let application_key = operations
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+        let data = b"data to sign";
+
+        let signature = operations.sign(application_key.key(), data).unwrap();
+
+        assert!(application_key.key().ec_key().is_none());
+        assert!(verify(application_key.key(), signature.as_ref().as_ref(), data));
+    }
+
+    #[test]
+    #[ignore]
+    fn sign_with_missing_key_fails() {
+        let operations = softhsm_operations();
+        let generated = operations
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+        let missing = PrivateKey(KeyMaterial::Pkcs11(Pkcs11KeyRef {
+            id: vec![0u8; KEY_ID_LEN],
+            public_key: PublicKey::from_key(generated.key()).to_raw(),
+        }));
+
+        assert_matches!(
+            operations.sign(&missing, b"data to sign"),
+            Err(SignError::Backend(_))
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn destroyed_key_cannot_sign() {
+        let operations = softhsm_operations();
+        let application_key = operations
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+
+        operations.destroy_key(application_key.key()).unwrap();
+
+        assert_matches!(
+            operations.sign(application_key.key(), b"data to sign"),
+            Err(SignError::Backend(_))
+        );
+        // Destroying a key that is already gone is not an error
+        operations.destroy_key(application_key.key()).unwrap();
+    }
+}
@@ -2,21 +2,98 @@ use std::fmt::{self, Debug};
 use std::result::Result;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use openssl::ec::EcKey;
-use openssl::pkey::Private;
+use openssl::pkey::{PKey, Private};
 
+use algorithm::Algorithm;
 use serde_base64::{to_base64, from_base64};
 
-pub struct PrivateKey(pub(crate) EcKey<Private>);
+/// Reference to a private key, owned by the crypto backend that generated it.
+/// Backends only see the form of key they understand, so keys that cannot be
+/// exported never have to be.
+pub struct PrivateKey(pub(crate) KeyMaterial);
+
+pub(crate) enum KeyMaterial {
+    /// Key held in memory by OpenSSL
+    EcKey(EcKey<Private>),
+    /// PKCS#8 document of a key generated by ring, which exports keys in no other form
+    Pkcs8(Vec<u8>),
+    /// Reference to a key that never leaves its PKCS#11 token
+    Pkcs11(Pkcs11KeyRef),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Pkcs11KeyRef {
+    /// CKA_ID of the private key object on the token
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub(crate) id: Vec<u8>,
+    /// Raw uncompressed public key point, as the token will not export the private key to derive it
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub(crate) public_key: Vec<u8>,
+}
 
 impl PrivateKey {
     pub fn from_pem(pem: &str) -> PrivateKey {
-        PrivateKey(EcKey::private_key_from_pem(pem.as_bytes()).unwrap())
+        PrivateKey::from_ec_key(EcKey::private_key_from_pem(pem.as_bytes()).unwrap())
+    }
+
+    pub(crate) fn from_ec_key(key: EcKey<Private>) -> PrivateKey {
+        PrivateKey(KeyMaterial::EcKey(key))
+    }
+
+    /// Keys that are not elliptic curve keys are kept as PKCS#8 documents
+    pub(crate) fn from_pkey(pkey: &PKey<Private>) -> Option<PrivateKey> {
+        pkey.private_key_to_pem_pkcs8()
+            .ok()
+            .and_then(|pem| pem_to_der(&pem))
+            .map(|document| PrivateKey(KeyMaterial::Pkcs8(document)))
+    }
+
+    /// The algorithm the key signs with, None when the key is not understood
+    pub(crate) fn algorithm(&self) -> Option<Algorithm> {
+        match self.0 {
+            // Tokens are only asked for P-256 keys
+            KeyMaterial::Pkcs11(_) => Some(Algorithm::Es256),
+            _ => self.to_pkey().and_then(|pkey| Algorithm::of_pkey(&pkey)),
+        }
+    }
+
+    /// The key when it is held in memory by OpenSSL, None for other backends
+    pub(crate) fn ec_key(&self) -> Option<&EcKey<Private>> {
+        match self.0 {
+            KeyMaterial::EcKey(ref key) => Some(key),
+            KeyMaterial::Pkcs8(_) | KeyMaterial::Pkcs11(_) => None,
+        }
+    }
+
+    /// The key in a form OpenSSL can sign with, None for keys held by a token
+    pub(crate) fn to_pkey(&self) -> Option<PKey<Private>> {
+        match self.0 {
+            KeyMaterial::EcKey(ref key) => PKey::from_ec_key(key.to_owned()).ok(),
+            KeyMaterial::Pkcs8(ref document) => PKey::private_key_from_der(document).ok(),
+            KeyMaterial::Pkcs11(_) => None,
+        }
+    }
+
+    /// The key as a PKCS#8 document, None for keys held by a token
+    pub(crate) fn to_pkcs8(&self) -> Option<Vec<u8>> {
+        match self.0 {
+            KeyMaterial::EcKey(ref key) => PKey::from_ec_key(key.to_owned())
+                .and_then(|pkey| pkey.private_key_to_pem_pkcs8())
+                .ok()
+                .and_then(|pem| pem_to_der(&pem)),
+            KeyMaterial::Pkcs8(ref document) => Some(document.clone()),
+            KeyMaterial::Pkcs11(_) => None,
+        }
     }
 }
 
 impl Clone for PrivateKey {
     fn clone(&self) -> PrivateKey {
-        PrivateKey(self.0.to_owned())
+        match self.0 {
+            KeyMaterial::EcKey(ref key) => PrivateKey::from_ec_key(key.to_owned()),
+            KeyMaterial::Pkcs8(ref document) => PrivateKey(KeyMaterial::Pkcs8(document.clone())),
+            KeyMaterial::Pkcs11(ref key_ref) => PrivateKey(KeyMaterial::Pkcs11(key_ref.clone())),
+        }
     }
 }
 
@@ -31,7 +108,18 @@ impl Serialize for PrivateKey {
     where
         S: Serializer,
     {
-        PrivateKeyAsPEM::from_key(self).serialize(serializer)
+        match self.0 {
+            KeyMaterial::EcKey(_) => {
+                StoredPrivateKey::Pem(PrivateKeyAsPEM::from_key(self)).serialize(serializer)
+            }
+            KeyMaterial::Pkcs8(ref document) => StoredPrivateKey::Pkcs8 {
+                pkcs8: document.clone(),
+            }
+            .serialize(serializer),
+            KeyMaterial::Pkcs11(ref key_ref) => {
+                StoredPrivateKey::Pkcs11(key_ref.clone()).serialize(serializer)
+            }
+        }
     }
 }
 
@@ -40,19 +128,45 @@ impl<'de> Deserialize<'de> for PrivateKey {
     where
         D: Deserializer<'de>,
     {
-        Ok(PrivateKeyAsPEM::deserialize(deserializer)?.as_key())
+        Ok(match StoredPrivateKey::deserialize(deserializer)? {
+            StoredPrivateKey::Pem(pem) => pem.as_key(),
+            StoredPrivateKey::Pkcs8 { pkcs8 } => PrivateKey(KeyMaterial::Pkcs8(pkcs8)),
+            StoredPrivateKey::Pkcs11(key_ref) => PrivateKey(KeyMaterial::Pkcs11(key_ref)),
+        })
     }
 }
 
+/// Keys stored before PKCS#11 support are a bare PEM string, so that stays untagged
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPrivateKey {
+    Pem(PrivateKeyAsPEM),
+    Pkcs8 {
+        #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+        pkcs8: Vec<u8>,
+    },
+    Pkcs11(Pkcs11KeyRef),
+}
+
+/// Body of a single block PEM document
+fn pem_to_der(pem: &[u8]) -> Option<Vec<u8>> {
+    let pem = String::from_utf8_lossy(pem);
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::decode(&body).ok()
+}
+
 struct PrivateKeyAsPEM(Vec<u8>);
 
 impl PrivateKeyAsPEM {
     fn as_key(&self) -> PrivateKey {
-        PrivateKey(EcKey::private_key_from_pem(&self.0).unwrap())
+        PrivateKey::from_ec_key(EcKey::private_key_from_pem(&self.0).unwrap())
     }
 
     fn from_key(key: &PrivateKey) -> PrivateKeyAsPEM {
-        PrivateKeyAsPEM(key.0.private_key_to_pem().unwrap())
+        PrivateKeyAsPEM(key.ec_key().unwrap().private_key_to_pem().unwrap())
     }
 }
 
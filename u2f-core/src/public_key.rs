@@ -1,18 +1,28 @@
 use openssl::bn::BigNumContext;
 use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
 use openssl::nid::Nid;
-use openssl::pkey::Public;
+use openssl::pkey::{Id, PKey, Public};
 use std::result::Result;
 
 use constants::EC_POINT_FORMAT_UNCOMPRESSED;
-use private_key::PrivateKey;
+use private_key::{KeyMaterial, PrivateKey};
 
-pub struct PublicKey(EcKey<Public>);
+/// DER encoding of an Ed25519 SubjectPublicKeyInfo ends with the raw 32 byte key
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+pub struct PublicKey(PKey<Public>);
 
 impl PublicKey {
     pub(crate) fn from_key(key: &PrivateKey) -> PublicKey {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        PublicKey(EcKey::from_public_key(&group, &key.0.public_key()).unwrap())
+        match key.0 {
+            // Checked to be a valid point when the key was generated
+            KeyMaterial::Pkcs11(ref key_ref) => PublicKey::from_bytes(&key_ref.public_key).unwrap(),
+            KeyMaterial::EcKey(_) | KeyMaterial::Pkcs8(_) => {
+                // Dropping the private half through DER works for every key type
+                let der = key.to_pkey().unwrap().public_key_to_der().unwrap();
+                PublicKey(PKey::public_key_from_der(&der).unwrap())
+            }
+        }
     }
 
     /// Raw ANSI X9.62 formatted Elliptic Curve public key [SEC1].
@@ -30,20 +40,46 @@ impl PublicKey {
             return Err(String::from("Expected uncompressed point"));
         }
         let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let point = EcPoint::from_bytes(&group, bytes, &mut ctx).unwrap();
-        Ok(PublicKey(EcKey::from_public_key(&group, &point).unwrap()))
+        let point = EcPoint::from_bytes(&group, bytes, &mut ctx)
+            .map_err(|_| String::from("Expected a point on the P-256 curve"))?;
+        let ec_key = EcKey::from_public_key(&group, &point).unwrap();
+        Ok(PublicKey(PKey::from_ec_key(ec_key).unwrap()))
     }
 
-    pub(crate) fn as_ec_key(&self) -> &EcKey<Public> {
+    pub(crate) fn as_pkey(&self) -> &PKey<Public> {
         &self.0
     }
 
+    /// Panics for keys that are not elliptic curve keys
+    pub(crate) fn as_ec_key(&self) -> EcKey<Public> {
+        self.0.ec_key().unwrap()
+    }
+
     /// Raw ANSI X9.62 formatted Elliptic Curve public key [SEC1].
     /// I.e. [0x04, X (32 bytes), Y (32 bytes)] . Where the byte 0x04 denotes the
     /// uncompressed point compression method.
     pub(crate) fn to_raw(&self) -> Vec<u8> {
         let mut ctx = BigNumContext::new().unwrap();
         let form = PointConversionForm::UNCOMPRESSED;
-        self.0.public_key().to_bytes(self.0.group(), form, &mut ctx).unwrap()
+        let ec_key = self.as_ec_key();
+        ec_key
+            .public_key()
+            .to_bytes(ec_key.group(), form, &mut ctx)
+            .unwrap()
+    }
+
+    /// Raw 32 byte Ed25519 public key [RFC8032], None for other key types
+    pub(crate) fn to_ed25519(&self) -> Option<Vec<u8>> {
+        if self.0.id() != Id::ED25519 {
+            return None;
+        }
+        let der = self.0.public_key_to_der().ok()?;
+        Some(der[der.len() - ED25519_PUBLIC_KEY_LEN..].to_vec())
+    }
+
+    /// Big-endian RSA modulus and public exponent, None for other key types
+    pub(crate) fn to_rsa_components(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let rsa = self.0.rsa().ok()?;
+        Some((rsa.n().to_vec(), rsa.e().to_vec()))
     }
 }
@@ -1,13 +1,46 @@
-use std::io::Cursor;
-use std::io::Read;
 use std::result::Result;
 
 use app_id::AppId;
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder};
 use constants::*;
+use ctap2::Ctap2Request;
 use key_handle::KeyHandle;
 
 use super::Challenge;
+use super::StatusCode;
+
+quick_error! {
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum RequestError {
+        LengthInvalid {
+            description("Request length is invalid")
+        }
+        ClassNotSupported(class: u8) {
+            description("Request class is not supported")
+            display("Request class {:#04x} is not supported", class)
+        }
+        InstructionNotSupported(instruction: u8) {
+            description("Request instruction is not supported")
+            display("Request instruction {:#04x} is not supported", instruction)
+        }
+        ParametersInvalid(parameter1: u8, parameter2: u8) {
+            description("Request parameters are invalid")
+            display("Request parameters P1={:#04x} P2={:#04x} are invalid", parameter1, parameter2)
+        }
+    }
+}
+
+impl RequestError {
+    /// Status word to answer the request with
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RequestError::LengthInvalid => StatusCode::RequestLengthInvalid,
+            RequestError::ClassNotSupported(_) => StatusCode::RequestClassNotSupported,
+            RequestError::InstructionNotSupported(_) => StatusCode::RequestInstructionNotSuppored,
+            RequestError::ParametersInvalid(_, _) => StatusCode::RequestParametersInvalid,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum AuthenticateControlCode {
@@ -28,136 +61,346 @@ pub enum Request {
         control_code: AuthenticateControlCode,
         key_handle: KeyHandle,
     },
+    Ctap2(Ctap2Request),
     GetVersion,
     Wink,
 }
 
 impl Request {
-    /// Only supports Extended Length Encoding
-    pub fn decode(data: &[u8]) -> Result<Request, ()> {
-        let mut reader = Cursor::new(data);
-
-        // CLA: Reserved to be used by the underlying transport protocol
-        let _class_byte = reader.read_u8().unwrap();
-        // TODO check or error with RequestClassNotSupported
-
-        // INS: U2F command code
-        let command_code = reader.read_u8().unwrap();
-        // TODO check or error with RequestInstructionNotSuppored
-
-        // P1, P2: Parameter 1 and 2, defined by each command.
-        let parameter1 = reader.read_u8().unwrap();
-        let parameter2 = reader.read_u8().unwrap();
-
-        // Extended Length Encoding
-        // Always begins with a byte of value 0
-        let zero_byte = reader.read_u8().unwrap();
-        assert_eq!(zero_byte, 0);
-
-        // Nc: Length of the request-data, range 0..65 535
-        // Lc: Encoding of Nc as two bytes
-        // If Nc is 0, Lc is omitted (Caveat: Not all implementations respect this)
-        let remaining_len = data.len() - reader.position() as usize;
-        let request_data_len = match remaining_len {
-            2 => {
-                // Lc was omitted, there is no request data
-                0
-            }
-            _ => {
-                // Lc in big-endian order
-                reader.read_u16::<BigEndian>().unwrap() as usize
-            }
-        };
+    /// Decode a raw U2F message, supports both short and extended length encoding
+    pub fn decode(data: &[u8]) -> Result<Request, RequestError> {
+        let apdu = Apdu::decode(data)?;
 
-        // Request-data
-        let mut request_data = vec![0u8; request_data_len];
-        reader.read_exact(&mut request_data[..]).unwrap();
-
-        // Ne: Maximum length of the response data, range 0..65 536
-        // Le: Encoding of Ne as two bytes
-        // If no response data are expected, Le may be omitted.
-        let remaining_len = data.len() - reader.position() as usize;
-        let _max_response_data_len = match remaining_len {
-            0 => {
-                // Lc was omitted, instruction is not expected to yield any response bytes
-                0
-            }
-            2 => {
-                // Encoded as: Le1 Le2
-                let mut value = reader.read_u16::<BigEndian>().unwrap() as usize;
-                // When Ne = 65 536, let Le1 = 0 and Le2 = 0.
-                if value == 0 {
-                    // The MSB is lost when encoding to two bytes, but
-                    // since Le can be omitted when there are no request data
-                    // bytes, we can unambigously assume 0 to mean 65 535
-                    value = 65535;
-                }
-                value
-            }
-            _ => return Err(()),
-        };
+        // CLA: Reserved to be used by the underlying transport protocol, always zero
+        if apdu.class != 0 {
+            return Err(RequestError::ClassNotSupported(apdu.class));
+        }
 
-        // TODO If the instruction is not expected to yield any response bytes, L e may be omitted. O
-        let mut reader = Cursor::new(request_data);
-        let request = match command_code {
+        let request_data = &apdu.data[..];
+        match apdu.instruction {
             REGISTER_COMMAND_CODE => {
-                // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
-
-                // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
-
-                assert_eq!(reader.position() as usize, request_data_len);
-                Request::Register {
-                    application: AppId(application_parameter),
-                    challenge: Challenge(challenge_parameter),
+                // The challenge parameter [32 bytes] and the application parameter [32 bytes].
+                if request_data.len() != 64 {
+                    return Err(RequestError::LengthInvalid);
                 }
+                let (challenge_parameter, application_parameter) = request_data.split_at(32);
+                Ok(Request::Register {
+                    application: AppId::from_bytes(application_parameter),
+                    challenge: Challenge::from_bytes(challenge_parameter),
+                })
             }
             AUTHENTICATE_COMMAND_CODE => {
-                assert_eq!(parameter2, 0);
+                if apdu.parameter2 != 0 {
+                    return Err(RequestError::ParametersInvalid(
+                        apdu.parameter1,
+                        apdu.parameter2,
+                    ));
+                }
 
                 // Control byte (P1).
-                let control_code = match parameter1 {
+                let control_code = match apdu.parameter1 {
                     AUTH_CHECK_ONLY => AuthenticateControlCode::CheckOnly,
                     AUTH_ENFORCE => AuthenticateControlCode::EnforceUserPresenceAndSign,
-                    AUTH_DONT_ENFORCE => {
-                        AuthenticateControlCode::DontEnforceUserPresenceAndSign
+                    AUTH_DONT_ENFORCE => AuthenticateControlCode::DontEnforceUserPresenceAndSign,
+                    _ => {
+                        return Err(RequestError::ParametersInvalid(
+                            apdu.parameter1,
+                            apdu.parameter2,
+                        ))
                     }
-                    _ => panic!("Unknown control code"),
                 };
 
-                // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
-
-                // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
-
-                // key handle length byte [1 byte]
-                let key_handle_len = reader.read_u8().unwrap();
+                // The challenge parameter [32 bytes], the application parameter [32 bytes]
+                // and the key handle length byte [1 byte].
+                if request_data.len() < 65 {
+                    return Err(RequestError::LengthInvalid);
+                }
 
                 // key handle [length specified in previous field]
-                let mut key_handle_bytes = vec![0u8; key_handle_len as usize];
-                reader.read_exact(&mut key_handle_bytes[..]).unwrap();
+                let key_handle_len = request_data[64] as usize;
+                if request_data.len() != 65 + key_handle_len {
+                    return Err(RequestError::LengthInvalid);
+                }
 
-                Request::Authenticate {
-                    application: AppId(application_parameter),
-                    challenge: Challenge(challenge_parameter),
+                Ok(Request::Authenticate {
+                    application: AppId::from_bytes(&request_data[32..64]),
+                    challenge: Challenge::from_bytes(&request_data[0..32]),
                     control_code,
-                    key_handle: KeyHandle::from(&key_handle_bytes),
-                }
+                    key_handle: KeyHandle::from(&request_data[65..]),
+                })
             }
             VERSION_COMMAND_CODE => {
-                assert_eq!(parameter1, 0);
-                assert_eq!(parameter2, 0);
-                assert_eq!(request_data_len, 0);
-                Request::GetVersion
+                if apdu.parameter1 != 0 || apdu.parameter2 != 0 {
+                    return Err(RequestError::ParametersInvalid(
+                        apdu.parameter1,
+                        apdu.parameter2,
+                    ));
+                }
+                if !request_data.is_empty() {
+                    return Err(RequestError::LengthInvalid);
+                }
+                Ok(Request::GetVersion)
+            }
+            instruction => Err(RequestError::InstructionNotSupported(instruction)),
+        }
+    }
+}
+
+/// Command APDU as defined in ISO/IEC 7816-4
+#[derive(Debug)]
+struct Apdu {
+    class: u8,
+    instruction: u8,
+    parameter1: u8,
+    parameter2: u8,
+    data: Vec<u8>,
+    /// Ne: Maximum length of the response data, zero if no response data is expected
+    #[allow(dead_code)]
+    max_response_len: usize,
+}
+
+impl Apdu {
+    fn decode(data: &[u8]) -> Result<Apdu, RequestError> {
+        // CLA, INS, P1, P2 [1 byte each]
+        if data.len() < 4 {
+            return Err(RequestError::LengthInvalid);
+        }
+        let (header, body) = data.split_at(4);
+
+        // Nc: Length of the request-data, encoded as Lc
+        // Ne: Maximum length of the response data, encoded as Le
+        let (request_data, max_response_len) = match body.len() {
+            // Case 1: No request data, no response data
+            0 => (&body[0..0], 0),
+
+            // Case 2S: Short Le, a value of 0 means 256
+            1 => (&body[0..0], short_le(body[0])),
+
+            // Extended length encoding always begins with a byte of value 0
+            _ if body[0] == 0 => {
+                let body = &body[1..];
+                if body.len() < 2 {
+                    return Err(RequestError::LengthInvalid);
+                }
+                match body.len() {
+                    // Case 2E: Extended Le
+                    2 => (&body[0..0], extended_le(&body[0..2])),
+                    _ => {
+                        // Case 3E and 4E: Lc in big-endian order, optionally followed by Le.
+                        // If Nc is 0, Lc should be omitted, but not all implementations respect this.
+                        let request_data_len = BigEndian::read_u16(&body[0..2]) as usize;
+                        let body = &body[2..];
+                        if body.len() == request_data_len {
+                            (body, 0)
+                        } else if body.len() == request_data_len + 2 {
+                            let (request_data, le) = body.split_at(request_data_len);
+                            (request_data, extended_le(le))
+                        } else {
+                            return Err(RequestError::LengthInvalid);
+                        }
+                    }
+                }
+            }
+
+            // Case 3S and 4S: Short Lc, optionally followed by short Le
+            _ => {
+                let request_data_len = body[0] as usize;
+                let body = &body[1..];
+                if body.len() == request_data_len {
+                    (body, 0)
+                } else if body.len() == request_data_len + 1 {
+                    let (request_data, le) = body.split_at(request_data_len);
+                    (request_data, short_le(le[0]))
+                } else {
+                    return Err(RequestError::LengthInvalid);
+                }
             }
-            _ => panic!("Not implemented"),
         };
-        Ok(request)
+
+        Ok(Apdu {
+            class: header[0],
+            instruction: header[1],
+            parameter1: header[2],
+            parameter2: header[3],
+            data: request_data.to_vec(),
+            max_response_len,
+        })
+    }
+}
+
+fn short_le(le: u8) -> usize {
+    // When Ne = 256, Le = 0
+    match le {
+        0 => 256,
+        le => le as usize,
+    }
+}
+
+fn extended_le(le: &[u8]) -> usize {
+    // When Ne = 65 536, Le1 = 0 and Le2 = 0
+    match BigEndian::read_u16(le) {
+        0 => 65536,
+        le => le as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register_data() -> Vec<u8> {
+        let mut data = vec![0x11u8; 32];
+        data.extend_from_slice(&[0x22u8; 32]);
+        data
+    }
+
+    #[test]
+    fn decode_extended_length_register() {
+        let mut apdu = vec![0x00, REGISTER_COMMAND_CODE, 0x00, 0x00, 0x00, 0x00, 64];
+        apdu.extend_from_slice(&register_data());
+        apdu.extend_from_slice(&[0x00, 0x00]);
+
+        assert_matches!(Request::decode(&apdu), Ok(Request::Register { .. }));
+    }
+
+    #[test]
+    fn decode_short_length_register() {
+        let mut apdu = vec![0x00, REGISTER_COMMAND_CODE, 0x00, 0x00, 64];
+        apdu.extend_from_slice(&register_data());
+        apdu.push(0x00);
+
+        match Request::decode(&apdu) {
+            Ok(Request::Register {
+                application,
+                challenge,
+            }) => {
+                assert_eq!(application.as_ref(), &[0x22u8; 32][..]);
+                assert_eq!(challenge.as_ref(), &[0x11u8; 32][..]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_version_without_data() {
+        assert_matches!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x00]),
+            Ok(Request::GetVersion)
+        );
+        assert_matches!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            Ok(Request::GetVersion)
+        );
+    }
+
+    #[test]
+    fn decode_authenticate_with_key_handle() {
+        let mut data = register_data();
+        data.push(3);
+        data.extend_from_slice(&[1, 2, 3]);
+        let mut apdu = vec![
+            0x00,
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_ENFORCE,
+            0x00,
+            data.len() as u8,
+        ];
+        apdu.extend_from_slice(&data);
+
+        match Request::decode(&apdu) {
+            Ok(Request::Authenticate {
+                control_code: AuthenticateControlCode::EnforceUserPresenceAndSign,
+                key_handle,
+                ..
+            }) => assert_eq!(key_handle.as_ref(), &[1, 2, 3]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_authenticate_with_truncated_key_handle_is_length_invalid() {
+        let mut data = register_data();
+        data.push(3);
+        data.extend_from_slice(&[1, 2]);
+        let mut apdu = vec![
+            0x00,
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_ENFORCE,
+            0x00,
+            data.len() as u8,
+        ];
+        apdu.extend_from_slice(&data);
+
+        assert_eq!(
+            Request::decode(&apdu).unwrap_err(),
+            RequestError::LengthInvalid
+        );
+    }
+
+    #[test]
+    fn decode_authenticate_with_unknown_control_code_is_parameters_invalid() {
+        let mut data = register_data();
+        data.push(0);
+        let mut apdu = vec![
+            0x00,
+            AUTHENTICATE_COMMAND_CODE,
+            0x42,
+            0x00,
+            data.len() as u8,
+        ];
+        apdu.extend_from_slice(&data);
+
+        assert_eq!(
+            Request::decode(&apdu).unwrap_err(),
+            RequestError::ParametersInvalid(0x42, 0x00)
+        );
+    }
+
+    #[test]
+    fn decode_short_header_is_length_invalid() {
+        assert_eq!(
+            Request::decode(&[]).unwrap_err(),
+            RequestError::LengthInvalid
+        );
+        assert_eq!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE]).unwrap_err(),
+            RequestError::LengthInvalid
+        );
+    }
+
+    #[test]
+    fn decode_with_wrong_lc_is_length_invalid() {
+        let apdu = [
+            0x00,
+            REGISTER_COMMAND_CODE,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            64,
+            0x01,
+        ];
+        assert_eq!(
+            Request::decode(&apdu).unwrap_err(),
+            RequestError::LengthInvalid
+        );
+    }
+
+    #[test]
+    fn decode_with_nonzero_class_is_class_not_supported() {
+        assert_eq!(
+            Request::decode(&[0x80, VERSION_COMMAND_CODE, 0x00, 0x00]).unwrap_err(),
+            RequestError::ClassNotSupported(0x80)
+        );
+    }
+
+    #[test]
+    fn decode_unknown_instruction_is_instruction_not_supported() {
+        let error = Request::decode(&[0x00, 0x42, 0x00, 0x00]).unwrap_err();
+        assert_eq!(error, RequestError::InstructionNotSupported(0x42));
+        assert_matches!(
+            error.status_code(),
+            StatusCode::RequestInstructionNotSuppored
+        );
     }
 }
@@ -0,0 +1,19 @@
+use application_key::ApplicationKey;
+use serde_base64::{from_base64, to_base64};
+
+/// User account a resident credential was created for, as given by the relying party
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct User {
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub id: Vec<u8>,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+}
+
+/// Discoverable credential, the authenticator can find these
+/// for a relying party without being sent a key handle.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ResidentCredential {
+    pub application_key: ApplicationKey,
+    pub user: User,
+}
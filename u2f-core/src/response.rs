@@ -2,6 +2,7 @@ use std::io;
 
 use attestation::AttestationCertificate;
 use byteorder::{BigEndian, WriteBytesExt};
+use ctap2::Ctap2Response;
 use key_handle::KeyHandle;
 
 use super::Counter;
@@ -22,6 +23,7 @@ pub enum Response {
         signature: Box<dyn Signature>,
         user_present: bool,
     },
+    Ctap2(Ctap2Response),
     Version {
         version_string: String,
     },
@@ -83,6 +85,10 @@ impl Response {
                 // Status word [2 bytes]
                 StatusCode::NoError.write(&mut bytes);
             }
+            Response::Ctap2(response) => {
+                // CTAP2 responses carry their own status byte instead of a status word
+                bytes.extend_from_slice(&response.into_bytes());
+            }
             Response::Version { version_string } => {
                 // The response message's raw representation is the
                 // ASCII representation of the string 'U2F_V2'
@@ -0,0 +1,219 @@
+use std::io;
+
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, EcdsaSigningAlgorithm, Ed25519KeyPair, RsaKeyPair,
+    ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P384_SHA384_ASN1_SIGNING, RSA_PKCS1_SHA256,
+};
+
+use algorithm::Algorithm;
+use app_id::AppId;
+use application_key::ApplicationKey;
+use attestation::{Attestation, AttestationCertificate};
+use key_handle::KeyHandle;
+use openssl_crypto::RawSignature;
+use private_key::{KeyMaterial, PrivateKey};
+
+use super::CryptoOperations;
+use super::SignError;
+use super::Signature;
+
+/// Generates and signs with keys using ring instead of OpenSSL. Keys are kept
+/// as PKCS#8 documents, keys generated by OpenSSL can be signed with as well.
+/// ring cannot generate RSA keys, so RS256 credentials are not offered.
+pub struct RingCryptoOperations {
+    attestation: Attestation,
+    rng: SystemRandom,
+}
+
+impl RingCryptoOperations {
+    pub fn new(attestation: Attestation) -> RingCryptoOperations {
+        RingCryptoOperations {
+            attestation,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn generate_key(&self, algorithm: Algorithm) -> io::Result<PrivateKey> {
+        let document = match algorithm {
+            Algorithm::Es256 | Algorithm::Es384 => {
+                EcdsaKeyPair::generate_pkcs8(ecdsa_algorithm(algorithm), &self.rng)
+            }
+            Algorithm::EdDsa => Ed25519KeyPair::generate_pkcs8(&self.rng),
+            Algorithm::Rs256 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ring cannot generate RSA keys",
+                ))
+            }
+        }
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(PrivateKey(KeyMaterial::Pkcs8(document.as_ref().to_vec())))
+    }
+
+    fn sign_pkcs8(
+        &self,
+        algorithm: Algorithm,
+        document: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>, SignError> {
+        match algorithm {
+            Algorithm::Es256 | Algorithm::Es384 => {
+                let key_pair = EcdsaKeyPair::from_pkcs8(ecdsa_algorithm(algorithm), document)
+                    .map_err(|err| SignError::Backend(err.to_string()))?;
+                key_pair
+                    .sign(&self.rng, data)
+                    .map(|signature| signature.as_ref().to_vec())
+                    .map_err(|err| SignError::Backend(err.to_string()))
+            }
+            Algorithm::EdDsa => {
+                // Keys generated by OpenSSL leave out the public key, which ring checks when present
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(document)
+                    .map_err(|err| SignError::Backend(err.to_string()))?;
+                Ok(key_pair.sign(data).as_ref().to_vec())
+            }
+            Algorithm::Rs256 => {
+                let key_pair = RsaKeyPair::from_pkcs8(document)
+                    .map_err(|err| SignError::Backend(err.to_string()))?;
+                let mut signature = vec![0u8; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(&RSA_PKCS1_SHA256, &self.rng, data, &mut signature)
+                    .map_err(|err| SignError::Backend(err.to_string()))?;
+                Ok(signature)
+            }
+        }
+    }
+}
+
+impl CryptoOperations for RingCryptoOperations {
+    fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.sign(&self.attestation.key, data)
+    }
+
+    fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
+        let key = self.generate_key(Algorithm::Es256)?;
+        let handle: KeyHandle = rand::random();
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+
+    fn supports_algorithm(&self, algorithm: Algorithm) -> bool {
+        algorithm != Algorithm::Rs256
+    }
+
+    fn generate_credential_key(
+        &self,
+        application: &AppId,
+        algorithm: Algorithm,
+    ) -> io::Result<ApplicationKey> {
+        let key = self.generate_key(algorithm)?;
+        let handle: KeyHandle = rand::random();
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+
+    fn unwrap_application_key(
+        &self,
+        _application: &AppId,
+        _handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        // Key handles are random, keys are kept by the secret store
+        Ok(None)
+    }
+
+    fn get_attestation_certificate(&self) -> AttestationCertificate {
+        self.attestation.certificate.clone()
+    }
+
+    fn get_attestation_chain(&self) -> Vec<AttestationCertificate> {
+        self.attestation.chain.clone()
+    }
+
+    fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        let document = key.to_pkcs8().ok_or(SignError::UnsupportedKey)?;
+        let algorithm = key.algorithm().ok_or(SignError::UnsupportedKey)?;
+        let signature = self.sign_pkcs8(algorithm, &document, data)?;
+        Ok(Box::new(RawSignature(signature)))
+    }
+}
+
+fn ecdsa_algorithm(algorithm: Algorithm) -> &'static EcdsaSigningAlgorithm {
+    match algorithm {
+        Algorithm::Es384 => &ECDSA_P384_SHA384_ASN1_SIGNING,
+        _ => &ECDSA_P256_SHA256_ASN1_SIGNING,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::hash::MessageDigest;
+    use openssl::sign::Verifier;
+
+    use super::*;
+    use openssl_crypto::OpenSSLCryptoOperations;
+    use public_key::PublicKey;
+    use self_signed_attestation::self_signed_attestation;
+
+    fn verify(key: &PrivateKey, signature: &dyn Signature, data: &[u8]) -> bool {
+        let public_key = PublicKey::from_key(key);
+        let mut verifier = Verifier::new(MessageDigest::sha256(), public_key.as_pkey()).unwrap();
+        verifier.update(data).unwrap();
+        verifier.verify(signature.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn generated_key_is_not_held_by_openssl() {
+        let operations = RingCryptoOperations::new(self_signed_attestation());
+
+        let application_key = operations
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+
+        assert!(application_key.key().ec_key().is_none());
+    }
+
+    #[test]
+    fn signs_keys_generated_by_openssl() {
+        let ring = RingCryptoOperations::new(self_signed_attestation());
+        let openssl = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application_key = openssl
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+        let data = b"data to sign";
+
+        let signature = ring.sign(application_key.key(), data).unwrap();
+
+        assert!(verify(application_key.key(), signature.as_ref(), data));
+    }
+
+    #[test]
+    fn openssl_signs_keys_generated_by_ring() {
+        let ring = RingCryptoOperations::new(self_signed_attestation());
+        let openssl = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application_key = ring
+            .generate_application_key(&AppId::from_bytes(&[0u8; 32]))
+            .unwrap();
+        let data = b"data to sign";
+
+        let signature = openssl.sign(application_key.key(), data).unwrap();
+
+        assert!(verify(application_key.key(), signature.as_ref(), data));
+    }
+
+    #[test]
+    fn signs_eddsa_keys_generated_by_openssl() {
+        let ring = RingCryptoOperations::new(self_signed_attestation());
+        let openssl = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application_key = openssl
+            .generate_credential_key(&AppId::from_bytes(&[0u8; 32]), Algorithm::EdDsa)
+            .unwrap();
+        let data = b"data to sign";
+
+        let signature = ring.sign(application_key.key(), data).unwrap();
+
+        let public_key = PublicKey::from_key(application_key.key());
+        let mut verifier = Verifier::new_without_digest(public_key.as_pkey()).unwrap();
+        assert_eq!(application_key.algorithm(), Algorithm::EdDsa);
+        assert!(verifier
+            .verify_oneshot(signature.as_ref().as_ref(), data)
+            .unwrap());
+    }
+}
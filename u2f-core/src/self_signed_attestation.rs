@@ -3,7 +3,9 @@ use private_key::PrivateKey;
 
 pub fn self_signed_attestation() -> Attestation {
     Attestation {
-        certificate: AttestationCertificate::from_pem(SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM),
+        certificate: AttestationCertificate::from_pem(SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM)
+            .unwrap(),
+        chain: Vec::new(),
         key: PrivateKey::from_pem(SELF_SIGNED_ATTESTATION_KEY_PEM),
     }
 }
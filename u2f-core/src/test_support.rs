@@ -0,0 +1,249 @@
+//! Fakes for tests of this crate and of crates built on it, which enable the
+//! `test-support` feature to use them
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::time::SystemTime;
+
+use futures::future;
+use futures::Future;
+
+use app_id::AppId;
+use application_key::{ApplicationKey, ApplicationKeyMetadata};
+use counter::CounterStrategy;
+use key_handle::KeyHandle;
+use resident_credential::ResidentCredential;
+
+use super::{Counter, SecretStore, UserPresence};
+
+/// Answers every request for user presence the same way, without asking anyone
+pub struct FakeUserPresence {
+    pub should_approve_authentication: bool,
+    pub should_approve_registration: bool,
+    pub should_allow_silent_authentication: bool,
+}
+
+impl FakeUserPresence {
+    pub fn always_approve() -> FakeUserPresence {
+        FakeUserPresence {
+            should_approve_authentication: true,
+            should_approve_registration: true,
+            should_allow_silent_authentication: false,
+        }
+    }
+
+    pub fn never_approve() -> FakeUserPresence {
+        FakeUserPresence {
+            should_approve_authentication: false,
+            should_approve_registration: false,
+            should_allow_silent_authentication: false,
+        }
+    }
+}
+
+impl UserPresence for FakeUserPresence {
+    fn approve_registration(&self, _: &AppId) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(self.should_approve_registration))
+    }
+    fn approve_authentication(&self, _: &AppId) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(self.should_approve_authentication))
+    }
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(self.should_approve_registration))
+    }
+    fn allow_silent_authentication(&self, _: &AppId) -> bool {
+        self.should_allow_silent_authentication
+    }
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+}
+
+/// Secret store that forgets everything when dropped
+pub struct InMemoryStorage(RefCell<InMemoryStorageInner>);
+
+struct InMemoryStorageInner {
+    application_keys: HashMap<AppId, ApplicationKey>,
+    counters: HashMap<AppId, Counter>,
+    device_counter: Counter,
+    counter_strategy: CounterStrategy,
+    metadata: HashMap<AppId, ApplicationKeyMetadata>,
+    resident_credentials: Vec<ResidentCredential>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> InMemoryStorage {
+        InMemoryStorage::with_counter_strategy(CounterStrategy::default())
+    }
+
+    pub fn with_counter_strategy(counter_strategy: CounterStrategy) -> InMemoryStorage {
+        InMemoryStorage(RefCell::new(InMemoryStorageInner {
+            application_keys: HashMap::new(),
+            counters: HashMap::new(),
+            device_counter: 0,
+            counter_strategy,
+            metadata: HashMap::new(),
+            resident_credentials: Vec::new(),
+        }))
+    }
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> InMemoryStorage {
+        InMemoryStorage::new()
+    }
+}
+
+impl SecretStore for InMemoryStorage {
+    fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
+        let mut borrow = self.0.borrow_mut();
+        borrow.application_keys.insert(key.application, key.clone());
+        borrow.metadata.insert(
+            key.application,
+            ApplicationKeyMetadata {
+                registered: Some(SystemTime::now()),
+                ..ApplicationKeyMetadata::default()
+            },
+        );
+        Ok(())
+    }
+
+    fn get_and_increment_counter(
+        &self,
+        application: &AppId,
+        _handle: &KeyHandle,
+    ) -> io::Result<Counter> {
+        let mut borrow = self.0.borrow_mut();
+        if let Some(metadata) = borrow.metadata.get_mut(application) {
+            metadata.last_used = Some(SystemTime::now());
+            metadata.use_count += 1;
+        }
+        let counter = borrow.counter_strategy.next(
+            borrow.counters.get(application).cloned(),
+            borrow.device_counter,
+            SystemTime::now(),
+        );
+        borrow.counters.insert(*application, counter);
+        borrow.device_counter = borrow.device_counter.max(counter);
+        Ok(counter)
+    }
+
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        let borrow = self.0.borrow();
+        if let Some(credential) = borrow.resident_credentials.iter().find(|credential| {
+            credential.application_key.application == *application
+                && credential.application_key.handle.eq_consttime(handle)
+        }) {
+            return Ok(Some(credential.application_key.clone()));
+        }
+        Ok(match borrow.application_keys.get(application) {
+            Some(key) => {
+                if key.handle.eq_consttime(handle) {
+                    Some(key.clone())
+                } else {
+                    None
+                }
+            }
+            None => None,
+        })
+    }
+
+    fn remove_all_application_keys(&self) -> io::Result<()> {
+        let mut borrow = self.0.borrow_mut();
+        borrow.application_keys.clear();
+        borrow.counters.clear();
+        borrow.metadata.clear();
+        borrow.resident_credentials.clear();
+        Ok(())
+    }
+
+    fn list_application_keys(&self) -> io::Result<Vec<ApplicationKey>> {
+        let borrow = self.0.borrow();
+        Ok(borrow
+            .application_keys
+            .values()
+            .cloned()
+            .chain(
+                borrow
+                    .resident_credentials
+                    .iter()
+                    .map(|credential| credential.application_key.clone()),
+            )
+            .collect())
+    }
+
+    fn remove_application_key(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        let mut borrow = self.0.borrow_mut();
+        let resident_count = borrow.resident_credentials.len();
+        borrow.resident_credentials.retain(|credential| {
+            credential.application_key.application != *application
+                || !credential.application_key.handle.eq_consttime(handle)
+        });
+        if borrow.resident_credentials.len() != resident_count {
+            return Ok(true);
+        }
+        let matches = match borrow.application_keys.get(application) {
+            Some(key) => key.handle.eq_consttime(handle),
+            None => false,
+        };
+        if matches {
+            borrow.application_keys.remove(application);
+            borrow.metadata.remove(application);
+        }
+        Ok(matches)
+    }
+
+    fn application_key_metadata(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKeyMetadata>> {
+        if self
+            .retrieve_application_key(application, handle)?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.0
+                .borrow()
+                .metadata
+                .get(application)
+                .cloned()
+                .unwrap_or_default(),
+        ))
+    }
+
+    fn counter_strategy(&self) -> CounterStrategy {
+        self.0.borrow().counter_strategy
+    }
+
+    fn add_resident_credential(&self, credential: &ResidentCredential) -> io::Result<()> {
+        let mut borrow = self.0.borrow_mut();
+        borrow.resident_credentials.retain(|existing| {
+            existing.application_key.application != credential.application_key.application
+                || existing.user.id != credential.user.id
+        });
+        borrow.resident_credentials.insert(0, credential.clone());
+        Ok(())
+    }
+
+    fn list_resident_credentials(
+        &self,
+        application: &AppId,
+    ) -> io::Result<Vec<ResidentCredential>> {
+        Ok(self
+            .0
+            .borrow()
+            .resident_credentials
+            .iter()
+            .filter(|credential| credential.application_key.application == *application)
+            .cloned()
+            .collect())
+    }
+}
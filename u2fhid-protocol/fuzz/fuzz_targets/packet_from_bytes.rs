@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate u2fhid_protocol;
+
+use u2fhid_protocol::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    // Any input must parse to a packet or an error, never panic
+    if let Ok(packet) = Packet::from_bytes(data) {
+        let _ = packet.into_bytes();
+    }
+});
@@ -0,0 +1,368 @@
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+use byteorder::{BigEndian, ByteOrder};
+use definitions::*;
+use futures::future::{self, Loop};
+use futures::stream;
+use futures::{Future, Sink, Stream};
+use rand;
+use u2f_core::Transport;
+
+/// Host side of U2FHID, carries U2F messages to a device over a transport
+/// of packets. Messages are sent on a channel allocated with INIT, one at a
+/// time.
+pub struct PacketTransport<T> {
+    channel_id: ChannelId,
+    transport: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> PacketTransport<T>
+where
+    T: Sink<SinkItem = Packet, SinkError = io::Error>
+        + Stream<Item = Packet, Error = io::Error>
+        + 'static,
+{
+    /// Allocate a channel on the device
+    pub fn init(transport: T) -> Box<dyn Future<Item = PacketTransport<T>, Error = io::Error>> {
+        let nonce: [u8; 8] = rand::random();
+        Box::new(
+            exchange(transport, BROADCAST_CHANNEL_ID, Command::Init, &nonce).and_then(
+                move |(transport, command, data)| match command {
+                    // Nonce [8 bytes], channel identifier [4 bytes], versions and capabilities
+                    Command::Init if data.len() >= 12 && data[0..8] == nonce[..] => {
+                        Ok(PacketTransport {
+                            channel_id: ChannelId(BigEndian::read_u32(&data[8..12])),
+                            transport: Rc::new(RefCell::new(Some(transport))),
+                        })
+                    }
+                    command => Err(unexpected_response(command, &data)),
+                },
+            ),
+        )
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+}
+
+impl<T> Transport for PacketTransport<T>
+where
+    T: Sink<SinkItem = Packet, SinkError = io::Error>
+        + Stream<Item = Packet, Error = io::Error>
+        + 'static,
+{
+    fn transmit(&self, request: Vec<u8>) -> Box<dyn Future<Item = Vec<u8>, Error = io::Error>> {
+        // The transport is lent to the exchange and returned when it completes
+        let transport = match self.transport.borrow_mut().take() {
+            Some(transport) => transport,
+            None => {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Transport is busy or failed",
+                )))
+            }
+        };
+        let slot = self.transport.clone();
+        Box::new(
+            exchange(transport, self.channel_id, Command::Msg, &request).and_then(
+                move |(transport, command, data)| {
+                    *slot.borrow_mut() = Some(transport);
+                    match command {
+                        Command::Msg => Ok(data),
+                        command => Err(unexpected_response(command, &data)),
+                    }
+                },
+            ),
+        )
+    }
+}
+
+/// Send a message and wait for the response on the same channel
+fn exchange<T>(
+    transport: T,
+    channel_id: ChannelId,
+    command: Command,
+    data: &[u8],
+) -> Box<dyn Future<Item = (T, Command, Vec<u8>), Error = io::Error>>
+where
+    T: Sink<SinkItem = Packet, SinkError = io::Error>
+        + Stream<Item = Packet, Error = io::Error>
+        + 'static,
+{
+    let packets = encode_message(channel_id, command, data);
+    Box::new(
+        stream::iter_ok(packets)
+            .fold(transport, |transport, packet| transport.send(packet))
+            .and_then(move |transport| {
+                future::loop_fn(
+                    (transport, MessageAssembler::new(channel_id)),
+                    |(transport, mut assembler)| {
+                        transport.into_future().map_err(|(err, _)| err).and_then(
+                            move |(packet, transport)| {
+                                let packet = packet.ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::UnexpectedEof,
+                                        "Transport closed before the response",
+                                    )
+                                })?;
+                                Ok(match assembler.accept(packet)? {
+                                    Some((command, data)) => {
+                                        Loop::Break((transport, command, data))
+                                    }
+                                    None => Loop::Continue((transport, assembler)),
+                                })
+                            },
+                        )
+                    },
+                )
+            }),
+    )
+}
+
+fn unexpected_response(command: Command, data: &[u8]) -> io::Error {
+    match command {
+        Command::Error => io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Device responded with error code {:#04x}",
+                data.get(0).cloned().unwrap_or(0)
+            ),
+        ),
+        command => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected {:?} response", command),
+        ),
+    }
+}
+
+/// Reassembles a message sent on one channel, packets of other channels are ignored
+pub struct MessageAssembler {
+    channel_id: ChannelId,
+    message: Option<(Command, usize)>,
+    buffer: Vec<u8>,
+    next_sequence_number: u8,
+}
+
+impl MessageAssembler {
+    pub fn new(channel_id: ChannelId) -> MessageAssembler {
+        MessageAssembler {
+            channel_id,
+            message: None,
+            buffer: Vec::new(),
+            next_sequence_number: 0,
+        }
+    }
+
+    pub fn accept(&mut self, packet: Packet) -> io::Result<Option<(Command, Vec<u8>)>> {
+        if packet.channel_id() != self.channel_id {
+            return Ok(None);
+        }
+        match packet {
+            Packet::Initialization {
+                command,
+                data,
+                payload_len,
+                ..
+            } => {
+                self.message = Some((command, payload_len));
+                self.buffer = data;
+                self.next_sequence_number = 0;
+            }
+            Packet::Continuation {
+                sequence_number,
+                data,
+                ..
+            } => {
+                if self.message.is_none() || sequence_number != self.next_sequence_number {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Continuation packet out of sequence",
+                    ));
+                }
+                self.next_sequence_number += 1;
+                self.buffer.extend_from_slice(&data);
+            }
+        }
+        match self.message {
+            Some((command, payload_len)) if self.buffer.len() >= payload_len => {
+                // Packets read from a device are padded to the report length
+                self.buffer.truncate(payload_len);
+                self.message = None;
+                Ok(Some((command, mem::replace(&mut self.buffer, Vec::new()))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use futures::{Async, AsyncSink, Poll, StartSend};
+    use slog::{self, Drain};
+    use slog_stdlog;
+    use tokio_core::reactor::Core;
+    use u2f_core::{AppId, Challenge, Client, ClientError, KeyHandle, Request, Response, Service};
+
+    use super::*;
+    use protocol_state_machine::StateMachine;
+
+    /// Answers version requests, any key handle is unknown
+    struct FakeU2FService;
+
+    impl Service for FakeU2FService {
+        type Request = Request;
+        type Response = Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            Box::new(future::ok(match req {
+                Request::GetVersion => Response::Version {
+                    version_string: String::from("U2F_V2"),
+                },
+                Request::Authenticate { .. } => Response::InvalidKeyHandle,
+                _ => Response::UnknownError,
+            }))
+        }
+    }
+
+    /// Device that answers packets as soon as they are sent
+    struct Loopback {
+        state_machine: StateMachine<FakeU2FService>,
+        packets: VecDeque<Packet>,
+    }
+
+    impl Sink for Loopback {
+        type SinkItem = Packet;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, packet: Packet) -> StartSend<Packet, io::Error> {
+            if let Some(response) = self.state_machine.accept_packet(packet)? {
+                self.packets.extend(response.into_packets());
+            }
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    impl Stream for Loopback {
+        type Item = Packet;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Packet>, io::Error> {
+            if let Some(response) = self.state_machine.step()? {
+                self.packets.extend(response.into_packets());
+            }
+            match self.packets.pop_front() {
+                Some(packet) => Ok(Async::Ready(Some(packet))),
+                // Fail instead of waiting forever for a response that will never come
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No response")),
+            }
+        }
+    }
+
+    fn loopback(core: &Core) -> Loopback {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        Loopback {
+            state_machine: StateMachine::new(FakeU2FService, core.handle(), logger),
+            packets: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn init_allocates_channel() {
+        let core = Core::new().unwrap();
+
+        let transport = PacketTransport::init(loopback(&core)).wait().unwrap();
+
+        assert_ne!(transport.channel_id(), BROADCAST_CHANNEL_ID);
+    }
+
+    #[test]
+    fn get_version() {
+        let core = Core::new().unwrap();
+        let transport = PacketTransport::init(loopback(&core)).wait().unwrap();
+        let client = Client::new(transport);
+
+        assert_eq!(client.get_version().wait().unwrap(), "U2F_V2");
+    }
+
+    #[test]
+    fn multiple_packet_request() {
+        let core = Core::new().unwrap();
+        let transport = PacketTransport::init(loopback(&core)).wait().unwrap();
+        let client = Client::new(transport);
+        let key_handle = KeyHandle::from(&[0u8; 128]);
+
+        let is_valid = client
+            .check_key_handle(&AppId::from_bytes(&[0u8; 32]), &key_handle)
+            .wait()
+            .unwrap();
+
+        assert!(!is_valid);
+    }
+
+    #[test]
+    fn unknown_error_is_status_error() {
+        let core = Core::new().unwrap();
+        let transport = PacketTransport::init(loopback(&core)).wait().unwrap();
+        let client = Client::new(transport);
+
+        let result = client
+            .register(
+                AppId::from_bytes(&[0u8; 32]),
+                Challenge::from_bytes(&[0u8; 32]),
+            )
+            .wait();
+
+        match result {
+            // SW_UNKNOWN
+            Err(ClientError::Status(0x6f00)) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn assembler_ignores_other_channels() {
+        let mut assembler = MessageAssembler::new(ChannelId(1));
+        let data = vec![0x42u8; 100];
+        let mut packets = encode_message(ChannelId(1), Command::Msg, &data);
+        let first = packets.pop_front().unwrap();
+
+        assert!(assembler.accept(first).unwrap().is_none());
+        for other in encode_message(ChannelId(2), Command::Msg, &[0u8; 100]) {
+            assert!(assembler.accept(other).unwrap().is_none());
+        }
+        let (command, message) = assembler
+            .accept(packets.pop_front().unwrap())
+            .unwrap()
+            .unwrap();
+
+        match command {
+            Command::Msg => assert_eq!(message, data),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn assembler_rejects_out_of_sequence_continuation() {
+        let mut assembler = MessageAssembler::new(ChannelId(1));
+
+        assert!(assembler
+            .accept(Packet::Continuation {
+                channel_id: ChannelId(1),
+                sequence_number: 0,
+                data: vec![0u8; 10],
+            })
+            .is_err());
+    }
+}
@@ -17,6 +17,8 @@ pub const U2FHID_PROTOCOL_VERSION: u8 = 2;
 const HID_REPORT_LEN: usize = 64;
 const INITIAL_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 7;
 const CONTINUATION_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 5;
+/// Sequence numbers of continuation packets run from 0 to 127
+pub const MAX_MESSAGE_LEN: usize = INITIAL_PACKET_DATA_LEN + 128 * CONTINUATION_PACKET_DATA_LEN;
 
 const FRAME_TYPE_INIT: u8 = 0b1000_0000;
 const FRAME_TYPE_CONT: u8 = 0b0000_0000;
@@ -27,6 +29,8 @@ const U2FHID_MSG: u8 = FRAME_TYPE_INIT | 0x03; // Send U2F message frame
 const U2FHID_LOCK: u8 = FRAME_TYPE_INIT | 0x04; // Send lock channel command
 const U2FHID_INIT: u8 = FRAME_TYPE_INIT | 0x06; // Channel initialization
 const U2FHID_WINK: u8 = FRAME_TYPE_INIT | 0x08; // Send device identification wink
+const CTAPHID_CBOR: u8 = FRAME_TYPE_INIT | 0x10; // Send encapsulated CTAP CBOR encoded message
+const CTAPHID_CANCEL: u8 = FRAME_TYPE_INIT | 0x11; // Cancel any outstanding requests on this channel
 const U2FHID_SYNC: u8 = FRAME_TYPE_INIT | 0x3c; // Protocol resync command
 const U2FHID_ERROR: u8 = FRAME_TYPE_INIT | 0x3f; // Error response
 
@@ -35,6 +39,7 @@ const U2FHID_VENDOR_LAST: u8 = FRAME_TYPE_INIT | 0x7f; // Last vendor defined co
 
 const COMMAND_INIT_DATA_LEN: usize = 8;
 const COMMAND_WINK_DATA_LEN: usize = 1;
+const COMMAND_SYNC_DATA_LEN: usize = 1;
 
 pub const BROADCAST_CHANNEL_ID: ChannelId = ChannelId(0xffff_ffff);
 
@@ -44,8 +49,17 @@ pub fn packet_timeout_duration() -> Duration {
 pub fn transaction_timeout_duration() -> Duration {
     Duration::from_millis(3000)
 }
+/// Requests wait for the user to confirm their presence, so they get far
+/// longer than receiving the message may take
+pub fn dispatch_timeout_duration() -> Duration {
+    Duration::from_secs(30)
+}
+/// Longest a channel may lock the device for with a single lock command
+pub fn max_lock_duration() -> Duration {
+    Duration::from_secs(10)
+}
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChannelId(pub u32);
 
 impl ChannelId {
@@ -72,6 +86,7 @@ impl slog::Value for ChannelId {
 bitflags! {
     pub struct CapabilityFlags: u8 {
         const CAPFLAG_WINK = 0b0000_0001;
+        const CAPFLAG_CBOR = 0b0000_0100;
     }
 }
 
@@ -108,15 +123,17 @@ impl ErrorCode {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum Command {
     Msg,
+    Cbor,
     Ping,
     Init,
     Error,
     Wink,
     Lock,
     Sync,
+    Cancel,
     Vendor { identifier: u8 },
     Unknown { identifier: u8 },
 }
@@ -130,12 +147,14 @@ impl slog::Value for Command {
     ) -> slog::Result {
         match self {
             &Command::Msg => "Msg",
+            &Command::Cbor => "Cbor",
             &Command::Ping => "Ping",
             &Command::Init => "Init",
             &Command::Error => "Error",
             &Command::Wink => "Wink",
             &Command::Lock => "Lock",
             &Command::Sync => "Sync",
+            &Command::Cancel => "Cancel",
             &Command::Unknown { .. } => "Unknown",
             &Command::Vendor { .. } => "Vendor",
         }.serialize(record, key, serializer)
@@ -180,7 +199,9 @@ impl Packet {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Packet, ()> {
-        assert_eq!(bytes.len(), HID_REPORT_LEN + 1);
+        if bytes.len() != HID_REPORT_LEN + 1 {
+            return Err(());
+        }
         let mut reader = Cursor::new(bytes);
         reader.read_u8().unwrap(); // TODO why do we have this extra byte to skip here
         let channel_id = ChannelId(reader.read_u32::<BigEndian>().unwrap());
@@ -188,12 +209,14 @@ impl Packet {
         if first_byte & FRAME_TYPE_MASK == FRAME_TYPE_INIT {
             let command = match first_byte {
                 U2FHID_MSG => Command::Msg,
+                CTAPHID_CBOR => Command::Cbor,
                 U2FHID_PING => Command::Ping,
                 U2FHID_INIT => Command::Init,
                 U2FHID_ERROR => Command::Error,
                 U2FHID_WINK => Command::Wink,
                 U2FHID_LOCK => Command::Lock,
                 U2FHID_SYNC => Command::Sync,
+                CTAPHID_CANCEL => Command::Cancel,
                 id if id >= U2FHID_VENDOR_FIRST && id <= U2FHID_VENDOR_LAST => {
                     Command::Vendor { identifier: id }
                 }
@@ -236,12 +259,14 @@ impl Packet {
                 // 4      1      CMD      Command identifier (bit 7 always set)
                 let command_byte = match command {
                     Command::Msg => U2FHID_MSG,
+                    Command::Cbor => CTAPHID_CBOR,
                     Command::Ping => U2FHID_PING,
                     Command::Init => U2FHID_INIT,
                     Command::Error => U2FHID_ERROR,
                     Command::Wink => U2FHID_WINK,
                     Command::Lock => U2FHID_LOCK,
                     Command::Sync => U2FHID_SYNC,
+                    Command::Cancel => CTAPHID_CANCEL,
                     Command::Vendor { identifier } => identifier,
                     Command::Unknown { identifier } => identifier,
                 };
@@ -291,10 +316,13 @@ pub struct Request {
 #[derive(Debug)]
 pub enum RequestMessage {
     EncapsulatedRequest { data: Vec<u8> },
+    Cbor { data: Vec<u8> },
     Init { nonce: [u8; 8] },
     // Lock time in seconds 0..10. A value of 0 immediately releases the lock
     Lock { lock_time: Duration },
     Ping { data: Vec<u8> },
+    Sync { nonce: u8 },
+    Cancel,
     Wink,
 }
 
@@ -304,6 +332,9 @@ impl RequestMessage {
             &Command::Msg => Ok(RequestMessage::EncapsulatedRequest {
                 data: data.to_vec(),
             }),
+            &Command::Cbor => Ok(RequestMessage::Cbor {
+                data: data.to_vec(),
+            }),
             &Command::Ping => Ok(RequestMessage::Ping {
                 data: data.to_vec(),
             }),
@@ -327,8 +358,13 @@ impl RequestMessage {
                 }
             },
             &Command::Sync => {
-                Err(RequestMessageDecodeError::UnsupportedCommand(*command))
+                if data.len() != COMMAND_SYNC_DATA_LEN {
+                    Err(RequestMessageDecodeError::PayloadLength(COMMAND_SYNC_DATA_LEN, data.len()))
+                } else {
+                    Ok(RequestMessage::Sync { nonce: data[0] })
+                }
             },
+            &Command::Cancel => Ok(RequestMessage::Cancel),
             &Command::Error => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
             &Command::Vendor { .. } => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
 
@@ -373,8 +409,9 @@ impl Response {
         let channel_id = self.channel_id;
         match self.message {
             ResponseMessage::EncapsulatedResponse { data } => {
-                encode_response(channel_id, Command::Msg, &data)
+                encode_message(channel_id, Command::Msg, &data)
             }
+            ResponseMessage::Cbor { data } => encode_message(channel_id, Command::Cbor, &data),
             ResponseMessage::Init {
                 nonce,
                 new_channel_id,
@@ -393,15 +430,16 @@ impl Response {
                 data.push(build_device_version_number);
                 data.push(capabilities.bits);
                 assert_eq!(data.len(), 17);
-                encode_response(channel_id, Command::Init, &data)
+                encode_message(channel_id, Command::Init, &data)
             }
-            ResponseMessage::Pong { data } => encode_response(channel_id, Command::Ping, &data),
+            ResponseMessage::Pong { data } => encode_message(channel_id, Command::Ping, &data),
             ResponseMessage::Error { code } => {
                 let data = vec![code.into_byte()];
-                encode_response(channel_id, Command::Error, &data)
+                encode_message(channel_id, Command::Error, &data)
             }
-            ResponseMessage::Wink => encode_response(channel_id, Command::Wink, &[]),
-            ResponseMessage::Lock => encode_response(channel_id, Command::Lock, &[]),
+            ResponseMessage::Wink => encode_message(channel_id, Command::Wink, &[]),
+            ResponseMessage::Lock => encode_message(channel_id, Command::Lock, &[]),
+            ResponseMessage::Sync { nonce } => encode_message(channel_id, Command::Sync, &[nonce]),
         }
     }
 }
@@ -411,6 +449,9 @@ pub enum ResponseMessage {
     EncapsulatedResponse {
         data: Vec<u8>,
     },
+    Cbor {
+        data: Vec<u8>,
+    },
     Init {
         nonce: [u8; 8],
         new_channel_id: ChannelId,
@@ -428,6 +469,9 @@ pub enum ResponseMessage {
     },
     Wink,
     Lock,
+    Sync {
+        nonce: u8,
+    },
 }
 
 impl slog::Value for ResponseMessage {
@@ -439,24 +483,34 @@ impl slog::Value for ResponseMessage {
     ) -> slog::Result {
         match self {
             ResponseMessage::EncapsulatedResponse { .. } => "EncapsulatedResponse",
+            ResponseMessage::Cbor { .. } => "Cbor",
             ResponseMessage::Init { .. } => "Init",
             ResponseMessage::Pong { .. } => "Pong",
             ResponseMessage::Error { .. } => "Error",
             ResponseMessage::Wink => "Wink",
             ResponseMessage::Lock => "Lock",
+            ResponseMessage::Sync { .. } => "Sync",
         }.serialize(record, key, serializer)
     }
 }
 
 impl From<u2f_core::Response> for ResponseMessage {
     fn from(response: u2f_core::Response) -> ResponseMessage {
-        ResponseMessage::EncapsulatedResponse {
-            data: response.into_bytes(),
+        match response {
+            u2f_core::Response::Ctap2(response) => ResponseMessage::Cbor {
+                data: response.into_bytes(),
+            },
+            // U2FHID_WINK is answered with an empty U2FHID_WINK, not a status word
+            u2f_core::Response::DidWink => ResponseMessage::Wink,
+            response => ResponseMessage::EncapsulatedResponse {
+                data: response.into_bytes(),
+            },
         }
     }
 }
 
-fn encode_response(channel_id: ChannelId, command: Command, data: &[u8]) -> VecDeque<Packet> {
+/// Split a message into an initialization packet followed by continuation packets
+pub fn encode_message(channel_id: ChannelId, command: Command, data: &[u8]) -> VecDeque<Packet> {
     let mut packets = VecDeque::new();
     let payload_len = data.len();
     let split_index = cmp::min(data.len(), INITIAL_PACKET_DATA_LEN);
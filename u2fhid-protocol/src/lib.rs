@@ -6,6 +6,7 @@ extern crate futures;
 extern crate itertools;
 #[macro_use]
 extern crate quick_error;
+extern crate rand;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -17,18 +18,24 @@ extern crate u2f_core;
 use std::collections::vec_deque::VecDeque;
 use std::io;
 
+pub use client::PacketTransport;
 use definitions::*;
-pub use definitions::Packet;
+pub use definitions::{ChannelId, Packet};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use protocol_state_machine::StateMachine;
 use segmenting_sink::{Segmenter, SegmentingSink};
 use slog::Drain;
+use timer::{ReactorTimer, Timer};
 use tokio_core::reactor::Handle;
 use u2f_core::{Service, U2F};
 
+mod client;
 mod definitions;
 mod protocol_state_machine;
 mod segmenting_sink;
+mod timer;
+#[cfg(test)]
+mod virtual_hid;
 
 struct PacketSegmenter;
 
@@ -57,6 +64,15 @@ where
         transport: T,
         service: U2F,
         logger: L,
+    ) -> U2FHID<T, U2F> {
+        U2FHID::bind_service_with_timer(Box::new(ReactorTimer(handle)), transport, service, logger)
+    }
+
+    pub(crate) fn bind_service_with_timer<L: Into<Option<slog::Logger>>>(
+        timer: Box<dyn Timer>,
+        transport: T,
+        service: U2F,
+        logger: L,
     ) -> U2FHID<T, U2F> {
         let logger = logger
             .into()
@@ -64,7 +80,7 @@ where
         let state_machine_logger = logger.new(o!());
         U2FHID {
             logger,
-            state_machine: StateMachine::new(service, handle, state_machine_logger),
+            state_machine: StateMachine::with_timer(service, timer, state_machine_logger),
             transport: SegmentingSink::new(transport, PacketSegmenter),
         }
     }
@@ -1,13 +1,14 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::io;
-use std::mem;
 use std::time::Duration;
 
 use definitions::*;
 use futures::{Async, Future};
 use futures::future;
 use slog::Logger;
+use timer::{Delay, ReactorTimer, Timer};
 use tokio_core::reactor::Handle;
-use tokio_core::reactor::Timeout;
 use u2f_core::{self, Service};
 
 macro_rules! try_some {
@@ -18,64 +19,73 @@ macro_rules! try_some {
     })
 }
 
+/// Partially received message on one channel
 struct ReceiveState {
     buffer: Vec<u8>,
     command: Command,
     next_sequence_number: u8,
     payload_len: usize,
-    channel_id: ChannelId,
-    packet_timeout: Timeout,
-    transaction_timeout: Timeout,
+    packet_timeout: Delay,
+    transaction_timeout: Delay,
 }
 
-struct DispatchState {
-    channel_id: ChannelId,
-    future: Box<dyn Future<Item = ResponseMessage, Error = io::Error>>,
-    timeout: Timeout,
-}
+impl ReceiveState {
+    /// Buffer data up to the payload length, packets are padded beyond it
+    fn extend(&mut self, data: &[u8]) {
+        let remaining = self.payload_len - self.buffer.len();
+        self.buffer
+            .extend_from_slice(&data[..cmp::min(remaining, data.len())]);
+    }
 
-enum State {
-    Idle,
-    Receive(ReceiveState),
-    Dispatch(DispatchState),
-    Unknown,
+    fn is_complete(&self) -> bool {
+        self.buffer.len() >= self.payload_len
+    }
 }
 
-impl State {
-    fn take(&mut self) -> State {
-        mem::replace(self, State::Unknown)
-    }
+struct DispatchState {
+    channel_id: ChannelId,
+    command: Command,
+    future: Box<dyn Future<Item = ResponseMessage, Error = io::Error>>,
+    timeout: Delay,
 }
 
 const MAX_CHANNEL_ID: ChannelId = ChannelId(BROADCAST_CHANNEL_ID.0 - 1);
 const MIN_CHANNEL_ID: ChannelId = ChannelId(1);
 
+/// Limits the memory held by messages that are still being received
+const MAX_RECEIVING_CHANNELS: usize = 16;
+
 #[derive(Debug)]
 struct Channels {
     next_allocation: ChannelId,
+    recycled: bool,
 }
 
 impl Channels {
     fn new() -> Channels {
         Channels {
             next_allocation: MIN_CHANNEL_ID,
+            recycled: false,
         }
     }
 
-    fn allocate(&mut self) -> Result<ChannelId, ()> {
-        if self.next_allocation > MAX_CHANNEL_ID {
-            Err(())
+    fn allocate(&mut self) -> ChannelId {
+        let allocation = self.next_allocation;
+        if allocation == MAX_CHANNEL_ID {
+            // Every identifier has been handed out, start again from the first
+            self.next_allocation = MIN_CHANNEL_ID;
+            self.recycled = true;
         } else {
-            let allocation = self.next_allocation;
-            self.next_allocation = self.next_allocation.checked_add(1).unwrap();
-            Ok(allocation)
+            self.next_allocation = allocation.checked_add(1).unwrap();
         }
+        allocation
     }
 
     fn is_valid(&self, channel_id: ChannelId) -> bool {
         let is_broadcast = channel_id == BROADCAST_CHANNEL_ID;
-        let is_in_allocated_range =
-            channel_id >= MIN_CHANNEL_ID && channel_id < self.next_allocation;
+        let is_in_allocated_range = channel_id >= MIN_CHANNEL_ID
+            && (channel_id < self.next_allocation
+                || (self.recycled && channel_id <= MAX_CHANNEL_ID));
         is_broadcast || is_in_allocated_range
     }
 }
@@ -84,7 +94,7 @@ enum LockState {
     None,
     Locked {
         channel_id: ChannelId,
-        timeout: Timeout,
+        timeout: Delay,
     },
 }
 
@@ -93,11 +103,11 @@ impl LockState {
         &mut self,
         duration: Duration,
         channel_id: ChannelId,
-        handle: &Handle,
+        timer: &dyn Timer,
     ) -> io::Result<()> {
         *self = LockState::Locked {
             channel_id: channel_id,
-            timeout: Timeout::new(duration, handle)?,
+            timeout: timer.delay(duration)?,
         };
         Ok(())
     }
@@ -106,15 +116,22 @@ impl LockState {
         *self = LockState::None;
     }
 
+    fn is_held_by_other(&self, channel_id: ChannelId) -> bool {
+        match *self {
+            LockState::Locked {
+                channel_id: locking_channel_id,
+                ..
+            } => locking_channel_id != channel_id,
+            LockState::None => false,
+        }
+    }
+
     fn tick(&mut self) -> Result<(), io::Error> {
         // Check lock timeout
         let timed_out = match *self {
             LockState::Locked {
                 ref mut timeout, ..
-            } => match timeout.poll()? {
-                Async::Ready(()) => true,
-                Async::NotReady => false,
-            },
+            } => has_elapsed(timeout)?,
             _ => false,
         };
 
@@ -122,24 +139,20 @@ impl LockState {
             *self = LockState::None;
         }
 
-        // TODO Check frame and transation timeouts
-
         Ok(())
     }
 }
 
-struct StateTransition<O> {
-    new_state: State,
-    output: O,
-}
-
+/// Messages are reassembled per channel, so packets of several channels may
+/// interleave. Only one request is dispatched at a time.
 pub struct StateMachine<S> {
     channels: Channels,
-    handle: Handle,
+    dispatch: Option<DispatchState>,
     lock: LockState,
     logger: Logger,
+    receiving: HashMap<ChannelId, ReceiveState>,
     service: S,
-    state: State,
+    timer: Box<dyn Timer>,
 }
 
 impl<S> StateMachine<S>
@@ -153,13 +166,18 @@ where
     ResponseMessage: From<<S as u2f_core::Service>::Response>,
 {
     pub fn new(service: S, handle: Handle, logger: Logger) -> StateMachine<S> {
+        StateMachine::with_timer(service, Box::new(ReactorTimer(handle)), logger)
+    }
+
+    pub fn with_timer(service: S, timer: Box<dyn Timer>, logger: Logger) -> StateMachine<S> {
         StateMachine {
             channels: Channels::new(),
-            handle: handle,
+            dispatch: None,
             lock: LockState::None,
             logger: logger,
+            receiving: HashMap::new(),
             service: service,
-            state: State::Idle,
+            timer: timer,
         }
     }
 
@@ -167,44 +185,16 @@ where
         // Tick the lock for possible timeout
         self.lock.tick()?;
 
-        let transition = match self.state.take() {
-            State::Receive(receive) => {
-                // TODO check timeouts
-                // receive.packet_timeout
-                StateTransition {
-                    new_state: State::Receive(receive),
-                    output: None,
-                }
-            }
-            State::Dispatch(mut dispatch) => {
-                // check if ready
-                match dispatch.future.poll()? {
-                    Async::Ready(result) => {
-                        let channel_id = dispatch.channel_id;
-                        StateTransition {
-                            new_state: State::Idle,
-                            output: Some(Response {
-                                channel_id: channel_id,
-                                message: result,
-                            }),
-                        }
-                    }
-                    Async::NotReady => StateTransition {
-                        new_state: State::Dispatch(dispatch),
-                        output: None,
-                    },
-                }
-            }
-            state => StateTransition {
-                new_state: state,
-                output: None,
-            },
-        };
-        self.state = transition.new_state;
-        Ok(transition.output)
+        try_some!(self.check_receive_timeouts());
+        try_some!(self.try_complete_dispatch());
+        try_some!(self.check_dispatch_timeout());
+
+        Ok(None)
     }
 
     pub fn accept_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
+        let channel_id = packet.channel_id();
+
         debug!(self.logger, "check_channel_id");
         try_some!(self.check_channel_id(&packet));
 
@@ -215,7 +205,7 @@ where
         try_some!(self.step_with_packet(packet));
 
         debug!(self.logger, "try_complete_receive");
-        try_some!(self.try_complete_receive());
+        try_some!(self.try_complete_receive(channel_id));
 
         debug!(self.logger, "try_complete_dispatch");
         try_some!(self.try_complete_dispatch());
@@ -238,221 +228,234 @@ where
 
     fn check_lock(&self, packet: &Packet) -> Result<Option<Response>, io::Error> {
         let packet_channel_id = packet.channel_id();
-        match self.lock {
-            LockState::Locked { channel_id, .. } => {
-                if packet_channel_id != channel_id {
-                    Ok(Some(Self::error_output(
-                        ErrorCode::ChannelBusy,
-                        packet_channel_id,
-                    )))
-                } else {
-                    Ok(None)
-                }
+        if self.lock.is_held_by_other(packet_channel_id) {
+            Ok(Some(Self::error_output(
+                ErrorCode::ChannelBusy,
+                packet_channel_id,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_receive_timeouts(&mut self) -> Result<Option<Response>, io::Error> {
+        // Every channel is polled so any of them can wake us
+        let mut timed_out = None;
+        for (channel_id, receive) in self.receiving.iter_mut() {
+            let has_timed_out = has_elapsed(&mut receive.packet_timeout)?
+                || has_elapsed(&mut receive.transaction_timeout)?;
+            if has_timed_out && timed_out.is_none() {
+                timed_out = Some(*channel_id);
+            }
+        }
+
+        match timed_out {
+            Some(channel_id) => {
+                debug!(self.logger, "Message timed out"; "channel_id" => channel_id);
+                self.receiving.remove(&channel_id);
+                Ok(Some(Self::error_output(
+                    ErrorCode::MessageTimedOut,
+                    channel_id,
+                )))
             }
-            LockState::None => Ok(None),
+            None => Ok(None),
         }
     }
 
     fn step_with_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
-        let transition = match (self.state.take(), packet) {
-            (
-                State::Idle,
-                Packet::Initialization {
-                    channel_id,
-                    data,
-                    payload_len,
-                    command,
-                },
-            ) => {
-                debug!(self.logger, "Begin transaction"; "channel_id" => &channel_id, "command" => &command, "payload_len" => payload_len);
-                StateTransition {
-                    new_state: State::Receive(ReceiveState {
-                        buffer: data.to_vec(),
-                        channel_id: channel_id,
-                        command: command,
-                        next_sequence_number: 0,
-                        payload_len: payload_len,
-                        packet_timeout: Timeout::new(packet_timeout_duration(), &self.handle)?,
-                        transaction_timeout: Timeout::new(
-                            transaction_timeout_duration(),
-                            &self.handle,
-                        )?,
-                    }),
-                    output: None,
+        match packet {
+            Packet::Initialization {
+                channel_id,
+                data,
+                payload_len,
+                command,
+            } => {
+                // Abort the channel's transaction instead of being refused because of it
+                let aborts = aborts_transaction(command);
+                if self.receiving.remove(&channel_id).is_some() && !aborts {
+                    debug!(self.logger, "Invalid message sequencing"; "channel_id" => channel_id);
+                    return Ok(Some(Self::error_output(
+                        ErrorCode::InvalidMessageSequencing,
+                        channel_id,
+                    )));
                 }
-            }
-            (state @ State::Idle, Packet::Continuation { .. }) => {
-                debug!(self.logger, "Out of order continuation packet, ignoring");
-                StateTransition {
-                    new_state: state,
-                    output: None,
+                if self.dispatch.is_some() && !aborts {
+                    debug!(self.logger, "Busy with dispatched transaction"; "channel_id" => channel_id);
+                    return Ok(Some(Self::error_output(ErrorCode::ChannelBusy, channel_id)));
                 }
+                if payload_len > MAX_MESSAGE_LEN {
+                    debug!(self.logger, "Message too long"; "channel_id" => channel_id, "payload_len" => payload_len);
+                    return Ok(Some(Self::error_output(
+                        ErrorCode::InvalidMessageLength,
+                        channel_id,
+                    )));
+                }
+                if self.receiving.len() >= MAX_RECEIVING_CHANNELS {
+                    debug!(self.logger, "Too many channels receiving"; "channel_id" => channel_id);
+                    return Ok(Some(Self::error_output(ErrorCode::ChannelBusy, channel_id)));
+                }
+
+                debug!(self.logger, "Begin transaction"; "channel_id" => &channel_id, "command" => &command, "payload_len" => payload_len);
+                let mut receive = ReceiveState {
+                    buffer: Vec::with_capacity(payload_len),
+                    command: command,
+                    next_sequence_number: 0,
+                    payload_len: payload_len,
+                    packet_timeout: self.timer.delay(packet_timeout_duration())?,
+                    transaction_timeout: self.timer.delay(transaction_timeout_duration())?,
+                };
+                receive.extend(&data);
+                self.receiving.insert(channel_id, receive);
+                Ok(None)
             }
-            (State::Receive(receive), Packet::Initialization { channel_id, .. }) => {
-                if channel_id == receive.channel_id {
-                    debug!(self.logger, "Invalid message sequencing");
-                    StateTransition {
-                        new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
-                    }
-                } else {
-                    debug!(self.logger, "Other channel busy with transaction");
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                data,
+            } => {
+                let is_in_sequence = match self.receiving.get(&channel_id) {
+                    Some(receive) => sequence_number == receive.next_sequence_number,
+                    None => {
+                        debug!(self.logger, "Out of order continuation packet, ignoring"; "channel_id" => channel_id);
+                        return Ok(None);
                     }
+                };
+                if !is_in_sequence {
+                    self.receiving.remove(&channel_id);
+                    return Ok(Some(Self::error_output(
+                        ErrorCode::InvalidMessageSequencing,
+                        channel_id,
+                    )));
                 }
+
+                let packet_timeout = self.timer.delay(packet_timeout_duration())?;
+                let receive = self.receiving.get_mut(&channel_id).unwrap();
+                receive.next_sequence_number += 1;
+                receive.extend(&data);
+                receive.packet_timeout = packet_timeout;
+                Ok(None)
             }
-            (
-                State::Receive(mut receive),
-                Packet::Continuation {
+        }
+    }
+
+    fn try_complete_receive(
+        &mut self,
+        channel_id: ChannelId,
+    ) -> Result<Option<Response>, io::Error> {
+        match self.receiving.get(&channel_id) {
+            Some(receive) if receive.is_complete() => {}
+            Some(receive) => {
+                debug!(self.logger, "Payload incomplete"; "payload_len" => receive.payload_len, "receive_len" => receive.buffer.len());
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+
+        let receive = self.receiving.remove(&channel_id).unwrap();
+        debug!(self.logger, "Received payload"; "len" => receive.payload_len);
+        if self.dispatch.is_some() && !aborts_transaction(receive.command) {
+            debug!(self.logger, "Busy with dispatched transaction"; "channel_id" => channel_id);
+            return Ok(Some(Self::error_output(ErrorCode::ChannelBusy, channel_id)));
+        }
+
+        match RequestMessage::decode(&receive.command, &receive.buffer) {
+            Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
+                info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
+                Ok(Some(Self::error_output(
+                    ErrorCode::InvalidCommand,
                     channel_id,
-                    sequence_number,
-                    data,
-                },
-            ) => {
-                if channel_id != receive.channel_id {
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
-                    }
-                } else if sequence_number != receive.next_sequence_number {
-                    StateTransition {
-                        new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
-                    }
-                } else {
-                    receive.next_sequence_number += 1;
-                    receive.buffer.extend_from_slice(&data);
-                    receive.packet_timeout = Timeout::new(packet_timeout_duration(), &self.handle)?;
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
-                    }
-                }
+                )))
             }
-            (state @ State::Dispatch(_), packet) => StateTransition {
-                new_state: state,
-                output: Some(Self::error_output(
-                    ErrorCode::ChannelBusy,
-                    packet.channel_id(),
-                )),
+            Err(error) => {
+                debug!(self.logger, "Unable to decode request message"; "error" => error);
+                Ok(Some(Self::error_output(ErrorCode::Other, channel_id)))
+            }
+            Ok(RequestMessage::Cancel) => Ok(self.cancel(channel_id)),
+            Ok(RequestMessage::Sync { nonce }) => {
+                self.cancel(channel_id);
+                Ok(Some(Response {
+                    channel_id: channel_id,
+                    message: ResponseMessage::Sync { nonce },
+                }))
+            }
+            Ok(message) => {
+                let response_future = self.handle_request(Request {
+                    channel_id: channel_id,
+                    message: message,
+                })?;
+                self.dispatch = Some(DispatchState {
+                    channel_id: channel_id,
+                    command: receive.command,
+                    future: response_future,
+                    timeout: self.timer.delay(dispatch_timeout_duration())?,
+                });
+                Ok(None)
+            }
+        }
+    }
+
+    fn try_complete_dispatch(&mut self) -> Result<Option<Response>, io::Error> {
+        let response = match self.dispatch {
+            Some(ref mut dispatch) => match dispatch.future.poll()? {
+                Async::Ready(message) => Response {
+                    channel_id: dispatch.channel_id,
+                    message: message,
+                },
+                Async::NotReady => return Ok(None),
             },
-            (State::Unknown, _) => panic!(),
+            None => return Ok(None),
         };
 
-        self.state = transition.new_state;
-        Ok(transition.output)
-    }
-
-    fn try_complete_receive(&mut self) -> Result<Option<Response>, io::Error> {
-        let transition = match self.state.take() {
-            State::Receive(receive) => {
-                if receive.buffer.len() >= receive.payload_len {
-                    let bytes = &receive.buffer[0..receive.payload_len];
-                    debug!(self.logger, "Received payload"; "len" => receive.payload_len);
-                    match RequestMessage::decode(&receive.command, bytes) {
-                        Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
-                            info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::InvalidCommand,
-                                    },
-                                }),
-                            }
-                        },
-                        Err(error) => {
-                            debug!(self.logger, "Unable to decode request message"; "error" => error);
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::Other,
-                                    },
-                                }),
-                            }
-                        },
-                        Ok(message) => {
-                            let response_future = self.handle_request(Request {
-                                channel_id: receive.channel_id,
-                                message: message,
-                            })?;
-                            let dispatch_state = DispatchState {
-                                channel_id: receive.channel_id,
-                                future: response_future,
-                                timeout: receive.transaction_timeout,
-                            };
-                            StateTransition {
-                                new_state: State::Dispatch(dispatch_state),
-                                output: None,
-                            }
-                        }
-                    }
-                } else {
-                    debug!(self.logger, "Payload incomplete"; "payload_len" => receive.payload_len, "receive_len" => receive.buffer.len());
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
-                    }
+        self.dispatch = None;
+        Ok(Some(response))
+    }
+
+    fn check_dispatch_timeout(&mut self) -> Result<Option<Response>, io::Error> {
+        let channel_id = match self.dispatch {
+            Some(ref mut dispatch) => {
+                if !has_elapsed(&mut dispatch.timeout)? {
+                    return Ok(None);
                 }
+                dispatch.channel_id
             }
-            state => StateTransition {
-                new_state: state,
-                output: None,
-            },
+            None => return Ok(None),
         };
 
-        self.state = transition.new_state;
-        Ok(transition.output)
+        // Dropping the future cancels the request
+        debug!(self.logger, "Request timed out"; "channel_id" => channel_id);
+        self.dispatch = None;
+        Ok(Some(Self::error_output(
+            ErrorCode::MessageTimedOut,
+            channel_id,
+        )))
     }
 
-    fn try_complete_dispatch(&mut self) -> Result<Option<Response>, io::Error> {
-        let transition = match self.state.take() {
-            State::Dispatch(mut dispatch) => match dispatch.future.poll()? {
-                Async::Ready(response) => StateTransition {
-                    new_state: State::Idle,
-                    output: Some(Response {
-                        channel_id: dispatch.channel_id,
-                        message: response,
+    /// Abort the request dispatched for the channel. Dropping its future
+    /// cancels any test of user presence it is waiting for.
+    fn cancel(&mut self, channel_id: ChannelId) -> Option<Response> {
+        match self.dispatch.take() {
+            Some(dispatch) => {
+                if dispatch.channel_id != channel_id {
+                    self.dispatch = Some(dispatch);
+                    return None;
+                }
+                debug!(self.logger, "Request cancelled"; "channel_id" => channel_id);
+                match dispatch.command {
+                    // CTAP2 requests are answered, U2F messages have no response for it
+                    Command::Cbor => Some(Response {
+                        channel_id: channel_id,
+                        message: ResponseMessage::Cbor {
+                            data: u2f_core::Ctap2Response::Error(
+                                u2f_core::Ctap2StatusCode::KeepaliveCancel,
+                            )
+                            .into_bytes(),
+                        },
                     }),
-                },
-                Async::NotReady => StateTransition {
-                    new_state: State::Dispatch(dispatch),
-                    output: None,
-                },
-            },
-            state => StateTransition {
-                new_state: state,
-                output: None,
-            },
-        };
-
-        self.state = transition.new_state;
-        Ok(transition.output)
+                    _ => None,
+                }
+            }
+            None => None,
+        }
     }
 
     fn error_output(error_code: ErrorCode, channel_id: ChannelId) -> Response {
@@ -469,17 +472,34 @@ where
         let channel_id = request.channel_id;
         match request.message {
             RequestMessage::EncapsulatedRequest { data } => {
-                // TODO no unwrap
                 debug!(self.logger, "RequestMessage::EncapsulatedRequest"; "data.len" => data.len());
-                let request = u2f_core::Request::decode(&data).unwrap();
-                Ok(self.dispatch(request))
+                match u2f_core::Request::decode(&data) {
+                    Ok(request) => Ok(self.dispatch(request)),
+                    Err(error) => {
+                        debug!(self.logger, "Unable to decode request"; "error" => %error);
+                        let mut data = Vec::new();
+                        error.status_code().write(&mut data);
+                        Ok(Box::new(future::ok(ResponseMessage::EncapsulatedResponse { data })))
+                    }
+                }
+            }
+            RequestMessage::Cbor { data } => {
+                debug!(self.logger, "RequestMessage::Cbor"; "data.len" => data.len());
+                match u2f_core::Ctap2Request::decode(&data) {
+                    Ok(request) => Ok(self.dispatch(u2f_core::Request::Ctap2(request))),
+                    Err(status) => {
+                        debug!(self.logger, "Unable to decode CTAP2 request"; "status" => ?status);
+                        Ok(Box::new(future::ok(ResponseMessage::Cbor {
+                            data: u2f_core::Ctap2Response::Error(status).into_bytes(),
+                        })))
+                    }
+                }
             }
             RequestMessage::Init { nonce } => {
                 // TODO Check what channnel message came in on
-                // TODO check unwrap
-                let new_channel_id = self.channels
-                    .allocate()
-                    .expect("Failed to allocate new channel");
+                let new_channel_id = self.channels.allocate();
+                // A recycled channel starts afresh
+                self.receiving.remove(&new_channel_id);
                 debug!(self.logger, "RequestMessage::Init"; "new_channel_id" => new_channel_id);
                 Ok(Box::new(future::ok(ResponseMessage::Init {
                     nonce,
@@ -488,7 +508,7 @@ where
                     major_device_version_number: MAJOR_DEVICE_VERSION_NUMBER,
                     minor_device_version_number: MINOR_DEVICE_VERSION_NUMBER,
                     build_device_version_number: BUILD_DEVICE_VERSION_NUMBER,
-                    capabilities: CapabilityFlags::CAPFLAG_WINK,
+                    capabilities: CapabilityFlags::CAPFLAG_WINK | CapabilityFlags::CAPFLAG_CBOR,
                 })))
             }
             RequestMessage::Ping { data } => {
@@ -496,15 +516,22 @@ where
                 Ok(Box::new(future::ok(ResponseMessage::Pong { data: data })))
             }
             RequestMessage::Wink => Ok(self.dispatch(u2f_core::Request::Wink)),
+            RequestMessage::Sync { .. } | RequestMessage::Cancel => {
+                unreachable!("Aborting messages are handled before dispatch")
+            }
             RequestMessage::Lock { lock_time } => {
                 debug!(self.logger, "RequestMessage::Lock"; "lock_time" => lock_time.as_secs());
+                if lock_time > max_lock_duration() {
+                    return Ok(Box::new(future::ok(ResponseMessage::Error {
+                        code: ErrorCode::InvalidParameter,
+                    })));
+                }
+                // Only the locking channel gets this far while the device is locked
                 if lock_time == Duration::from_secs(0) {
-                    // TODO Enforce correct channel
                     self.lock.release();
                 } else {
-                    // TODO enforce range of 1-10
-                    // TODO check channel_id matches current lock state
-                    self.lock.lock(lock_time, channel_id, &self.handle)?;
+                    // Locking again extends or shortens the lock
+                    self.lock.lock(lock_time, channel_id, self.timer.as_ref())?;
                 }
                 Ok(Box::new(future::ok(ResponseMessage::Lock)))
             }
@@ -519,6 +546,19 @@ where
     }
 }
 
+/// Whether the command aborts a transaction on its channel rather than
+/// waiting for it to complete
+fn aborts_transaction(command: Command) -> bool {
+    command == Command::Sync || command == Command::Cancel
+}
+
+fn has_elapsed(delay: &mut Delay) -> io::Result<bool> {
+    Ok(match delay.poll()? {
+        Async::Ready(()) => true,
+        Async::NotReady => false,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     extern crate rand;
@@ -527,7 +567,13 @@ mod tests {
     use slog_stdlog;
     use tokio_core::reactor::Core;
 
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use futures::Poll;
+
     use super::*;
+    use timer::FakeClock;
 
     use self::rand::{OsRng, Rng};
 
@@ -544,6 +590,173 @@ mod tests {
         }
     }
 
+    struct WinkingU2FService;
+
+    impl Service for WinkingU2FService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            Box::new(future::ok(u2f_core::Response::DidWink))
+        }
+    }
+
+    /// Never answers, like a user who ignores the presence prompt
+    #[derive(Default)]
+    struct PendingU2FService {
+        cancelled: Rc<Cell<bool>>,
+    }
+
+    impl Service for PendingU2FService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            Box::new(PendingResponse(self.cancelled.clone()))
+        }
+    }
+
+    /// Records being dropped before completing
+    struct PendingResponse(Rc<Cell<bool>>);
+
+    impl Future for PendingResponse {
+        type Item = u2f_core::Response;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<u2f_core::Response, io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for PendingResponse {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    fn with_fake_clock<S>(service: S) -> (StateMachine<S>, FakeClock)
+    where
+        S: Service<
+            Request = u2f_core::Request,
+            Response = u2f_core::Response,
+            Error = io::Error,
+            Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+        >,
+    {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let clock = FakeClock::default();
+        let state_machine = StateMachine::with_timer(service, Box::new(clock.clone()), logger);
+        (state_machine, clock)
+    }
+
+    /// Timeouts can only be polled from within a task
+    fn step_in_task<S>(state_machine: &mut StateMachine<S>) -> Option<Response>
+    where
+        S: Service<
+            Request = u2f_core::Request,
+            Response = u2f_core::Response,
+            Error = io::Error,
+            Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+        >,
+    {
+        future::lazy(|| state_machine.step()).wait().unwrap()
+    }
+
+    fn assert_timed_out(response: Option<Response>, expected_channel_id: ChannelId) {
+        match response {
+            Some(Response {
+                channel_id,
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::MessageTimedOut,
+                    },
+            }) => assert_eq!(channel_id, expected_channel_id),
+            _ => panic!(),
+        }
+    }
+
+    fn get_version_request(channel_id: ChannelId) -> Packet {
+        Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Msg,
+            data: vec![0x00, 0x03, 0x00, 0x00],
+            payload_len: 4,
+        }
+    }
+
+    fn cancel_request(channel_id: ChannelId) -> Packet {
+        Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Cancel,
+            data: vec![],
+            payload_len: 0,
+        }
+    }
+
+    fn sync_request(channel_id: ChannelId, nonce: u8) -> Packet {
+        Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Sync,
+            data: vec![nonce],
+            payload_len: 1,
+        }
+    }
+
+    fn lock_request(channel_id: ChannelId, seconds: u8) -> Packet {
+        Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Lock,
+            data: vec![seconds],
+            payload_len: 1,
+        }
+    }
+
+    fn ping_request(channel_id: ChannelId) -> Packet {
+        Packet::Initialization {
+            channel_id: channel_id,
+            command: Command::Ping,
+            data: vec![0u8; 8],
+            payload_len: 8,
+        }
+    }
+
+    fn assert_locked(response: Option<Response>, expected_channel_id: ChannelId) {
+        match response {
+            Some(Response {
+                channel_id,
+                message: ResponseMessage::Lock,
+            }) => assert_eq!(channel_id, expected_channel_id),
+            _ => panic!(),
+        }
+    }
+
+    fn assert_pong(response: Option<Response>, expected_channel_id: ChannelId) {
+        match response {
+            Some(Response {
+                channel_id,
+                message: ResponseMessage::Pong { .. },
+            }) => assert_eq!(channel_id, expected_channel_id),
+            _ => panic!(),
+        }
+    }
+
+    fn assert_busy(response: Option<Response>, expected_channel_id: ChannelId) {
+        match response {
+            Some(Response {
+                channel_id,
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::ChannelBusy,
+                    },
+            }) => assert_eq!(channel_id, expected_channel_id),
+            _ => panic!(),
+        }
+    }
+
     #[test]
     fn channels_broadcast_channel_is_valid() {
         let channels = Channels::new();
@@ -553,10 +766,24 @@ mod tests {
     #[test]
     fn channels_allocated_channel_is_valid() {
         let mut channels = Channels::new();
-        let channel_id = channels.allocate().unwrap();
+        let channel_id = channels.allocate();
         assert!(channels.is_valid(channel_id));
     }
 
+    #[test]
+    fn channels_are_recycled_once_exhausted() {
+        let mut channels = Channels {
+            next_allocation: MAX_CHANNEL_ID,
+            recycled: false,
+        };
+
+        assert_eq!(channels.allocate(), MAX_CHANNEL_ID);
+        assert_eq!(channels.allocate(), MIN_CHANNEL_ID);
+        assert!(channels.is_valid(MAX_CHANNEL_ID));
+        assert!(channels.is_valid(BROADCAST_CHANNEL_ID));
+        assert!(!channels.is_valid(ChannelId(0)));
+    }
+
     #[test]
     fn init() {
         let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
@@ -642,4 +869,570 @@ mod tests {
             _ => panic!(),
         };
     }
+
+    #[test]
+    fn init_advertises_cbor_capability() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: BROADCAST_CHANNEL_ID,
+                command: Command::Init,
+                data: vec![0u8; 8],
+                payload_len: 8,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                message: ResponseMessage::Init { capabilities, .. },
+                ..
+            }) => assert!(capabilities.contains(CapabilityFlags::CAPFLAG_CBOR)),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn cbor_with_unknown_command_responds_with_status() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Cbor,
+                data: vec![0x42],
+                payload_len: 1,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message: ResponseMessage::Cbor { data },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                // CTAP1_ERR_INVALID_COMMAND
+                assert_eq!(data, vec![0x01]);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn wink_responds_with_wink() {
+        let (mut state_machine, _clock) = with_fake_clock(WinkingU2FService);
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Wink,
+                data: vec![],
+                payload_len: 0,
+            })
+            .unwrap();
+        let res = match res {
+            Some(res) => Some(res),
+            None => step_in_task(&mut state_machine),
+        };
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message: ResponseMessage::Wink,
+            }) => assert_eq!(response_channel_id, channel_id),
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn msg_with_truncated_request_responds_with_wrong_length() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Msg,
+                data: vec![0x00, 0x01],
+                payload_len: 2,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message: ResponseMessage::EncapsulatedResponse { data },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                // SW_WRONG_LENGTH
+                assert_eq!(data, vec![0x67, 0x00]);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn incomplete_message_times_out_after_packet_timeout() {
+        let (mut state_machine, clock) = with_fake_clock(FakeU2FService);
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Ping,
+                data: vec![0u8; 57],
+                payload_len: 100,
+            })
+            .unwrap();
+        assert!(res.is_none());
+        assert!(step_in_task(&mut state_machine).is_none());
+
+        clock.advance(packet_timeout_duration());
+
+        assert_timed_out(step_in_task(&mut state_machine), channel_id);
+        assert!(step_in_task(&mut state_machine).is_none());
+    }
+
+    #[test]
+    fn continuation_packets_do_not_extend_transaction_timeout() {
+        let (mut state_machine, clock) = with_fake_clock(FakeU2FService);
+        let channel_id = init_channel(&mut state_machine);
+        let packet_interval = packet_timeout_duration() / 2;
+
+        state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Ping,
+                data: vec![0u8; 57],
+                payload_len: 57 + 59 * 100,
+            })
+            .unwrap();
+
+        let mut elapsed = Duration::from_secs(0);
+        let mut sequence_number = 0;
+        while elapsed + packet_interval < transaction_timeout_duration() {
+            clock.advance(packet_interval);
+            elapsed += packet_interval;
+            assert!(step_in_task(&mut state_machine).is_none());
+            let res = state_machine
+                .accept_packet(Packet::Continuation {
+                    channel_id: channel_id,
+                    sequence_number: sequence_number,
+                    data: vec![0u8; 59],
+                })
+                .unwrap();
+            assert!(res.is_none());
+            sequence_number += 1;
+        }
+
+        clock.advance(packet_interval);
+
+        assert_timed_out(step_in_task(&mut state_machine), channel_id);
+    }
+
+    #[test]
+    fn channel_is_usable_after_timeout() {
+        let (mut state_machine, clock) = with_fake_clock(FakeU2FService);
+        let channel_id = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Ping,
+                data: vec![0u8; 57],
+                payload_len: 100,
+            })
+            .unwrap();
+        clock.advance(packet_timeout_duration());
+        assert_timed_out(step_in_task(&mut state_machine), channel_id);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Ping,
+                data: vec![1u8; 8],
+                payload_len: 8,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                message: ResponseMessage::Pong { data },
+                ..
+            }) => assert_eq!(data, vec![1u8; 8]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn pending_request_times_out() {
+        let (mut state_machine, clock) = with_fake_clock(PendingU2FService::default());
+        let channel_id = init_channel(&mut state_machine);
+
+        // U2F_VERSION
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Msg,
+                data: vec![0x00, 0x03, 0x00, 0x00],
+                payload_len: 4,
+            })
+            .unwrap();
+        assert!(res.is_none());
+
+        clock.advance(transaction_timeout_duration());
+        assert!(step_in_task(&mut state_machine).is_none());
+
+        clock.advance(dispatch_timeout_duration());
+        assert_timed_out(step_in_task(&mut state_machine), channel_id);
+        assert!(step_in_task(&mut state_machine).is_none());
+    }
+
+    #[test]
+    fn interleaved_messages_are_reassembled_per_channel() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let first = init_channel(&mut state_machine);
+        let second = init_channel(&mut state_machine);
+        let first_data = vec![1u8; 100];
+        let second_data = vec![2u8; 100];
+        let mut first_packets = encode_message(first, Command::Ping, &first_data);
+        let mut second_packets = encode_message(second, Command::Ping, &second_data);
+
+        for packet in vec![
+            first_packets.pop_front().unwrap(),
+            second_packets.pop_front().unwrap(),
+        ] {
+            assert!(state_machine.accept_packet(packet).unwrap().is_none());
+        }
+        let second_res = state_machine
+            .accept_packet(second_packets.pop_front().unwrap())
+            .unwrap();
+        let first_res = state_machine
+            .accept_packet(first_packets.pop_front().unwrap())
+            .unwrap();
+
+        for (res, channel_id, data) in vec![
+            (first_res, first, first_data),
+            (second_res, second, second_data),
+        ] {
+            match res {
+                Some(Response {
+                    channel_id: response_channel_id,
+                    message:
+                        ResponseMessage::Pong {
+                            data: response_data,
+                        },
+                }) => {
+                    assert_eq!(response_channel_id, channel_id);
+                    assert_eq!(response_data, data);
+                }
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn other_channels_are_busy_while_request_is_dispatched() {
+        let (mut state_machine, _clock) = with_fake_clock(PendingU2FService::default());
+        let dispatching = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+        let mut other_packets = encode_message(other, Command::Ping, &[0u8; 100]);
+        state_machine
+            .accept_packet(other_packets.pop_front().unwrap())
+            .unwrap();
+
+        // U2F_VERSION
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: dispatching,
+                command: Command::Msg,
+                data: vec![0x00, 0x03, 0x00, 0x00],
+                payload_len: 4,
+            })
+            .unwrap();
+        assert!(res.is_none());
+
+        let res = state_machine
+            .accept_packet(other_packets.pop_front().unwrap())
+            .unwrap();
+        assert_busy(res, other);
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: other,
+                command: Command::Ping,
+                data: vec![0u8; 8],
+                payload_len: 8,
+            })
+            .unwrap();
+        assert_busy(res, other);
+    }
+
+    #[test]
+    fn message_longer_than_sequence_allows_is_invalid_length() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Ping,
+                data: vec![0u8; 57],
+                payload_len: MAX_MESSAGE_LEN + 1,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::InvalidMessageLength,
+                    },
+            }) => assert_eq!(response_channel_id, channel_id),
+            _ => panic!(),
+        }
+        assert!(state_machine.receiving.is_empty());
+    }
+
+    #[test]
+    fn cancel_aborts_pending_cbor_request() {
+        let service = PendingU2FService::default();
+        let cancelled = service.cancelled.clone();
+        let (mut state_machine, _clock) = with_fake_clock(service);
+        let channel_id = init_channel(&mut state_machine);
+
+        // authenticatorGetInfo
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: channel_id,
+                command: Command::Cbor,
+                data: vec![0x04],
+                payload_len: 1,
+            })
+            .unwrap();
+        assert!(res.is_none());
+        assert!(!cancelled.get());
+
+        let res = state_machine
+            .accept_packet(cancel_request(channel_id))
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message: ResponseMessage::Cbor { data },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                // CTAP2_ERR_KEEPALIVE_CANCEL
+                assert_eq!(data, vec![0x2d]);
+            }
+            _ => panic!(),
+        }
+        assert!(cancelled.get());
+        assert!(state_machine.dispatch.is_none());
+    }
+
+    #[test]
+    fn cancel_of_u2f_message_has_no_response() {
+        let service = PendingU2FService::default();
+        let cancelled = service.cancelled.clone();
+        let (mut state_machine, _clock) = with_fake_clock(service);
+        let channel_id = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(get_version_request(channel_id))
+            .unwrap();
+
+        let res = state_machine
+            .accept_packet(cancel_request(channel_id))
+            .unwrap();
+
+        assert!(res.is_none());
+        assert!(cancelled.get());
+    }
+
+    #[test]
+    fn cancel_from_other_channel_is_ignored() {
+        let service = PendingU2FService::default();
+        let cancelled = service.cancelled.clone();
+        let (mut state_machine, _clock) = with_fake_clock(service);
+        let dispatching = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(get_version_request(dispatching))
+            .unwrap();
+
+        let res = state_machine.accept_packet(cancel_request(other)).unwrap();
+
+        assert!(res.is_none());
+        assert!(!cancelled.get());
+        assert!(state_machine.dispatch.is_some());
+    }
+
+    #[test]
+    fn sync_aborts_pending_request_and_echoes_nonce() {
+        let service = PendingU2FService::default();
+        let cancelled = service.cancelled.clone();
+        let (mut state_machine, _clock) = with_fake_clock(service);
+        let channel_id = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(get_version_request(channel_id))
+            .unwrap();
+
+        let res = state_machine
+            .accept_packet(sync_request(channel_id, 0x42))
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message: ResponseMessage::Sync { nonce },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(nonce, 0x42);
+            }
+            _ => panic!(),
+        }
+        assert!(cancelled.get());
+    }
+
+    #[test]
+    fn sync_discards_partial_message() {
+        let (mut state_machine, _clock) = with_fake_clock(PendingU2FService::default());
+        let channel_id = init_channel(&mut state_machine);
+        let mut packets = encode_message(channel_id, Command::Ping, &[0u8; 100]);
+        state_machine
+            .accept_packet(packets.pop_front().unwrap())
+            .unwrap();
+
+        let res = state_machine
+            .accept_packet(sync_request(channel_id, 0x01))
+            .unwrap();
+        match res {
+            Some(Response {
+                message: ResponseMessage::Sync { nonce: 0x01 },
+                ..
+            }) => {}
+            _ => panic!(),
+        }
+
+        let res = state_machine
+            .accept_packet(packets.pop_front().unwrap())
+            .unwrap();
+        assert!(res.is_none());
+        assert!(state_machine.receiving.is_empty());
+    }
+
+    #[test]
+    fn lock_excludes_other_channels() {
+        let (mut state_machine, _clock) = with_fake_clock(FakeU2FService);
+        let locking = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(lock_request(locking, 5))
+            .unwrap();
+        assert_locked(res, locking);
+
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_busy(res, other);
+        let res = state_machine.accept_packet(lock_request(other, 5)).unwrap();
+        assert_busy(res, other);
+        let res = state_machine.accept_packet(ping_request(locking)).unwrap();
+        assert_pong(res, locking);
+    }
+
+    #[test]
+    fn lock_expires() {
+        let (mut state_machine, clock) = with_fake_clock(FakeU2FService);
+        let locking = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(lock_request(locking, 2))
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        assert!(step_in_task(&mut state_machine).is_none());
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_busy(res, other);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(step_in_task(&mut state_machine).is_none());
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_pong(res, other);
+    }
+
+    #[test]
+    fn locking_channel_can_extend_lock() {
+        let (mut state_machine, clock) = with_fake_clock(FakeU2FService);
+        let locking = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(lock_request(locking, 1))
+            .unwrap();
+
+        let res = state_machine
+            .accept_packet(lock_request(locking, 3))
+            .unwrap();
+        assert_locked(res, locking);
+        clock.advance(Duration::from_secs(2));
+        assert!(step_in_task(&mut state_machine).is_none());
+
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_busy(res, other);
+    }
+
+    #[test]
+    fn lock_of_zero_seconds_releases_lock() {
+        let (mut state_machine, _clock) = with_fake_clock(FakeU2FService);
+        let locking = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+        state_machine
+            .accept_packet(lock_request(locking, 10))
+            .unwrap();
+
+        let res = state_machine
+            .accept_packet(lock_request(locking, 0))
+            .unwrap();
+        assert_locked(res, locking);
+
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_pong(res, other);
+    }
+
+    #[test]
+    fn lock_longer_than_ten_seconds_is_invalid_parameter() {
+        let (mut state_machine, _clock) = with_fake_clock(FakeU2FService);
+        let locking = init_channel(&mut state_machine);
+        let other = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(lock_request(locking, 11))
+            .unwrap();
+
+        match res {
+            Some(Response {
+                channel_id,
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::InvalidParameter,
+                    },
+            }) => assert_eq!(channel_id, locking),
+            _ => panic!(),
+        }
+        let res = state_machine.accept_packet(ping_request(other)).unwrap();
+        assert_pong(res, other);
+    }
 }
@@ -0,0 +1,92 @@
+use std::io;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::{Handle, Timeout};
+
+pub type Delay = Box<dyn Future<Item = (), Error = io::Error>>;
+
+/// Source of the state machine's timeouts, so tests can control time
+pub trait Timer {
+    fn delay(&self, duration: Duration) -> io::Result<Delay>;
+}
+
+/// Timeouts driven by the reactor
+pub struct ReactorTimer(pub Handle);
+
+impl Timer for ReactorTimer {
+    fn delay(&self, duration: Duration) -> io::Result<Delay> {
+        Ok(Box::new(Timeout::new(duration, &self.0)?))
+    }
+}
+
+#[cfg(test)]
+pub use self::fake::FakeClock;
+
+#[cfg(test)]
+mod fake {
+    use std::cell::RefCell;
+    use std::io;
+    use std::mem;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use futures::task::{self, Task};
+    use futures::{Async, Future, Poll};
+
+    use super::{Delay, Timer};
+
+    /// Clock that only moves when advanced. Delays must be polled from
+    /// within a task, which is notified when the clock passes the deadline.
+    #[derive(Clone, Default)]
+    pub struct FakeClock(Rc<RefCell<FakeClockInner>>);
+
+    #[derive(Default)]
+    struct FakeClockInner {
+        now: Duration,
+        waiting: Vec<Task>,
+    }
+
+    impl FakeClock {
+        pub fn advance(&self, duration: Duration) {
+            let waiting = {
+                let mut inner = self.0.borrow_mut();
+                inner.now += duration;
+                mem::replace(&mut inner.waiting, Vec::new())
+            };
+            for task in waiting {
+                task.notify();
+            }
+        }
+    }
+
+    impl Timer for FakeClock {
+        fn delay(&self, duration: Duration) -> io::Result<Delay> {
+            let deadline = self.0.borrow().now + duration;
+            Ok(Box::new(FakeDelay {
+                clock: self.clone(),
+                deadline,
+            }))
+        }
+    }
+
+    struct FakeDelay {
+        clock: FakeClock,
+        deadline: Duration,
+    }
+
+    impl Future for FakeDelay {
+        type Item = ();
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<(), io::Error> {
+            let mut inner = self.clock.0.borrow_mut();
+            if inner.now >= self.deadline {
+                Ok(Async::Ready(()))
+            } else {
+                inner.waiting.push(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
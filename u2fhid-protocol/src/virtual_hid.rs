@@ -0,0 +1,382 @@
+//! In-process U2FHID device paired with a scripted host, so the protocol can
+//! be exercised end to end without a uhid device.
+
+use std::io;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::future::Either;
+use futures::unsync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{Future, Poll, Sink, StartSend, Stream};
+use rand;
+use slog::{self, Drain};
+use slog_stdlog;
+use tokio_core::reactor::{Core, Timeout};
+use u2f_core::test_support::{FakeUserPresence, InMemoryStorage};
+use u2f_core::{self_signed_attestation, AppId, SecureCryptoOperations, U2F};
+
+use client::MessageAssembler;
+use definitions::*;
+use timer::FakeClock;
+use U2FHID;
+
+/// How long the host waits for a response before giving up
+fn response_timeout_duration() -> Duration {
+    Duration::from_millis(1000)
+}
+
+/// Device end of an in-memory pair of packet pipes
+pub struct DeviceTransport {
+    from_host: UnboundedReceiver<Packet>,
+    to_host: UnboundedSender<Packet>,
+}
+
+impl Sink for DeviceTransport {
+    type SinkItem = Packet;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, io::Error> {
+        self.to_host.start_send(packet).map_err(|_| host_gone())
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.to_host.poll_complete().map_err(|_| host_gone())
+    }
+}
+
+impl Stream for DeviceTransport {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, io::Error> {
+        self.from_host.poll().map_err(|()| host_gone())
+    }
+}
+
+fn host_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Host is gone")
+}
+
+/// Host side of a U2FHID device running on its own reactor. The device only
+/// makes progress while the host waits for a response. Its timeouts follow a
+/// fake clock that only moves when the host advances it.
+pub struct VirtualHost {
+    core: Core,
+    clock: FakeClock,
+    to_device: UnboundedSender<Packet>,
+    from_device: Option<UnboundedReceiver<Packet>>,
+}
+
+impl VirtualHost {
+    /// A device whose user approves every request
+    pub fn new() -> VirtualHost {
+        VirtualHost::with_user_presence(FakeUserPresence::always_approve())
+    }
+
+    pub fn with_user_presence(user_presence: FakeUserPresence) -> VirtualHost {
+        let core = Core::new().unwrap();
+        let clock = FakeClock::default();
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let (to_device, from_host) = mpsc::unbounded();
+        let (to_host, from_device) = mpsc::unbounded();
+        let service = U2F::new(
+            Box::new(user_presence),
+            Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+            Box::new(InMemoryStorage::new()),
+            logger.new(o!()),
+        )
+        .unwrap();
+        let device = U2FHID::bind_service_with_timer(
+            Box::new(clock.clone()),
+            DeviceTransport { from_host, to_host },
+            service,
+            logger,
+        );
+        core.handle()
+            .spawn(device.map_err(|err| panic!("Device failed: {}", err)));
+        VirtualHost {
+            core,
+            clock,
+            to_device,
+            from_device: Some(from_device),
+        }
+    }
+
+    pub fn send(&mut self, packet: Packet) {
+        self.to_device.unbounded_send(packet).unwrap();
+    }
+
+    /// Send a message split into packets, all at once
+    pub fn send_message(&mut self, channel_id: ChannelId, command: Command, data: &[u8]) {
+        for packet in encode_message(channel_id, command, data) {
+            self.send(packet);
+        }
+    }
+
+    /// The next packet from the device, None if none arrives within the duration
+    pub fn receive_within(&mut self, duration: Duration) -> Option<Packet> {
+        let from_device = self.from_device.take().unwrap();
+        let timeout = Timeout::new(duration, &self.core.handle()).unwrap();
+        match self.core.run(from_device.into_future().select2(timeout)) {
+            Ok(Either::A(((packet, from_device), _))) => {
+                self.from_device = Some(from_device);
+                Some(packet.expect("Device closed the transport"))
+            }
+            Ok(Either::B((_, next))) => {
+                self.from_device = next.into_inner();
+                None
+            }
+            Err(_) => panic!("Transport failed"),
+        }
+    }
+
+    pub fn receive(&mut self) -> Packet {
+        self.receive_within(response_timeout_duration())
+            .expect("No response from device")
+    }
+
+    /// The next complete message on the channel, packets on other channels are dropped
+    pub fn receive_message(&mut self, channel_id: ChannelId) -> (Command, Vec<u8>) {
+        let mut assembler = MessageAssembler::new(channel_id);
+        loop {
+            let packet = self.receive();
+            if let Some(message) = assembler.accept(packet).unwrap() {
+                return message;
+            }
+        }
+    }
+
+    pub fn expect_no_response(&mut self, duration: Duration) {
+        if self.receive_within(duration).is_some() {
+            panic!("Unexpected response from device");
+        }
+    }
+
+    /// Move the device's clock forward, expired timeouts are handled the
+    /// next time the host waits for a response
+    pub fn advance(&mut self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    pub fn transact(
+        &mut self,
+        channel_id: ChannelId,
+        command: Command,
+        data: &[u8],
+    ) -> (Command, Vec<u8>) {
+        self.send_message(channel_id, command, data);
+        self.receive_message(channel_id)
+    }
+
+    /// Allocate a channel with INIT on the broadcast channel
+    pub fn init(&mut self) -> ChannelId {
+        let nonce: [u8; 8] = rand::random();
+        let (command, data) = self.transact(BROADCAST_CHANNEL_ID, Command::Init, &nonce);
+        assert_eq!(command, Command::Init);
+        assert_eq!(data.len(), 17);
+        assert_eq!(data[0..8], nonce[..]);
+        ChannelId(BigEndian::read_u32(&data[8..12]))
+    }
+
+    pub fn ping(&mut self, channel_id: ChannelId, data: &[u8]) -> Vec<u8> {
+        let (command, response) = self.transact(channel_id, Command::Ping, data);
+        assert_eq!(command, Command::Ping);
+        response
+    }
+
+    /// Send a raw U2F request message, returns the raw response message
+    pub fn msg(&mut self, channel_id: ChannelId, request: &[u8]) -> Vec<u8> {
+        let (command, response) = self.transact(channel_id, Command::Msg, request);
+        assert_eq!(command, Command::Msg);
+        response
+    }
+
+    pub fn wink(&mut self, channel_id: ChannelId) {
+        let (command, response) = self.transact(channel_id, Command::Wink, &[]);
+        assert_eq!(command, Command::Wink);
+        assert!(response.is_empty());
+    }
+
+    pub fn lock(&mut self, channel_id: ChannelId, seconds: u8) -> (Command, Vec<u8>) {
+        self.transact(channel_id, Command::Lock, &[seconds])
+    }
+
+    /// Error code of the next message on the channel, which must be an error
+    pub fn expect_error(&mut self, channel_id: ChannelId) -> u8 {
+        let (command, data) = self.receive_message(channel_id);
+        assert_eq!(command, Command::Error);
+        assert_eq!(data.len(), 1);
+        data[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use u2f_core::{
+        authenticate_request, register_request, verify_authentication, verify_registration,
+        AuthenticateControlCode, Challenge,
+    };
+
+    use super::*;
+
+    const ERR_INVALID_PAR: u8 = 0x02;
+    const ERR_MSG_TIMEOUT: u8 = 0x05;
+    const ERR_CHANNEL_BUSY: u8 = 0x06;
+    const ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+    #[test]
+    fn init_allocates_distinct_channels() {
+        let mut host = VirtualHost::new();
+
+        let first = host.init();
+        let second = host.init();
+
+        assert_ne!(first, BROADCAST_CHANNEL_ID);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn ping_echoes_multiple_packet_payload() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+
+        assert_eq!(host.ping(channel_id, &data), data);
+    }
+
+    #[test]
+    fn msg_registers_and_authenticates() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+        let application = AppId::from_bytes(&rand::random::<[u8; 32]>());
+        let challenge = Challenge::from_bytes(&rand::random::<[u8; 32]>());
+
+        let response = host.msg(channel_id, &register_request(&application, &challenge));
+        let mut key = verify_registration(&application, &challenge, &response).unwrap();
+
+        for _ in 0..2 {
+            let challenge = Challenge::from_bytes(&rand::random::<[u8; 32]>());
+            let request = authenticate_request(
+                &application,
+                &challenge,
+                &key.key_handle,
+                &AuthenticateControlCode::EnforceUserPresenceAndSign,
+            );
+            let response = host.msg(channel_id, &request);
+            let authentication = verify_authentication(&key, &challenge, &response).unwrap();
+            assert!(authentication.user_present);
+            key.counter = Some(authentication.counter);
+        }
+    }
+
+    #[test]
+    fn msg_without_approval_is_conditions_not_satisfied() {
+        let mut host = VirtualHost::with_user_presence(FakeUserPresence::never_approve());
+        let channel_id = host.init();
+        let request = register_request(
+            &AppId::from_bytes(&[1u8; 32]),
+            &Challenge::from_bytes(&[2u8; 32]),
+        );
+
+        assert_eq!(host.msg(channel_id, &request), vec![0x69, 0x85]);
+    }
+
+    #[test]
+    fn wink() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+
+        host.wink(channel_id);
+    }
+
+    #[test]
+    fn sync_echoes_nonce() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+
+        let response = host.transact(channel_id, Command::Sync, &[0x17]);
+
+        assert_eq!(response, (Command::Sync, vec![0x17]));
+    }
+
+    #[test]
+    fn unallocated_channel_is_invalid() {
+        let mut host = VirtualHost::new();
+
+        host.send_message(ChannelId(0x1234), Command::Ping, &[0u8; 8]);
+
+        assert_eq!(host.expect_error(ChannelId(0x1234)), ERR_INVALID_CHANNEL);
+    }
+
+    #[test]
+    fn interleaved_channels_are_received_independently() {
+        let mut host = VirtualHost::new();
+        let first = host.init();
+        let second = host.init();
+        let data = vec![0x42u8; 200];
+        let mut packets = encode_message(first, Command::Ping, &data);
+
+        host.send(packets.pop_front().unwrap());
+        assert_eq!(host.ping(second, &[0u8; 8]), vec![0u8; 8]);
+
+        for packet in packets {
+            host.send(packet);
+        }
+        let (command, response) = host.receive_message(first);
+        assert_eq!(command, Command::Ping);
+        assert_eq!(response, data);
+    }
+
+    #[test]
+    fn lock_excludes_other_channels_until_it_expires() {
+        let mut host = VirtualHost::new();
+        let locking = host.init();
+        let other = host.init();
+
+        assert_eq!(host.lock(locking, 1), (Command::Lock, vec![]));
+        host.send_message(other, Command::Ping, &[0u8; 8]);
+        assert_eq!(host.expect_error(other), ERR_CHANNEL_BUSY);
+        assert_eq!(host.ping(locking, &[1u8; 8]), vec![1u8; 8]);
+
+        host.advance(Duration::from_secs(1));
+
+        assert_eq!(host.ping(other, &[2u8; 8]), vec![2u8; 8]);
+    }
+
+    #[test]
+    fn lock_is_released_by_locking_with_zero_seconds() {
+        let mut host = VirtualHost::new();
+        let locking = host.init();
+        let other = host.init();
+
+        assert_eq!(host.lock(locking, 10), (Command::Lock, vec![]));
+        assert_eq!(host.lock(locking, 0), (Command::Lock, vec![]));
+
+        assert_eq!(host.ping(other, &[2u8; 8]), vec![2u8; 8]);
+    }
+
+    #[test]
+    fn lock_longer_than_ten_seconds_is_invalid_parameter() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+
+        assert_eq!(
+            host.lock(channel_id, 11),
+            (Command::Error, vec![ERR_INVALID_PAR])
+        );
+    }
+
+    #[test]
+    fn incomplete_message_times_out() {
+        let mut host = VirtualHost::new();
+        let channel_id = host.init();
+        let mut packets = encode_message(channel_id, Command::Ping, &[0u8; 100]);
+
+        host.send(packets.pop_front().unwrap());
+        host.expect_no_response(Duration::from_millis(100));
+        host.advance(packet_timeout_duration());
+
+        assert_eq!(host.expect_error(channel_id), ERR_MSG_TIMEOUT);
+        assert_eq!(host.ping(channel_id, &[3u8; 8]), vec![3u8; 8]);
+    }
+}